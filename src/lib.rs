@@ -0,0 +1,9204 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    fs::File,
+    hash::Hash,
+    io::{self, BufRead, Write},
+    mem,
+    ops::Deref,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use indexmap::IndexMap;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+
+//
+// Symbol.
+//
+
+thread_local! {
+    // Every symbol name ever seen. Interning makes symbols comparable by identity.
+    static SYMBOLS: RefCell<HashSet<Rc<str>>> = RefCell::new(HashSet::new());
+}
+
+/// An interned name such as `:red`. Two symbols are equal only if they share the same allocation.
+#[derive(Clone)]
+pub struct Symbol(Rc<str>);
+
+impl Symbol {
+    /// Return the symbol for `name`, creating it on first use.
+    fn intern(name: &str) -> Self {
+        SYMBOLS.with(|symbols| {
+            let mut symbols = symbols.borrow_mut();
+
+            if let Some(existing) = symbols.get(name) {
+                return Symbol(existing.clone());
+            }
+
+            let symbol: Rc<str> = Rc::from(name);
+            symbols.insert(symbol.clone());
+            Symbol(symbol)
+        })
+    }
+
+    fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+//
+// Decimal.
+//
+
+// The number of fractional digits kept when a division does not terminate.
+#[cfg(feature = "decimal")]
+const DECIMAL_DIVISION_SCALE: u32 = 18;
+
+/// An exact base-10 number, `mantissa * 10^-scale`. Written as `1.10d` in source.
+#[cfg(feature = "decimal")]
+#[derive(Clone, Copy)]
+pub struct Decimal {
+    mantissa: i128,
+    scale: u32,
+}
+
+#[cfg(feature = "decimal")]
+impl Decimal {
+    /// Parse a decimal literal without its `d` suffix, e.g. `1.10`.
+    fn parse(text: &str) -> Option<Self> {
+        let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+        let digits = format!("{}{}", whole, fraction);
+
+        Some(Self {
+            mantissa: digits.parse().ok()?,
+            scale: fraction.len() as u32,
+        })
+    }
+
+    // The mantissa expressed with `scale` fractional digits. `scale` must not be smaller than ours.
+    fn rescaled(&self, scale: u32) -> Option<i128> {
+        10i128
+            .checked_pow(scale - self.scale)
+            .and_then(|factor| self.mantissa.checked_mul(factor))
+    }
+
+    // Both mantissas expressed with a common scale.
+    fn aligned(&self, other: &Decimal) -> Option<(i128, i128, u32)> {
+        let scale = self.scale.max(other.scale);
+        Some((self.rescaled(scale)?, other.rescaled(scale)?, scale))
+    }
+
+    fn add(&self, other: &Decimal) -> Result<Decimal, &'static str> {
+        self.aligned(other)
+            .and_then(|(a, b, scale)| {
+                Some(Decimal {
+                    mantissa: a.checked_add(b)?,
+                    scale,
+                })
+            })
+            .ok_or("Decimal overflow.")
+    }
+
+    fn subtract(&self, other: &Decimal) -> Result<Decimal, &'static str> {
+        self.aligned(other)
+            .and_then(|(a, b, scale)| {
+                Some(Decimal {
+                    mantissa: a.checked_sub(b)?,
+                    scale,
+                })
+            })
+            .ok_or("Decimal overflow.")
+    }
+
+    fn multiply(&self, other: &Decimal) -> Result<Decimal, &'static str> {
+        let mantissa = self
+            .mantissa
+            .checked_mul(other.mantissa)
+            .ok_or("Decimal overflow.")?;
+
+        Ok(Decimal {
+            mantissa,
+            scale: self.scale + other.scale,
+        }
+        .trimmed(self.scale.max(other.scale)))
+    }
+
+    fn divide(&self, other: &Decimal) -> Result<Decimal, &'static str> {
+        if other.mantissa == 0 {
+            return Err("Division by zero.");
+        }
+
+        // Scale the dividend so the quotient carries DECIMAL_DIVISION_SCALE digits, then round
+        // half away from zero on the remainder. Products can carry more fractional digits than a
+        // power of ten fits in, so scaling down is checked too.
+        let scale = DECIMAL_DIVISION_SCALE;
+        let shift = scale + other.scale;
+        let dividend = if shift >= self.scale {
+            self.rescaled(shift)
+        } else {
+            10i128
+                .checked_pow(self.scale - shift)
+                .map(|factor| self.mantissa / factor)
+        }
+        .ok_or("Decimal overflow.")?;
+
+        let mut mantissa = dividend / other.mantissa;
+        let remainder = dividend % other.mantissa;
+        if remainder.unsigned_abs() * 2 >= other.mantissa.unsigned_abs() {
+            mantissa += dividend.signum() * other.mantissa.signum();
+        }
+
+        Ok(Decimal { mantissa, scale }.trimmed(self.scale.max(other.scale)))
+    }
+
+    fn negate(&self) -> Decimal {
+        Decimal {
+            mantissa: -self.mantissa,
+            scale: self.scale,
+        }
+    }
+
+    // Drop trailing fractional zeros, keeping at least `min_scale` digits.
+    fn trimmed(mut self, min_scale: u32) -> Decimal {
+        while self.scale > min_scale && self.mantissa % 10 == 0 {
+            self.mantissa /= 10;
+            self.scale -= 1;
+        }
+        self
+    }
+
+    fn compare(&self, other: &Decimal) -> std::cmp::Ordering {
+        let sign = self.mantissa.signum().cmp(&other.mantissa.signum());
+        if sign.is_ne() || self.mantissa == 0 {
+            return sign;
+        }
+
+        let magnitude = Self::compare_magnitudes(
+            (self.mantissa.unsigned_abs(), self.scale),
+            (other.mantissa.unsigned_abs(), other.scale),
+        );
+        if self.mantissa < 0 {
+            magnitude.reverse()
+        } else {
+            magnitude
+        }
+    }
+
+    // Compare two `(mantissa, scale)` magnitudes without rescaling upwards, which can overflow.
+    // The one with more fractional digits is divided down to the other's scale, so the integer
+    // parts decide first and any dropped remainder breaks a tie.
+    fn compare_magnitudes(a: (u128, u32), b: (u128, u32)) -> std::cmp::Ordering {
+        if a.1 < b.1 {
+            return Self::compare_magnitudes(b, a).reverse();
+        }
+
+        let (mut whole, mut remainder) = (a.0, false);
+        for _ in b.1..a.1 {
+            if whole == 0 {
+                break;
+            }
+            remainder |= whole % 10 != 0;
+            whole /= 10;
+        }
+
+        whole.cmp(&b.0).then(if remainder {
+            std::cmp::Ordering::Greater
+        } else {
+            std::cmp::Ordering::Equal
+        })
+    }
+}
+
+#[cfg(feature = "decimal")]
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits);
+        }
+
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, whole, fraction)
+    }
+}
+
+//
+// Dates and durations.
+//
+
+const SECONDS_PER_DAY: f64 = 86_400.0;
+
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const WEEKDAY_NAMES: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// A moment in time, in seconds since the Unix epoch. Dates are always in UTC.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub struct Date {
+    epoch: f64,
+}
+
+/// A span of time in seconds, negative when it runs backward.
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub struct Duration {
+    seconds: f64,
+}
+
+// Days since the epoch of a day in the proleptic Gregorian calendar. This is Howard Hinnant's
+// `days_from_civil`.
+const fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// The year, month and day of a day counted from the epoch, the inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+// The first and last days a date can fall on, a million years either side of year zero as
+// `Date::from_parts` allows. Calendar arithmetic stays well inside `i64` across this range.
+const EARLIEST_DAY: i64 = days_from_civil(-1_000_000, 1, 1);
+const LATEST_DAY: i64 = days_from_civil(1_000_000, 12, 31);
+
+impl Date {
+    /// The date `epoch` seconds after the Unix epoch, if it falls within the supported range.
+    fn from_epoch(epoch: f64) -> Option<Self> {
+        let range =
+            EARLIEST_DAY as f64 * SECONDS_PER_DAY..(LATEST_DAY + 1) as f64 * SECONDS_PER_DAY;
+        range.contains(&epoch).then_some(Self { epoch })
+    }
+
+    /// Midnight at the start of the given day. Months and days count from 1.
+    fn from_parts(year: f64, month: f64, day: f64) -> Result<Self, &'static str> {
+        if [year, month, day]
+            .iter()
+            .any(|part| part.fract() != 0.0 || part.abs() > 1e6)
+        {
+            return Err("Date parts must be whole numbers.");
+        }
+        let (year, month, day) = (year as i64, month as u32, day as u32);
+
+        // The day must not roll over into the next month.
+        let rolled_over = civil_from_days(days_from_civil(year, month, day)).2 != day;
+        if !(1..=12).contains(&month) || day < 1 || rolled_over {
+            return Err("No such date.");
+        }
+        Ok(Self {
+            epoch: days_from_civil(year, month, day) as f64 * SECONDS_PER_DAY,
+        })
+    }
+
+    // Whole days since the epoch, and the milliseconds into the last of them. The date is rounded
+    // to whole milliseconds first, so `59.9999` seconds carries into the next minute.
+    fn split(&self) -> (i64, i64) {
+        let millis = (self.epoch * 1000.0).round() as i64;
+        (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000))
+    }
+
+    /// The date's calendar fields, e.g. `year` or `weekday`, which runs from 1 for Monday.
+    fn field(&self, name: &str) -> Option<f64> {
+        let (days, time) = self.split();
+        let (year, month, day) = civil_from_days(days);
+        Some(match name {
+            "year" => year as f64,
+            "month" => month as f64,
+            "day" => day as f64,
+            "hour" => (time / 3_600_000) as f64,
+            "minute" => (time / 60_000 % 60) as f64,
+            "second" => (time % 60_000) as f64 / 1000.0,
+            // The epoch was a Thursday.
+            "weekday" => ((days + 3).rem_euclid(7) + 1) as f64,
+            "epoch" => self.epoch,
+            _ => return None,
+        })
+    }
+
+    /// Format the date like `strftime`: `%Y`, `%m`, `%d`, `%H`, `%M`, `%S`, `%a`, `%b` and `%%`.
+    fn format(&self, pattern: &str) -> Result<String, String> {
+        let (days, time) = self.split();
+        let (year, month, day) = civil_from_days(days);
+        let weekday = (days + 3).rem_euclid(7) as usize;
+
+        let mut text = String::new();
+        let mut chars = pattern.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                text.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('Y') => text.push_str(&format!("{:04}", year)),
+                Some('m') => text.push_str(&format!("{:02}", month)),
+                Some('d') => text.push_str(&format!("{:02}", day)),
+                Some('H') => text.push_str(&format!("{:02}", time / 3_600_000)),
+                Some('M') => text.push_str(&format!("{:02}", time / 60_000 % 60)),
+                Some('S') => text.push_str(&format!("{:02}", time / 1000 % 60)),
+                Some('a') => text.push_str(WEEKDAY_NAMES[weekday]),
+                Some('b') => text.push_str(MONTH_NAMES[month as usize - 1]),
+                Some('%') => text.push('%'),
+                Some(other) => return Err(format!("Unknown format directive '%{}'.", other)),
+                None => return Err("Format pattern ends with '%'.".to_string()),
+            }
+        }
+        Ok(text)
+    }
+}
+
+// ISO 8601, e.g. `2024-03-01T09:30:00Z`, with fractions of a second only when there are any.
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (days, time) = self.split();
+        let (year, month, day) = civil_from_days(days);
+        let (hour, minute, second) = (time / 3_600_000, time / 60_000 % 60, time / 1000 % 60);
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year, month, day, hour, minute, second
+        )?;
+        match time % 1000 {
+            0 => write!(f, "Z"),
+            fraction => write!(f, ".{:03}Z", fraction),
+        }
+    }
+}
+
+impl Duration {
+    /// The duration in the given unit, e.g. `hours`, including any fraction.
+    fn field(&self, name: &str) -> Option<f64> {
+        Some(match name {
+            "seconds" => self.seconds,
+            "minutes" => self.seconds / 60.0,
+            "hours" => self.seconds / 3600.0,
+            "days" => self.seconds / SECONDS_PER_DAY,
+            _ => return None,
+        })
+    }
+}
+
+// The largest units first, e.g. `1d 2h 30s`, and `0s` when empty.
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.seconds < 0.0 {
+            write!(f, "-")?;
+        }
+        let mut rest = self.seconds.abs();
+        let mut parts = vec![];
+        for (unit, size) in [("d", SECONDS_PER_DAY), ("h", 3600.0), ("m", 60.0)] {
+            let count = (rest / size).floor();
+            if count > 0.0 {
+                parts.push(format!("{}{}", count, unit));
+                rest -= count * size;
+            }
+        }
+        if rest > 0.0 || parts.is_empty() {
+            parts.push(format!("{}s", format_number(rest)));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+// Apply an arithmetic or comparison operator where at least one operand is a date or duration.
+fn time_arithmetic(opcode: Opcode, a: &Value, b: &Value) -> Result<Value, &'static str> {
+    use Value::{Date as D, Duration as T, Number as N};
+
+    let date = |epoch| Date::from_epoch(epoch).map(Value::Date);
+    let duration = |seconds: f64| {
+        seconds
+            .is_finite()
+            .then_some(Value::Duration(Duration { seconds }))
+    };
+    let result = match (opcode, a, b) {
+        (Opcode::Add, D(a), T(b)) | (Opcode::Add, T(b), D(a)) => date(a.epoch + b.seconds),
+        (Opcode::Add, T(a), T(b)) => duration(a.seconds + b.seconds),
+        (Opcode::Subtract, D(a), T(b)) => date(a.epoch - b.seconds),
+        (Opcode::Subtract, D(a), D(b)) => duration(a.epoch - b.epoch),
+        (Opcode::Subtract, T(a), T(b)) => duration(a.seconds - b.seconds),
+        (Opcode::Multiply, T(a), N(n)) | (Opcode::Multiply, N(n), T(a)) => duration(a.seconds * n),
+        (Opcode::Divide, T(a), N(n)) => duration(a.seconds / n),
+        (Opcode::Divide, T(a), T(b)) => Some(Value::Number(a.seconds / b.seconds)),
+        (Opcode::Greater, D(a), D(b)) => Some(Value::Bool(a > b)),
+        (Opcode::Greater, T(a), T(b)) => Some(Value::Bool(a > b)),
+        (Opcode::Less, D(a), D(b)) => Some(Value::Bool(a < b)),
+        (Opcode::Less, T(a), T(b)) => Some(Value::Bool(a < b)),
+        _ => return Err("Operands don't combine as dates and durations."),
+    };
+
+    // Like the natives which make them, dates stay within range and durations finite.
+    result.ok_or("Date or duration out of range.")
+}
+
+//
+// Value.
+//
+#[derive(Clone)]
+pub enum Value {
+    Bool(bool),
+    Nil,
+    Number(f64),
+    // Strings are immutable, so copies of a value share one heap allocation.
+    String(Rc<LoxString>),
+    // Short strings live in the value itself and need no allocation at all.
+    InlineStr(InlineStr),
+    Symbol(Symbol),
+    Function(Rc<Function>),
+    Native(Rc<NativeFn>),
+    Class(Rc<Class>),
+    BoundMethod(Rc<BoundMethod>),
+    // Instances are mutable, and every copy of the value sees the same object.
+    Instance(Rc<RefCell<Instance>>),
+    // Lists and maps are mutable and shared the same way.
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<IndexMap<MapKey, Value>>>),
+    Date(Date),
+    Duration(Duration),
+    #[cfg(feature = "decimal")]
+    Decimal(Decimal),
+}
+
+/// A compiled function.
+pub struct Function {
+    arity: u8,
+    // Whether the arguments after the first `arity` are collected into a list, as with
+    // `fun f(a, ...rest)`.
+    variadic: bool,
+    chunk: Chunk,
+    // `None` for the top-level script.
+    name: Option<Rc<str>>,
+}
+
+impl Function {
+    // Wrap a top-level chunk so it can be run like any other function.
+    pub fn script(chunk: Chunk) -> Self {
+        Self {
+            arity: 0,
+            variadic: false,
+            chunk,
+            name: None,
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+// A function implemented in Rust, called with its arguments. An `Err` is reported as a runtime
+// error at the call.
+type NativeBody = dyn Fn(&[Value]) -> Result<Value, String>;
+
+/// A class declared in Lox. Calling it makes an instance.
+pub struct Class {
+    name: Rc<str>,
+    // Set by OP_INHERIT, before any methods are attached.
+    superclass: OnceCell<Rc<Class>>,
+    // Filled in by the OP_METHODs which follow OP_CLASS. Inherited methods are not copied here.
+    methods: RefCell<StringTable<Rc<Function>>>,
+    // Methods called on the class itself: functions filled in by OP_STATIC_METHOD, or natives
+    // for classes defined in Rust.
+    statics: RefCell<StringTable<Value>>,
+}
+
+impl Class {
+    // Find a method on `class` or the nearest superclass declaring it, along with that class.
+    fn find_method(class: &Rc<Class>, name: &Rc<LoxString>) -> Option<(Rc<Function>, Rc<Class>)> {
+        let mut class = class;
+        loop {
+            if let Some(method) = class.methods.borrow().get(name) {
+                return Some((method.clone(), class.clone()));
+            }
+            class = class.superclass.get()?;
+        }
+    }
+
+    // Find a static method on `class` or the nearest superclass declaring it.
+    fn find_static(class: &Rc<Class>, name: &Rc<LoxString>) -> Option<Value> {
+        let mut class = class;
+        loop {
+            if let Some(method) = class.statics.borrow().get(name) {
+                return Some(method.clone());
+            }
+            class = class.superclass.get()?;
+        }
+    }
+}
+
+/// A method read from an instance, which remembers the instance to call it on.
+pub struct BoundMethod {
+    receiver: Value,
+    method: Rc<Function>,
+    // The class declaring the method, where `super` inside it starts looking.
+    class: Rc<Class>,
+}
+
+/// An object made by calling a class.
+pub struct Instance {
+    class: Rc<Class>,
+    fields: StringTable<Value>,
+}
+
+/// A Rust function callable from Lox.
+pub struct NativeFn {
+    name: Rc<str>,
+    arity: u8,
+    body: Box<NativeBody>,
+}
+
+impl Value {
+    #[allow(dead_code)]
+    fn is_bool(&self) -> bool {
+        matches!(*self, Value::Bool(_))
+    }
+
+    fn is_number(&self) -> bool {
+        matches!(*self, Value::Number(_))
+    }
+
+    fn is_nil(&self) -> bool {
+        matches!(*self, Value::Nil)
+    }
+
+    fn is_string(&self) -> bool {
+        matches!(*self, Value::String(_) | Value::InlineStr(_))
+    }
+
+    fn is_time(&self) -> bool {
+        matches!(*self, Value::Date(_) | Value::Duration(_))
+    }
+
+    fn is_function(&self) -> bool {
+        matches!(
+            *self,
+            Value::Function(_) | Value::Native(_) | Value::BoundMethod(_)
+        )
+    }
+
+    // A read-only field of a function, native or bound method: how many arguments it takes
+    // before any rest parameter, or its name.
+    fn function_field(&self, name: &str) -> Option<Value> {
+        let (arity, function_name) = match self {
+            Value::Function(function) => (function.arity, function.name.as_deref()),
+            Value::BoundMethod(bound) => (bound.method.arity, bound.method.name.as_deref()),
+            Value::Native(native) => (native.arity, Some(&*native.name)),
+            _ => return None,
+        };
+        match name {
+            "arity" => Some(Value::Number(arity as f64)),
+            "name" => Some(string_value(function_name.unwrap_or("script"))),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "decimal")]
+    fn is_decimal(&self) -> bool {
+        matches!(*self, Value::Decimal(_))
+    }
+
+    #[cfg(feature = "decimal")]
+    fn as_decimal(&self) -> Decimal {
+        match *self {
+            Value::Decimal(value) => value,
+            _ => unreachable!(),
+        }
+    }
+
+    #[allow(dead_code)]
+    fn as_bool(&self) -> bool {
+        match *self {
+            Value::Bool(value) => value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn as_number(&self) -> f64 {
+        match *self {
+            Value::Number(value) => value,
+            _ => unreachable!(),
+        }
+    }
+
+    // Only for names, which the compiler always stores as heap strings so tables can key on them.
+    fn as_string(&self) -> Rc<LoxString> {
+        match *self {
+            Value::String(ref value) => value.clone(),
+            _ => unreachable!(),
+        }
+    }
+
+    // The characters of either kind of string.
+    fn as_str(&self) -> &str {
+        match self {
+            Value::String(value) => value.as_str(),
+            Value::InlineStr(value) => value.as_str(),
+            _ => unreachable!(),
+        }
+    }
+
+    // Lox truthiness: nil and false are falsey, every other value is truthy.
+    // Conditional opcodes must go through this rather than matching on values themselves.
+    fn is_falsey(&self) -> bool {
+        match *self {
+            Value::Bool(value) => !value,
+            Value::Nil => true,
+            _ => false,
+        }
+    }
+
+    fn is_equal(&self, other: &Value) -> bool {
+        self.is_equal_within(other, &mut vec![])
+    }
+
+    // Equality, where `open` holds the pairs of maps being compared further up. A map which
+    // contains itself meets the same pair again, which is taken as equal so the comparison ends,
+    // much as `Display` writes `{...}`.
+    fn is_equal_within(&self, other: &Value, open: &mut Vec<(*const (), *const ())>) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            // Constants are shared, so equal strings are often the same allocation.
+            (Value::String(a), Value::String(b)) => Rc::ptr_eq(a, b) || a == b,
+            (a, b) if a.is_string() && b.is_string() => a.as_str() == b.as_str(),
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            // Maps are equal when they hold equal values under the same keys.
+            (Value::Map(a), Value::Map(b)) => {
+                let pair = (Rc::as_ptr(a) as *const (), Rc::as_ptr(b) as *const ());
+                if Rc::ptr_eq(a, b) || open.contains(&pair) {
+                    return true;
+                }
+
+                open.push(pair);
+                let (a, b) = (a.borrow(), b.borrow());
+                let equal = a.len() == b.len()
+                    && a.iter().all(|(key, value)| {
+                        b.get(key)
+                            .is_some_and(|other| value.is_equal_within(other, open))
+                    });
+                open.pop();
+                equal
+            }
+            (Value::Date(a), Value::Date(b)) => a == b,
+            (Value::Duration(a), Value::Duration(b)) => a == b,
+            #[cfg(feature = "decimal")]
+            (Value::Decimal(a), Value::Decimal(b)) => a.compare(b).is_eq(),
+            _ => false,
+        }
+    }
+
+    // How the value is written in listings, distinguishing types which print alike: strings are
+    // quoted, so `"1"` and `1` differ, and decimals keep their `d`.
+    fn repr(&self) -> String {
+        match self {
+            Value::String(_) | Value::InlineStr(_) => format!("{:?}", self.as_str()),
+            #[cfg(feature = "decimal")]
+            Value::Decimal(v) => format!("{}d", v),
+            _ => self.to_string(),
+        }
+    }
+
+    // Whether one constant table entry can stand in for both values. Stricter than equality:
+    // `0` and `-0` are equal but print differently, and `1.0d` must stay distinct from `1.00d`.
+    fn is_same_constant(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            // A heap string can stand in for an inline one but not the other way round, since
+            // names must stay heap strings.
+            (Value::String(a), b) if b.is_string() => a.as_str() == b.as_str(),
+            (Value::InlineStr(a), Value::InlineStr(b)) => a.as_str() == b.as_str(),
+            (Value::Symbol(a), Value::Symbol(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    // The byte OP_SMALL_INTEGER loads this number with, if it is a whole number in range. `-0` is
+    // left out, since it prints differently from `0`.
+    fn as_small_integer(&self) -> Option<u8> {
+        match self {
+            Value::Number(n) if n.fract() == 0.0 && (0.0..=255.0).contains(n) => {
+                (n.to_bits() != (-0.0f64).to_bits()).then_some(*n as u8)
+            }
+            _ => None,
+        }
+    }
+}
+
+thread_local! {
+    // Heap string constants from every chunk compiled so far, by text. Functions and scripts
+    // repeating a long literal or name then hold one allocation between them.
+    static CONSTANT_STRINGS: RefCell<HashMap<Box<str>, Rc<LoxString>>> =
+        RefCell::new(HashMap::new());
+}
+
+// The shared copy of a constant. Only heap strings are pooled; every other constant is a copy.
+fn shared_constant(value: Value) -> Value {
+    match value {
+        Value::String(string) => CONSTANT_STRINGS.with(|pool| {
+            let mut pool = pool.borrow_mut();
+            let text = Box::from(string.as_str());
+            Value::String(pool.entry(text).or_insert(string).clone())
+        }),
+        other => other,
+    }
+}
+
+// Make a string value, inline when it is short enough.
+fn string_value(text: &str) -> Value {
+    match InlineStr::new(text) {
+        Some(inline) => Value::InlineStr(inline),
+        None => Value::String(Rc::new(LoxString::new(text))),
+    }
+}
+
+// The longest string kept inline. With its length byte and the tag it fills the space the widest
+// other variant already takes, so inline strings do not make values any bigger.
+const INLINE_STR_MAX: usize = 22;
+
+/// A string of up to `INLINE_STR_MAX` bytes stored by value.
+#[derive(Clone, Copy)]
+pub struct InlineStr {
+    len: u8,
+    bytes: [u8; INLINE_STR_MAX],
+}
+
+impl InlineStr {
+    // `None` if `text` is too long to fit.
+    fn new(text: &str) -> Option<Self> {
+        if text.len() > INLINE_STR_MAX {
+            return None;
+        }
+
+        let mut bytes = [0; INLINE_STR_MAX];
+        bytes[..text.len()].copy_from_slice(text.as_bytes());
+        Some(Self {
+            len: text.len() as u8,
+            bytes,
+        })
+    }
+
+    fn as_str(&self) -> &str {
+        // The bytes were copied from a `str`, so they are valid UTF-8.
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap()
+    }
+}
+
+/// The contents of a Lox string, with its hash computed once when it is created so table
+/// lookups never rehash the characters.
+pub struct LoxString {
+    hash: u32,
+    chars: Box<str>,
+}
+
+impl LoxString {
+    fn new(chars: &str) -> Self {
+        Self {
+            hash: fnv1a(chars.as_bytes()),
+            chars: Box::from(chars),
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        &self.chars
+    }
+}
+
+// 32-bit FNV-1a, as used by clox.
+fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(2166136261u32, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(16777619)
+    })
+}
+
+impl PartialEq for LoxString {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.chars == other.chars
+    }
+}
+
+impl Eq for LoxString {}
+
+impl std::hash::Hash for LoxString {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        state.write_u32(self.hash);
+    }
+}
+
+impl fmt::Display for LoxString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.chars)
+    }
+}
+
+// Hashes a `LoxString` by handing over its cached hash instead of hashing anything itself.
+#[derive(Default)]
+struct CachedHasher(u64);
+
+impl std::hash::Hasher for CachedHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // Only reached for keys which are not strings. Fold them in like any other hash would.
+        self.0 = fnv1a(bytes) as u64 ^ self.0.rotate_left(5);
+    }
+
+    fn write_u32(&mut self, hash: u32) {
+        self.0 = hash as u64;
+    }
+}
+
+// A table keyed by Lox strings, such as the globals.
+type StringTable<V> = HashMap<Rc<LoxString>, V, std::hash::BuildHasherDefault<CachedHasher>>;
+
+impl fmt::Display for Value {
+    // This trait requires `fmt` with this exact signature.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Nil => write!(f, "nil"),
+            Value::Number(v) => write!(f, "{}", format_number(*v)),
+            Value::String(v) => write!(f, "{}", v),
+            Value::InlineStr(v) => write!(f, "{}", v.as_str()),
+            Value::Symbol(symbol) => write!(f, ":{}", symbol.name()),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::Native(_) => write!(f, "<native fn>"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::BoundMethod(bound) => write!(f, "{}", bound.method),
+            Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+            Value::List(_) | Value::Map(_) => write_collection(f, self, &mut vec![]),
+            Value::Date(date) => write!(f, "{}", date),
+            Value::Duration(duration) => write!(f, "{}", duration),
+            #[cfg(feature = "decimal")]
+            Value::Decimal(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+// Write a list or map such as `[1, "two"]` or `{"a": [3]}`, quoting the strings in it. `open`
+// holds the collections being written, so one which contains itself is cut short as `[...]` or
+// `{...}` instead of going on forever.
+fn write_collection(
+    f: &mut fmt::Formatter,
+    value: &Value,
+    open: &mut Vec<*const ()>,
+) -> fmt::Result {
+    let id = match value {
+        Value::List(list) => Rc::as_ptr(list) as *const (),
+        Value::Map(map) => Rc::as_ptr(map) as *const (),
+        _ => return write!(f, "{}", value.repr()),
+    };
+    if open.contains(&id) {
+        return match value {
+            Value::List(_) => write!(f, "[...]"),
+            _ => write!(f, "{{...}}"),
+        };
+    }
+    open.push(id);
+
+    match value {
+        Value::List(list) => {
+            write!(f, "[")?;
+            for (i, element) in list.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_collection(f, element, open)?;
+            }
+            write!(f, "]")?;
+        }
+        Value::Map(map) => {
+            write!(f, "{{")?;
+            for (i, (key, value)) in map.borrow().iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: ", key.0.repr())?;
+                write_collection(f, value, open)?;
+            }
+            write!(f, "}}")?;
+        }
+        _ => unreachable!(),
+    }
+    open.pop();
+    Ok(())
+}
+
+/// A value used as a map key. Only values compared by content can be keys, so that a key
+/// can be found again with an equal value.
+#[derive(Clone)]
+pub struct MapKey(Value);
+
+impl MapKey {
+    fn new(value: Value) -> Result<Self, &'static str> {
+        match value {
+            Value::Number(n) if n.is_nan() => Err("Map keys can't be NaN."),
+            Value::Nil
+            | Value::Bool(_)
+            | Value::Number(_)
+            | Value::String(_)
+            | Value::InlineStr(_)
+            | Value::Symbol(_) => Ok(MapKey(value)),
+            _ => Err("Map keys must be nil, booleans, numbers, strings or symbols."),
+        }
+    }
+}
+
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.is_equal(&other.0)
+    }
+}
+
+impl Eq for MapKey {}
+
+impl std::hash::Hash for MapKey {
+    // Values which are equal must hash alike: both kinds of string hash their text, and `-0`
+    // hashes as `0`.
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        match &self.0 {
+            Value::Nil => state.write_u8(0),
+            Value::Bool(b) => b.hash(state),
+            Value::Number(n) => (n + 0.0).to_bits().hash(state),
+            Value::Symbol(symbol) => symbol.name().hash(state),
+            value => value.as_str().hash(state),
+        }
+    }
+}
+
+// Integral numbers below this magnitude print in full, larger ones use exponent notation.
+const MAX_PLAIN_INTEGER: f64 = 1e16;
+
+// Format a number the way Lox prints it: integral values have no trailing `.0`, everything else
+// uses the shortest representation that reads back as the same double. Never locale dependent.
+fn format_number(value: f64) -> String {
+    if value.is_nan() {
+        "nan".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else if value.fract() == 0.0 && value.abs() < MAX_PLAIN_INTEGER {
+        // Keeps the sign of negative zero.
+        format!("{}", value)
+    } else {
+        // Debug is the shortest round-trip form and switches to exponents for extreme magnitudes.
+        format!("{:?}", value)
+    }
+}
+
+//
+// Chunk.
+//
+
+// How the operand bytes after an opcode are laid out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operands {
+    Simple,   // No operands.
+    Byte,     // A stack slot or a count.
+    Constant, // An index into the constant table.
+    Jump,     // A 16-bit offset forward.
+    Loop,     // A 16-bit offset backward.
+    Invoke,   // A constant holding a method name, then an argument count.
+}
+
+impl Operands {
+    /// The number of operand bytes following the opcode.
+    pub fn width(self) -> usize {
+        match self {
+            Operands::Simple => 0,
+            Operands::Byte | Operands::Constant => 1,
+            Operands::Jump | Operands::Loop | Operands::Invoke => 2,
+        }
+    }
+}
+
+// Defines `Opcode` and its metadata from one row per instruction: its byte, its name in listings,
+// its operands and its stack effect. The effect reads `|count| pops => pushes`, where `count` is
+// the instruction's last operand byte, and describes the path which does not jump.
+macro_rules! opcodes {
+    ($(
+        $opcode:ident = $byte:literal, $name:literal, $operands:ident,
+        |$count:tt| $pops:expr => $pushes:expr;
+    )*) => {
+        // List of VM instructions.
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        pub enum Opcode {
+            $($opcode = $byte,)*
+        }
+
+        impl Opcode {
+            /// Every opcode, in byte order.
+            pub const ALL: &'static [Opcode] = &[$(Opcode::$opcode,)*];
+
+            /// The opcode encoded as `byte`, if any.
+            pub fn decode(byte: u8) -> Option<Opcode> {
+                match byte {
+                    $($byte => Some(Opcode::$opcode),)*
+                    _ => None,
+                }
+            }
+
+            /// The name used in listings and reports, e.g. `OP_JUMP_IF_FALSE` for `JumpIfFalse`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Opcode::$opcode => $name,)*
+                }
+            }
+
+            /// How the operand bytes after this opcode are laid out.
+            pub fn operands(self) -> Operands {
+                match self {
+                    $(Opcode::$opcode => Operands::$operands,)*
+                }
+            }
+
+            /// How many values the instruction pops and then pushes, given its last operand byte.
+            pub fn stack_effect(self, count: u8) -> (usize, usize) {
+                match self {
+                    $(Opcode::$opcode => {
+                        let $count = count as usize;
+                        ($pops, $pushes)
+                    })*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    Constant = 1, "OP_CONSTANT", Constant, |_| 0 => 1;
+    // These three values are added here because it's better for performance. By doing so, we
+    // don't need to create another look up table, and we can save an additional byte (no need
+    // for Opcode::Constant).
+    Nil = 2, "OP_NIL", Simple, |_| 0 => 1;
+    True = 3, "OP_TRUE", Simple, |_| 0 => 1;
+    False = 4, "OP_FALSE", Simple, |_| 0 => 1;
+    Equal = 5, "OP_EQUAL", Simple, |_| 2 => 1;
+    Greater = 6, "OP_GREATER", Simple, |_| 2 => 1;
+    Less = 7, "OP_LESS", Simple, |_| 2 => 1;
+    Add = 8, "OP_ADD", Simple, |_| 2 => 1;
+    Subtract = 9, "OP_SUBTRACT", Simple, |_| 2 => 1;
+    Multiply = 10, "OP_MULTIPLY", Simple, |_| 2 => 1;
+    Divide = 11, "OP_DIVIDE", Simple, |_| 2 => 1;
+    Modulo = 12, "OP_MODULO", Simple, |_| 2 => 1;
+    Power = 13, "OP_POWER", Simple, |_| 2 => 1;
+    BitAnd = 14, "OP_BIT_AND", Simple, |_| 2 => 1;
+    BitOr = 15, "OP_BIT_OR", Simple, |_| 2 => 1;
+    BitXor = 16, "OP_BIT_XOR", Simple, |_| 2 => 1;
+    BitNot = 17, "OP_BIT_NOT", Simple, |_| 1 => 1;
+    ShiftLeft = 18, "OP_SHIFT_LEFT", Simple, |_| 2 => 1;
+    ShiftRight = 19, "OP_SHIFT_RIGHT", Simple, |_| 2 => 1;
+    Not = 20, "OP_NOT", Simple, |_| 1 => 1;
+    Negate = 21, "OP_NEGATE", Simple, |_| 1 => 1;
+    // Replace the top with the text `print` would show for it.
+    Stringify = 22, "OP_STRINGIFY", Simple, |_| 1 => 1;
+    Dup = 23, "OP_DUP", Simple, |_| 1 => 2;   // [a]    -> [a a]
+    Swap = 24, "OP_SWAP", Simple, |_| 2 => 2; // [a b]  -> [b a]
+    Over = 25, "OP_OVER", Simple, |_| 2 => 3; // [a b]  -> [a b a]
+    // Pop the top if it is nil, otherwise keep it and jump forward.
+    Coalesce = 26, "OP_COALESCE", Jump, |_| 1 => 0;
+    Jump = 27, "OP_JUMP", Jump, |_| 0 => 0;
+    // Jump forward if the top is falsey. The condition is left on the stack.
+    JumpIfFalse = 28, "OP_JUMP_IF_FALSE", Jump, |_| 1 => 1;
+    // Jump backward.
+    Loop = 29, "OP_LOOP", Loop, |_| 0 => 0;
+    Print = 30, "OP_PRINT", Simple, |_| 1 => 0;
+    Pop = 31, "OP_POP", Simple, |_| 1 => 0;
+    DefineGlobal = 32, "OP_DEFINE_GLOBAL", Constant, |_| 1 => 0;
+    GetGlobal = 33, "OP_GET_GLOBAL", Constant, |_| 0 => 1;
+    SetGlobal = 34, "OP_SET_GLOBAL", Constant, |_| 1 => 1;
+    GetLocal = 35, "OP_GET_LOCAL", Byte, |_| 0 => 1;
+    SetLocal = 36, "OP_SET_LOCAL", Byte, |_| 1 => 1;
+    // [callee args...] -> [result]
+    Call = 37, "OP_CALL", Byte, |args| args + 1 => 1;
+    Return = 38, "OP_RETURN", Simple, |_| 1 => 0;
+    Class = 39, "OP_CLASS", Constant, |_| 0 => 1;
+    GetProperty = 40, "OP_GET_PROPERTY", Constant, |_| 1 => 1;
+    SetProperty = 41, "OP_SET_PROPERTY", Constant, |_| 2 => 1;
+    // Replace the top `n` values with a list of them, where `n` is the operand.
+    BuildList = 42, "OP_BUILD_LIST", Byte, |n| n => 1;
+    // Replace the top `n` key and value pairs with a map of them.
+    BuildMap = 43, "OP_BUILD_MAP", Byte, |n| 2 * n => 1;
+    IndexGet = 44, "OP_INDEX_GET", Simple, |_| 2 => 1; // [list index]       -> [element]
+    IndexSet = 45, "OP_INDEX_SET", Simple, |_| 3 => 1; // [list index value] -> [value]
+    Method = 46, "OP_METHOD", Constant, |_| 1 => 0;
+    Inherit = 47, "OP_INHERIT", Simple, |_| 2 => 0;
+    GetSuper = 48, "OP_GET_SUPER", Constant, |_| 1 => 1;
+    // [this args...] -> [result]
+    SuperInvoke = 49, "OP_SUPER_INVOKE", Invoke, |args| args + 1 => 1;
+    // Whole numbers from 0 to 255 are common enough to skip the constant table, like OP_NIL.
+    SmallInteger = 50, "OP_SMALL_INTEGER", Byte, |_| 0 => 1;
+    // Like OP_DEFINE_GLOBAL, but the global can't be assigned to afterwards.
+    DefineConstGlobal = 51, "OP_DEFINE_CONST_GLOBAL", Constant, |_| 1 => 0;
+    // Like OP_METHOD, for a method called on the class itself.
+    StaticMethod = 52, "OP_STATIC_METHOD", Constant, |_| 1 => 0;
+    // Jump forward if the top is nil, which is left on the stack either way.
+    JumpIfNil = 53, "OP_JUMP_IF_NIL", Jump, |_| 1 => 1;
+}
+
+// Precedence table. From lowest to highest.
+#[derive(FromPrimitive, Clone, Copy)]
+#[repr(u8)]
+pub enum Precedence {
+    None = 1,
+    Assignment,  // =
+    Conditional, // ?:
+    Coalesce,    // ??
+    Or,          // or
+    And,         // and
+    Equality,    // == !=
+    Comparison,  // < > <= >=
+    BitOr,       // |
+    BitXor,      // ^
+    BitAnd,      // &
+    Shift,       // << >>
+    Term,        // + -
+    Factor,      // * / %
+    Unary,       // ! - ~
+    Power,       // **
+    Call,        // . () []
+    Primary,
+}
+
+// The number of distinct values a one byte operand can address.
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+/// Identifies a source file. 0 is used for code without a file, e.g. REPL input.
+pub type FileId = u32;
+
+/// Where in the source a piece of bytecode came from. Lines and columns start at 1.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct SourceLocation {
+    pub file: FileId,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl SourceLocation {
+    /// A location in the file-less source, at the start of `line`.
+    pub fn line(line: u32) -> Self {
+        Self {
+            file: 0,
+            line,
+            column: 1,
+        }
+    }
+}
+
+/// The files loaded into a VM, so diagnostics can name the file a location is in.
+#[derive(Default)]
+pub struct SourceRegistry {
+    // Display names, where `names[i]` belongs to file id `i + 1`.
+    names: Vec<String>,
+}
+
+impl SourceRegistry {
+    /// Return the id for `path`, registering it on first sight.
+    pub fn register(&mut self, path: &str) -> FileId {
+        let name = display_path(path);
+
+        let index = match self.names.iter().position(|existing| *existing == name) {
+            Some(index) => index,
+            None => {
+                self.names.push(name);
+                self.names.len() - 1
+            }
+        };
+        index as FileId + 1
+    }
+
+    /// The display name of `file`, or `None` for code without a file.
+    pub fn name(&self, file: FileId) -> Option<&str> {
+        let index = (file as usize).checked_sub(1)?;
+        self.names.get(index).map(String::as_str)
+    }
+
+    /// Describe a location for a diagnostic, e.g. `src/main.lox:3:7` or `line 3`.
+    pub fn describe(&self, location: SourceLocation) -> String {
+        describe_location(self.name(location.file), location.line, location.column)
+    }
+}
+
+// Shared by compile and runtime diagnostics so both agree on the format.
+fn describe_location(file: Option<&str>, line: u32, column: u32) -> String {
+    match file {
+        Some(file) => format!("{}:{}:{}", file, line, column),
+        None => format!("line {}", line),
+    }
+}
+
+/// The text of a script file. The scanner, tokens and compiler all borrow from this one buffer.
+///
+/// A mapped file must not change while it is loaded. See `SourceText::load`.
+pub enum SourceText {
+    Mapped(memmap2::Mmap),
+    Read(String),
+}
+
+impl SourceText {
+    /// Load `path`, memory-mapping it when possible so large files are never copied.
+    ///
+    /// Pipes and other files which can't be mapped are read into memory instead.
+    ///
+    /// The mapping is shared with the file, so its soundness rests on nothing else writing to or
+    /// truncating the file until the `SourceText` is dropped. That can't be enforced from here:
+    /// the interpreter relies on scripts not being edited while they are being loaded, as other
+    /// tools which map their inputs do.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let file = File::open(path)?;
+
+        // SAFETY: this relies on the invariant above. A write from another process would change
+        // bytes behind the `&str` handed out by `deref`, which is undefined behavior, and a
+        // truncation makes reads past the new end fault with SIGBUS.
+        match unsafe { memmap2::Mmap::map(&file) } {
+            Ok(map) => {
+                std::str::from_utf8(&map)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                Ok(SourceText::Mapped(map))
+            }
+            Err(_) => io::read_to_string(file).map(SourceText::Read),
+        }
+    }
+}
+
+impl Deref for SourceText {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        match self {
+            // SAFETY: checked to be UTF-8 when it was loaded, and unchanged since by the invariant
+            // documented on `SourceText::load`.
+            SourceText::Mapped(map) => unsafe { std::str::from_utf8_unchecked(map) },
+            SourceText::Read(text) => text,
+        }
+    }
+}
+
+// Paths under the working directory are shown relative to it, others as given.
+fn display_path(path: &str) -> String {
+    let absolute = std::path::Path::new(path)
+        .canonicalize()
+        .unwrap_or_else(|_| PathBuf::from(path));
+
+    std::env::current_dir()
+        .ok()
+        .and_then(|cwd| {
+            absolute
+                .strip_prefix(cwd)
+                .ok()
+                .map(|relative| relative.to_path_buf())
+        })
+        .unwrap_or_else(|| PathBuf::from(path))
+        .display()
+        .to_string()
+}
+
+/// Maps bytecode offsets to source locations.
+///
+/// Consecutive bytes usually share a location, so only the offset where each new location starts
+/// is stored.
+#[derive(Default)]
+pub struct SourceMap {
+    runs: Vec<(usize, SourceLocation)>,
+}
+
+impl SourceMap {
+    /// Record that the byte at `offset` came from `location`. Offsets must be added in order.
+    pub fn add(&mut self, offset: usize, location: SourceLocation) {
+        match self.runs.last() {
+            Some((_, last)) if *last == location => {}
+            _ => self.runs.push((offset, location)),
+        }
+    }
+
+    /// The location of the byte at `offset`.
+    pub fn location(&self, offset: usize) -> SourceLocation {
+        let run = self.runs.partition_point(|(start, _)| *start <= offset);
+        self.runs[run - 1].1
+    }
+
+    /// Forget the locations of the bytes from `offset` on.
+    pub fn truncate(&mut self, offset: usize) {
+        let kept = self.runs.partition_point(|(start, _)| *start < offset);
+        self.runs.truncate(kept);
+    }
+}
+
+/// A chunk is a sequence of bytecode.
+#[derive(Default)]
+pub struct Chunk {
+    /// The list of bytecode which represents the program.
+    pub code: Vec<u8>,
+    /// The list of constants declared.
+    constants: Vec<Value>,
+    /// Where each byte of code came from.
+    pub source_map: SourceMap,
+}
+
+impl Chunk {
+    /// Returns a newly intialized chunk.
+    pub fn new() -> Self {
+        Self {
+            code: vec![],
+            constants: vec![],
+            source_map: SourceMap::default(),
+        }
+    }
+
+    /// Write a byte into the chunk.
+    pub fn write(&mut self, byte: u8, location: SourceLocation) {
+        self.source_map.add(self.code.len(), location);
+        self.code.push(byte);
+    }
+
+    /// Write an instruction into the chunk.
+    pub fn write_instruction(&mut self, instruction: Opcode, location: SourceLocation) {
+        self.write(instruction as u8, location);
+    }
+
+    /// The source location of the byte at `offset`.
+    pub fn location(&self, offset: usize) -> SourceLocation {
+        self.source_map.location(offset)
+    }
+
+    /// Drop the code from `offset` on, and the constants added after the first `constants`.
+    fn truncate(&mut self, offset: usize, constants: usize) {
+        self.code.truncate(offset);
+        self.source_map.truncate(offset);
+        self.constants.truncate(constants);
+    }
+
+    /// Push a constant into the constant vector, return the index which the constant resides.
+    /// A constant already in the vector is reused, e.g. a global's name or a repeated literal.
+    fn add_constant(&mut self, value: Value) -> usize {
+        let value = shared_constant(value);
+        if let Some(existing) = self
+            .constants
+            .iter()
+            .position(|c| c.is_same_constant(&value))
+        {
+            return existing;
+        }
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    /// Dump the instruction's information. Returns the next offset.
+    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+        let (text, next) = Disassembler::new(self, Palette::PLAIN).instruction(offset);
+        println!("{}", text);
+        next
+    }
+
+    /// For debugging. Dumps the program's instructions.
+    pub fn disassemble_chunk(&self, name: &str) {
+        print!("{}", Disassembler::new(self, Palette::PLAIN).chunk(name));
+    }
+}
+
+/// Escape codes used to color disassembly. `Palette::PLAIN` leaves it uncolored.
+#[derive(Clone, Copy)]
+pub struct Palette {
+    opcode: &'static str,
+    operand: &'static str,
+    constant: &'static str,
+    jump: &'static str,
+    reset: &'static str,
+}
+
+impl Palette {
+    pub const PLAIN: Palette = Palette {
+        opcode: "",
+        operand: "",
+        constant: "",
+        jump: "",
+        reset: "",
+    };
+
+    pub const ANSI: Palette = Palette {
+        opcode: "\x1b[1;34m",
+        operand: "\x1b[33m",
+        constant: "\x1b[32m",
+        jump: "\x1b[35m",
+        reset: "\x1b[0m",
+    };
+}
+
+/// Renders a chunk's bytecode as text, one instruction per line.
+pub struct Disassembler<'a> {
+    chunk: &'a Chunk,
+    palette: Palette,
+    // The instruction being rendered.
+    text: String,
+    // Offsets that some jump lands on.
+    targets: HashSet<usize>,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(chunk: &'a Chunk, palette: Palette) -> Self {
+        Self {
+            chunk,
+            palette,
+            text: String::new(),
+            targets: HashSet::new(),
+        }
+    }
+
+    /// Render the whole chunk under a header. Instructions which a jump lands on are marked
+    /// with `>` in the gutter.
+    pub fn chunk(mut self, name: &str) -> String {
+        let mut lines = vec![];
+        let mut offset: usize = 0;
+        while offset < self.chunk.code.len() {
+            let (text, next) = self.instruction(offset);
+            lines.push((offset, text));
+            offset = next;
+        }
+
+        let mut out = format!("== {} ==\n", name);
+        for (offset, text) in lines {
+            let marker = if self.targets.contains(&offset) {
+                '>'
+            } else {
+                ' '
+            };
+            out.push_str(&format!("{}{}\n", marker, text));
+        }
+        out
+    }
+
+    /// Render the instruction at `offset`. Returns it along with the next offset.
+    pub fn instruction(&mut self, offset: usize) -> (String, usize) {
+        self.text = format!("{:04} ", offset);
+
+        let line = self.chunk.location(offset).line;
+        if offset > 0 && line == self.chunk.location(offset - 1).line {
+            self.text.push_str("   | ");
+        } else {
+            self.text.push_str(&format!("{:4} ", line));
+        }
+
+        let byte = self.chunk.code[offset];
+        let next = match Opcode::decode(byte) {
+            Some(opcode) => {
+                let name = opcode.name();
+                match opcode.operands() {
+                    Operands::Simple => self.simple_instruction(name, offset),
+                    Operands::Byte => self.byte_instruction(name, offset),
+                    Operands::Constant => self.constant_instruction(name, offset),
+                    Operands::Jump => self.jump_instruction(name, 1, offset),
+                    Operands::Loop => self.jump_instruction(name, -1, offset),
+                    Operands::Invoke => self.invoke_instruction(name, offset),
+                }
+            }
+            None => {
+                self.text.push_str(&format!("Unknown opcode {}", byte));
+                offset + 1
+            }
+        };
+        (mem::take(&mut self.text), next)
+    }
+
+    // Render an opcode's name, padded to line up the operands after it.
+    fn opcode(&mut self, name: &str, width: usize) {
+        let Palette { opcode, reset, .. } = self.palette;
+        self.text
+            .push_str(&format!("{}{:-width$}{}", opcode, name, reset));
+    }
+
+    // Render an instruction without operands. Returns the next offset.
+    fn simple_instruction(&mut self, name: &str, offset: usize) -> usize {
+        self.opcode(name, 0);
+        offset + 1
+    }
+
+    // Render an instruction with a single byte operand, such as a stack slot.
+    fn byte_instruction(&mut self, name: &str, offset: usize) -> usize {
+        let slot = self.chunk.code[offset + 1];
+
+        let Palette { operand, reset, .. } = self.palette;
+        self.opcode(name, 16);
+        self.text
+            .push_str(&format!(" {}{:4}{}", operand, slot, reset));
+        offset + 2
+    }
+
+    // Render a jump and the absolute offset it lands on. Returns the next offset.
+    fn jump_instruction(&mut self, name: &str, sign: i64, offset: usize) -> usize {
+        let code = &self.chunk.code;
+        let jump = u16::from_be_bytes([code[offset + 1], code[offset + 2]]);
+        let target = offset as i64 + 3 + sign * jump as i64;
+        self.targets.insert(target as usize);
+
+        let Palette {
+            operand,
+            jump: color,
+            reset,
+            ..
+        } = self.palette;
+        self.opcode(name, 16);
+        self.text.push_str(&format!(
+            " {}{:4}{} {}-> {:04}{}",
+            operand, jump, reset, color, target, reset
+        ));
+        offset + 3
+    }
+
+    // Render the constant's handle and its value. Returns the next offset.
+    fn constant_instruction(&mut self, name: &str, offset: usize) -> usize {
+        let index = self.chunk.code[offset + 1] as usize;
+
+        let Palette {
+            operand,
+            constant,
+            reset,
+            ..
+        } = self.palette;
+        self.opcode(name, 16);
+        self.text.push_str(&format!(
+            " {}{:4}{} {}{}{}",
+            operand,
+            index,
+            reset,
+            constant,
+            self.chunk.constants[index].repr(),
+            reset
+        ));
+        offset + 2
+    }
+
+    // A method name constant followed by an argument count.
+    fn invoke_instruction(&mut self, name: &str, offset: usize) -> usize {
+        let index = self.chunk.code[offset + 1] as usize;
+        let arg_count = self.chunk.code[offset + 2];
+
+        let Palette {
+            operand,
+            constant,
+            reset,
+            ..
+        } = self.palette;
+        self.opcode(name, 16);
+        self.text.push_str(&format!(
+            " ({} args) {}{:4}{} {}{}{}",
+            arg_count,
+            operand,
+            index,
+            reset,
+            constant,
+            self.chunk.constants[index].repr(),
+            reset
+        ));
+        offset + 3
+    }
+}
+
+/// Builds a chunk one instruction at a time, for tests and code generation experiments.
+///
+/// Jumps name their target with a label, which may be placed before or after them.
+///
+/// ```ignore
+/// let chunk = ChunkBuilder::new()
+///     .op(Opcode::True)
+///     .jump(Opcode::JumpIfFalse, "end")
+///     .constant(Value::Number(1.0))
+///     .op(Opcode::Negate)
+///     .label("end")
+///     .op(Opcode::Return)
+///     .build();
+/// ```
+#[derive(Default)]
+pub struct ChunkBuilder {
+    chunk: Chunk,
+    location: SourceLocation,
+    labels: HashMap<&'static str, usize>,
+    // Forward jumps waiting for their label, by the offset of their operand.
+    pending: Vec<(usize, &'static str)>,
+}
+
+impl ChunkBuilder {
+    /// Returns an empty builder emitting on line 1.
+    pub fn new() -> Self {
+        Self {
+            chunk: Chunk::new(),
+            location: SourceLocation::line(1),
+            labels: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    /// Set the source line recorded for the instructions emitted after this call.
+    pub fn line(mut self, line: u32) -> Self {
+        self.location = SourceLocation::line(line);
+        self
+    }
+
+    /// Emit an instruction without operands.
+    ///
+    /// Panics if `opcode` takes operands, which `byte`, `constant`, `jump` and `loop_to` emit.
+    pub fn op(mut self, opcode: Opcode) -> Self {
+        assert_eq!(
+            opcode.operands(),
+            Operands::Simple,
+            "{:?} takes operands.",
+            opcode
+        );
+        self.chunk.write_instruction(opcode, self.location);
+        self
+    }
+
+    /// Emit an instruction with a one-byte operand, such as a stack slot or a constant index.
+    ///
+    /// Panics if `opcode` does not take exactly one operand byte.
+    pub fn byte(mut self, opcode: Opcode, operand: u8) -> Self {
+        assert_eq!(
+            opcode.operands().width(),
+            1,
+            "{:?} does not take one operand byte.",
+            opcode
+        );
+        self.chunk.write_instruction(opcode, self.location);
+        self.chunk.write(operand, self.location);
+        self
+    }
+
+    /// Add `value` to the constant table and emit the `OP_CONSTANT` that loads it.
+    ///
+    /// Panics if the chunk already holds the maximum number of constants.
+    pub fn constant(mut self, value: Value) -> Self {
+        let index = self.chunk.add_constant(value);
+        let index = u8::try_from(index).expect("Too many constants in one chunk.");
+
+        self.chunk
+            .write_instruction(Opcode::Constant, self.location);
+        self.chunk.write(index, self.location);
+        self
+    }
+
+    /// Mark the next instruction as the target of the jumps to `label`.
+    ///
+    /// Panics if `label` is already placed, or if a jump to it would be too long.
+    pub fn label(mut self, label: &'static str) -> Self {
+        let target = self.chunk.code.len();
+        assert!(
+            self.labels.insert(label, target).is_none(),
+            "Label '{}' placed twice.",
+            label
+        );
+
+        for (offset, _) in self.pending.iter().filter(|(_, name)| *name == label) {
+            // -2 to adjust for the jump operand itself.
+            let jump = u16::try_from(target - offset - 2).expect("Too much code to jump over.");
+            let [high, low] = jump.to_be_bytes();
+            self.chunk.code[*offset] = high;
+            self.chunk.code[*offset + 1] = low;
+        }
+        self.pending.retain(|(_, name)| *name != label);
+        self
+    }
+
+    /// Emit a forward jump to `label`, which is patched once the label is placed.
+    ///
+    /// Panics if `opcode` is not a forward jump.
+    pub fn jump(mut self, opcode: Opcode, label: &'static str) -> Self {
+        assert_eq!(
+            opcode.operands(),
+            Operands::Jump,
+            "{:?} is not a forward jump.",
+            opcode
+        );
+        self.chunk.write_instruction(opcode, self.location);
+        self.pending.push((self.chunk.code.len(), label));
+        self.chunk.write(0xff, self.location);
+        self.chunk.write(0xff, self.location);
+        self
+    }
+
+    /// Emit an `OP_LOOP` back to `label`.
+    ///
+    /// Panics if `label` has not been placed yet, or if it is too far back.
+    pub fn loop_to(mut self, label: &'static str) -> Self {
+        let target = *self
+            .labels
+            .get(label)
+            .unwrap_or_else(|| panic!("Label '{}' is not placed before the loop.", label));
+
+        self.chunk.write_instruction(Opcode::Loop, self.location);
+        // +2 to jump over the loop operand itself.
+        let offset =
+            u16::try_from(self.chunk.code.len() - target + 2).expect("Loop body too large.");
+        let [high, low] = offset.to_be_bytes();
+        self.chunk.write(high, self.location);
+        self.chunk.write(low, self.location);
+        self
+    }
+
+    /// Finish building and return the chunk.
+    ///
+    /// Panics if a jump names a label which was never placed.
+    pub fn build(self) -> Chunk {
+        if let Some((_, label)) = self.pending.first() {
+            panic!("Label '{}' is never placed.", label);
+        }
+        self.chunk
+    }
+}
+
+//
+// Token.
+//
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+    // Single-character tokens.
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
+    Comma,
+    Dot,
+    Minus,
+    Plus,
+    Semicolon,
+    Slash,
+    Star,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    Tilde,
+    // One or two character tokens.
+    StarStar,
+    DotDotDot,
+    Bang,
+    BangEqual,
+    Equal,
+    EqualEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    LessLess,
+    GreaterGreater,
+    Question,
+    QuestionQuestion,
+    QuestionDot,
+    // Literals.
+    Identifier,
+    String,
+    Interpolation, // A string segment ending in `${`.
+    Number,
+    Decimal,
+    // Keywords.
+    And,
+    Break,
+    Case,
+    Class,
+    Const,
+    Continue,
+    Default,
+    Defer,
+    Else,
+    False,
+    For,
+    Fun,
+    If,
+    Nil,
+    Or,
+    Print,
+    Return,
+    Static,
+    Super,
+    Switch,
+    This,
+    True,
+    Using,
+    Var,
+    While,
+
+    Error,
+    Eof,
+}
+
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    kind: TokenKind,
+    start: usize,
+    length: usize,
+    line: usize,
+    column: usize,
+    source: &'a str,
+}
+
+impl<'a> Token<'a> {
+    fn lexeme(&self) -> &'a str {
+        match self.kind {
+            // Error tokens carry their message as the source.
+            TokenKind::Error => self.source,
+            _ => &self.source[self.start..self.start + self.length],
+        }
+    }
+
+    fn new(
+        tty: TokenKind,
+        start: usize,
+        length: usize,
+        line: usize,
+        column: usize,
+        source: &'a str,
+    ) -> Self {
+        Self {
+            kind: tty,
+            start,
+            length,
+            line,
+            column,
+            source,
+        }
+    }
+
+    fn dummy() -> Self {
+        Token::new(TokenKind::Eof, 0, 0, 0, 0, "")
+    }
+
+    // An identifier the compiler refers to which does not appear in the source, e.g. `this`.
+    fn synthetic(name: &'static str) -> Self {
+        Token::new(TokenKind::Identifier, 0, name.len(), 0, 0, name)
+    }
+}
+
+//
+// Scanner.
+//
+#[derive(Clone)]
+struct Scanner<'a> {
+    current: usize,
+    line: usize,
+    // Offset of the first character on the current line.
+    line_start: usize,
+    source: &'a str,
+    start: usize,
+    // Where the token being scanned starts.
+    start_line: usize,
+    start_column: usize,
+    // Open braces inside each interpolation being scanned, innermost last. The `}` which closes an
+    // interpolation resumes its string.
+    interpolations: Vec<usize>,
+}
+
+impl<'a> Scanner<'a> {
+    // Creates a new [`Scanner`].
+    fn new(source: &'a str) -> Self {
+        Self {
+            current: 0,
+            line: 1,
+            line_start: 0,
+            start: 0,
+            start_line: 1,
+            start_column: 1,
+            source,
+            interpolations: vec![],
+        }
+    }
+
+    // Note that a newline was just consumed.
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
+    // Returns an error token if a block comment is never closed.
+    fn skip_whitespace(&mut self) -> Option<Token<'a>> {
+        loop {
+            let c: char = self.peek();
+            match c {
+                // General whitespace.
+                ' ' | '\r' | '\t' => {
+                    self.advance();
+                }
+                // Handle newline.
+                '\n' => {
+                    self.advance();
+                    self.new_line();
+                }
+                // Handle comments.
+                '/' if self.peek_offset(1) == '/' => {
+                    // Ignore until newline or scanner exhausted.
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
+                    }
+                }
+                '/' if self.peek_offset(1) == '*' => {
+                    // Errors are reported where the comment opened.
+                    self.begin_token();
+                    if !self.block_comment() {
+                        return Some(self.error_token("Unterminated comment."));
+                    }
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    // Skip a `/* ... */` comment, which may contain other block comments. Returns false if the
+    // source ends first.
+    fn block_comment(&mut self) -> bool {
+        let mut depth = 0;
+        loop {
+            if self.is_at_end() {
+                return false;
+            }
+
+            if self.peek() == '/' && self.peek_offset(1) == '*' {
+                self.current += 2;
+                depth += 1;
+            } else if self.peek() == '*' && self.peek_offset(1) == '/' {
+                self.current += 2;
+                depth -= 1;
+                if depth == 0 {
+                    return true;
+                }
+            } else if self.advance() == '\n' {
+                self.new_line();
+            }
+        }
+    }
+
+    // Mark the current position as the start of the next token.
+    fn begin_token(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.start - self.line_start + 1;
+    }
+
+    // Scan the next token.
+    fn scan_token(&mut self) -> Token<'a> {
+        if let Some(error) = self.skip_whitespace() {
+            return error;
+        }
+        self.begin_token();
+
+        if self.is_at_end() {
+            return self.make_token(TokenKind::Eof);
+        }
+
+        let c = self.advance();
+
+        if c.is_ascii_digit() {
+            return self.number();
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            return self.identifer();
+        }
+
+        match c {
+            '(' => return self.make_token(TokenKind::LeftParen),
+            ')' => return self.make_token(TokenKind::RightParen),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                return self.make_token(TokenKind::LeftBrace);
+            }
+            '}' => {
+                match self.interpolations.last_mut() {
+                    Some(0) => {
+                        self.interpolations.pop();
+                        return self.string();
+                    }
+                    Some(depth) => *depth -= 1,
+                    None => {}
+                }
+                return self.make_token(TokenKind::RightBrace);
+            }
+            '[' => return self.make_token(TokenKind::LeftBracket),
+            ']' => return self.make_token(TokenKind::RightBracket),
+            ';' => return self.make_token(TokenKind::Semicolon),
+            ':' => return self.make_token(TokenKind::Colon),
+            ',' => return self.make_token(TokenKind::Comma),
+            '.' if self.peek() == '.' && self.peek_offset(1) == '.' => {
+                self.advance();
+                self.advance();
+                return self.make_token(TokenKind::DotDotDot);
+            }
+            '.' => return self.make_token(TokenKind::Dot),
+            '-' => return self.make_token(TokenKind::Minus),
+            '+' => return self.make_token(TokenKind::Plus),
+            '/' => return self.make_token(TokenKind::Slash),
+            '*' if self.match_char('*') => return self.make_token(TokenKind::StarStar),
+            '*' => return self.make_token(TokenKind::Star),
+            '%' => return self.make_token(TokenKind::Percent),
+            '&' => return self.make_token(TokenKind::Ampersand),
+            '|' => return self.make_token(TokenKind::Pipe),
+            '^' => return self.make_token(TokenKind::Caret),
+            '~' => return self.make_token(TokenKind::Tilde),
+            '!' => {
+                if self.match_char('=') {
+                    return self.make_token(TokenKind::BangEqual);
+                } else {
+                    return self.make_token(TokenKind::Bang);
+                }
+            }
+            '=' => {
+                if self.match_char('=') {
+                    return self.make_token(TokenKind::EqualEqual);
+                } else {
+                    return self.make_token(TokenKind::Equal);
+                }
+            }
+            '<' => {
+                if self.match_char('<') {
+                    return self.make_token(TokenKind::LessLess);
+                } else if self.match_char('=') {
+                    return self.make_token(TokenKind::LessEqual);
+                } else {
+                    return self.make_token(TokenKind::Less);
+                }
+            }
+            '>' => {
+                if self.match_char('>') {
+                    return self.make_token(TokenKind::GreaterGreater);
+                } else if self.match_char('=') {
+                    return self.make_token(TokenKind::GreaterEqual);
+                } else {
+                    return self.make_token(TokenKind::Greater);
+                }
+            }
+            '?' if self.match_char('?') => return self.make_token(TokenKind::QuestionQuestion),
+            '?' if self.match_char('.') => return self.make_token(TokenKind::QuestionDot),
+            '?' => return self.make_token(TokenKind::Question),
+            '"' => {
+                return self.string();
+            }
+            _ => {}
+        }
+
+        self.error_token("Unexpected character.")
+    }
+
+    // Advance to the next character.
+    fn advance(&mut self) -> char {
+        let c = self.peek();
+        self.current += 1;
+        c
+    }
+
+    // Lox syntax is ASCII, so scanning by byte is enough. Multi-byte characters can only appear
+    // inside strings, where their bytes never match a quote or newline.
+    fn peek_offset(&self, idx: usize) -> char {
+        self.char_at(self.current + idx)
+    }
+
+    fn char_at(&self, offset: usize) -> char {
+        self.source
+            .as_bytes()
+            .get(offset)
+            .map_or('\0', |&byte| byte as char)
+    }
+
+    fn peek(&self) -> char {
+        self.peek_offset(0)
+    }
+
+    fn match_char(&mut self, expected: char) -> bool {
+        if self.is_at_end() || self.peek() != expected {
+            false
+        } else {
+            self.current += 1;
+            true
+        }
+    }
+
+    // Returns true when the scanner is exhausted.
+    fn is_at_end(&self) -> bool {
+        self.current >= self.source.len()
+    }
+
+    // Create a new token of given kind.
+    fn make_token(&self, tty: TokenKind) -> Token<'a> {
+        Token::new(
+            tty,
+            self.start,
+            self.current - self.start,
+            self.start_line,
+            self.start_column,
+            self.source,
+        )
+    }
+
+    // Create a new error token with the specific message.
+    fn error_token(&self, message: &'static str) -> Token<'a> {
+        Token {
+            kind: TokenKind::Error,
+            start: self.start,
+            length: self.current - self.start,
+            line: self.start_line,
+            column: self.start_column,
+            source: message,
+        }
+    }
+
+    // Scan the rest of a string, after its opening `"` or the `}` ending an interpolation.
+    fn string(&mut self) -> Token<'a> {
+        while self.peek() != '"' && !self.is_at_end() {
+            if self.peek() == '$' && self.peek_offset(1) == '{' {
+                self.current += 2;
+                self.interpolations.push(0);
+                return self.make_token(TokenKind::Interpolation);
+            }
+
+            let c = self.advance();
+            if c == '\n' {
+                self.new_line();
+            }
+        }
+
+        if self.peek() != '"' {
+            self.error_token("Unterminated string")
+        } else {
+            _ = self.advance();
+            self.make_token(TokenKind::String)
+        }
+    }
+
+    fn number(&mut self) -> Token<'a> {
+        while self.peek().is_ascii_digit() {
+            _ = self.advance();
+        }
+
+        // Handle fraction.
+        if self.peek() == '.' {
+            self.advance();
+
+            while self.peek().is_ascii_digit() {
+                _ = self.advance();
+            }
+        }
+
+        // Handle the decimal suffix, e.g. `1.10d`.
+        let next = self.peek_offset(1);
+        if self.peek() == 'd' && !(next.is_ascii_alphanumeric() || next == '_') {
+            self.advance();
+            return self.make_token(TokenKind::Decimal);
+        }
+
+        self.make_token(TokenKind::Number)
+    }
+
+    fn identifer(&mut self) -> Token<'a> {
+        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
+            self.advance();
+        }
+        self.make_token(self.identifer_type())
+    }
+
+    fn identifer_type(&self) -> TokenKind {
+        match self.char_at(self.start) {
+            'a' => return self.check_keyword(1, "nd", TokenKind::And),
+            'b' => return self.check_keyword(1, "reak", TokenKind::Break),
+            'c' if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                'a' => return self.check_keyword(2, "se", TokenKind::Case),
+                'l' => return self.check_keyword(2, "ass", TokenKind::Class),
+                'o' if self.current - self.start > 3 => match self.char_at(self.start + 3) {
+                    's' => return self.check_keyword(2, "nst", TokenKind::Const),
+                    't' => return self.check_keyword(2, "ntinue", TokenKind::Continue),
+                    _ => {}
+                },
+                _ => {}
+            },
+            'd' if self.current - self.start > 3 => match self.char_at(self.start + 3) {
+                'a' => return self.check_keyword(1, "efault", TokenKind::Default),
+                'e' => return self.check_keyword(1, "efer", TokenKind::Defer),
+                _ => {}
+            },
+            'e' => return self.check_keyword(1, "lse", TokenKind::Else),
+            'f' if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                'a' => return self.check_keyword(2, "lse", TokenKind::False),
+                'o' => return self.check_keyword(2, "r", TokenKind::For),
+                'u' => return self.check_keyword(2, "n", TokenKind::Fun),
+                _ => {}
+            },
+            't' if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                'h' => return self.check_keyword(2, "is", TokenKind::This),
+                'r' => return self.check_keyword(2, "ue", TokenKind::True),
+                _ => {}
+            },
+            'i' => return self.check_keyword(1, "f", TokenKind::If),
+            'n' => return self.check_keyword(1, "il", TokenKind::Nil),
+            'o' => return self.check_keyword(1, "r", TokenKind::Or),
+            'p' => return self.check_keyword(1, "rint", TokenKind::Print),
+            'r' => return self.check_keyword(1, "eturn", TokenKind::Return),
+            's' if self.current - self.start > 1 => match self.char_at(self.start + 1) {
+                't' => return self.check_keyword(2, "atic", TokenKind::Static),
+                'u' => return self.check_keyword(2, "per", TokenKind::Super),
+                'w' => return self.check_keyword(2, "itch", TokenKind::Switch),
+                _ => {}
+            },
+            'u' => return self.check_keyword(1, "sing", TokenKind::Using),
+            'v' => return self.check_keyword(1, "ar", TokenKind::Var),
+            'w' => return self.check_keyword(1, "hile", TokenKind::While),
+            _ => {}
+        }
+
+        TokenKind::Identifier
+    }
+
+    fn check_keyword(&self, offset: usize, expected: &str, kind: TokenKind) -> TokenKind {
+        let length_matches = (self.current - self.start) == expected.len() + offset;
+
+        // Only compare once the lengths agree, shorter identifiers would slice past their end.
+        let keyword_found = length_matches
+            && &self.source[self.start + offset..self.start + offset + expected.len()] == expected;
+
+        if keyword_found {
+            kind
+        } else {
+            TokenKind::Identifier
+        }
+    }
+}
+
+//
+// The parser.
+//
+struct Parser<'a> {
+    scanner: Scanner<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    had_error: bool,
+    chunk: Chunk,
+
+    // The file being compiled, recorded in the chunk's source map.
+    file: FileId,
+    // Its display name for diagnostics, if it has one.
+    file_name: Option<&'a str>,
+    // Where errors are reported.
+    console: Rc<RefCell<Console>>,
+
+    // Flag for sane error reporting.
+    // Resync the state of the parser.
+    panic: bool,
+
+    // Locals in scope, in declaration order, which matches their stack slots.
+    locals: Vec<Local<'a>>,
+    // How many blocks deep we are. Zero is the global scope.
+    scope_depth: usize,
+
+    // The name of the function being compiled, `None` for the script.
+    function_name: Option<Rc<str>>,
+    function_kind: FunctionKind,
+    // The functions this one is nested in, innermost last, waiting for it to be compiled.
+    enclosing: Vec<EnclosingFunction<'a>>,
+
+    // The class bodies we are in, innermost last. `this` is only meaningful inside one.
+    classes: Vec<ClassCompiler>,
+
+    // The loops in the function being compiled, innermost last.
+    loops: Vec<Loop>,
+
+    // The `defer` statements in scope in the function being compiled, in order.
+    defers: Vec<Defer<'a>>,
+}
+
+// A loop whose body is being compiled.
+struct Loop {
+    // Where `continue` jumps back to: the condition, or a `for` loop's increment.
+    start: usize,
+    // The scope depth outside the body. Locals deeper than this are popped when leaving early.
+    scope_depth: usize,
+    // `break` jumps, patched to land after the loop once it is compiled.
+    breaks: Vec<usize>,
+}
+
+// Code to run when a scope is left. It is compiled again at every exit from the scope.
+#[derive(Clone)]
+struct Defer<'a> {
+    code: Deferred<'a>,
+    scope_depth: usize,
+    // How many locals were declared before it. Only those are visible to the expression.
+    local_count: usize,
+}
+
+// What a scope runs on its way out.
+#[derive(Clone)]
+enum Deferred<'a> {
+    // A `defer` statement's expression: its first token, and the scanner just after it.
+    Expression {
+        start: Token<'a>,
+        scanner: Scanner<'a>,
+    },
+    // Calling `close()` on the resource a `using` statement holds in this slot.
+    Close(u8),
+}
+
+// A class whose body is being compiled.
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+// What kind of code a function's body is, which decides what `return` may do in it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    // An `init` method, which always returns its instance.
+    Initializer,
+    // A method called on the class rather than an instance, so it has no `this`.
+    StaticMethod,
+}
+
+// The compilation state of a function suspended while a function declared in it is compiled.
+struct EnclosingFunction<'a> {
+    chunk: Chunk,
+    locals: Vec<Local<'a>>,
+    scope_depth: usize,
+    function_name: Option<Rc<str>>,
+    function_kind: FunctionKind,
+    loops: Vec<Loop>,
+    defers: Vec<Defer<'a>>,
+}
+
+// A local variable known to the compiler.
+#[derive(Clone, Copy)]
+struct Local<'a> {
+    name: Token<'a>,
+    // The scope depth it was declared at, or `None` while its initializer is compiled.
+    depth: Option<usize>,
+    // Declared with `const`, so it can't be assigned to.
+    constant: bool,
+}
+
+impl Local<'_> {
+    // Slot zero of every call frame holds the function being called. Its empty name can never
+    // be referenced.
+    fn reserved() -> Self {
+        Self {
+            name: Token::dummy(),
+            depth: Some(0),
+            constant: false,
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, file: FileId, file_name: Option<&'a str>) -> Self {
+        Self {
+            scanner: Scanner::new(source),
+            current: Token::dummy(),
+            previous: Token::dummy(),
+            had_error: false,
+            panic: false,
+            chunk: Chunk::new(),
+            file,
+            file_name,
+            console: Rc::default(),
+            locals: vec![Local::reserved()],
+            scope_depth: 0,
+            function_name: None,
+            function_kind: FunctionKind::Script,
+            enclosing: vec![],
+            classes: vec![],
+            loops: vec![],
+            defers: vec![],
+        }
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current;
+
+        loop {
+            self.current = self.scanner.scan_token();
+            if self.current.kind != TokenKind::Error {
+                break;
+            }
+
+            let lexeme = self.current.lexeme();
+            self.report_error_at_current(lexeme);
+        }
+    }
+
+    fn check(&self, kind: TokenKind) -> bool {
+        self.current.kind == kind
+    }
+
+    // Consume the current token if it is of the given kind.
+    fn match_token(&mut self, kind: TokenKind) -> bool {
+        if !self.check(kind) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn consume(&mut self, kind: TokenKind, message: &str) {
+        let got_expected = self.current.kind == kind;
+
+        if got_expected {
+            self.advance();
+        } else {
+            self.report_error_at_current(message);
+        }
+    }
+
+    fn report_error_at(&mut self, token: Token, message: &str) {
+        if self.panic {
+            return;
+        }
+
+        self.panic = true;
+
+        let mut report = format!(
+            "[{}] Error",
+            describe_location(self.file_name, token.line as u32, token.column as u32)
+        );
+
+        if token.kind == TokenKind::Eof {
+            report.push_str(" at end");
+        } else if token.kind == TokenKind::Error {
+            // Do nothing.
+        } else {
+            report.push_str(&format!(" at {}", token.lexeme()));
+        }
+
+        // Print error message
+        let report = format!("{}: {}\n", report, message);
+        self.console.borrow_mut().write_error(&report);
+
+        self.had_error = true;
+    }
+
+    // Report a problem which doesn't stop the program from compiling.
+    fn report_warning_at(&mut self, token: Token, message: &str) {
+        if self.panic {
+            return;
+        }
+
+        let report = format!(
+            "[{}] Warning at {}: {}\n",
+            describe_location(self.file_name, token.line as u32, token.column as u32),
+            token.lexeme(),
+            message
+        );
+        self.console.borrow_mut().write_error(&report);
+    }
+
+    fn report_error_at_current(&mut self, message: &str) {
+        let token = self.current;
+        self.report_error_at(token, message);
+    }
+
+    #[allow(dead_code)]
+    fn report_error(&mut self, message: &str) {
+        let token = self.previous;
+        self.report_error_at(token, message);
+    }
+
+    #[allow(dead_code)]
+    fn emit_byte(&mut self, byte: u8) {
+        let location = SourceLocation {
+            file: self.file,
+            line: self.previous.line as u32,
+            column: self.previous.column as u32,
+        };
+        self.chunk.write(byte, location);
+    }
+
+    fn emit_opcode(&mut self, opcode: Opcode) {
+        self.emit_byte(opcode as u8);
+    }
+
+    #[allow(dead_code)]
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    // Emit a jump with a placeholder operand. Returns the operand's offset for `patch_jump`.
+    fn emit_jump(&mut self, opcode: Opcode) -> usize {
+        self.emit_opcode(opcode);
+        self.emit_bytes(0xff, 0xff);
+        self.chunk.code.len() - 2
+    }
+
+    // Emit a jump back to the instruction at `loop_start`.
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_opcode(Opcode::Loop);
+
+        // +2 to jump over the loop operand itself.
+        let offset = self.chunk.code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.report_error("Loop body too large.");
+        }
+
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_bytes(high, low);
+    }
+
+    // Where the chunk ends so far, for `discard_code` to go back to.
+    fn code_mark(&self) -> (usize, usize) {
+        (self.chunk.code.len(), self.chunk.constants.len())
+    }
+
+    // Drop everything emitted since `mark`, along with the breaks waiting to be patched in it.
+    fn discard_code(&mut self, mark: (usize, usize)) {
+        let (offset, constants) = mark;
+        self.chunk.truncate(offset, constants);
+        for enclosing in &mut self.loops {
+            enclosing.breaks.retain(|jump| *jump < offset);
+        }
+    }
+
+    // Whether the condition compiled from `start` is always true or always false: a literal,
+    // possibly negated.
+    fn constant_condition(&self, start: usize) -> Option<bool> {
+        let code = &self.chunk.code[start..];
+        let nots = code
+            .iter()
+            .rev()
+            .take_while(|byte| **byte == Opcode::Not as u8)
+            .count();
+        let value = match Opcode::decode(*code.first()?)? {
+            Opcode::True if code.len() == 1 + nots => true,
+            Opcode::False | Opcode::Nil if code.len() == 1 + nots => false,
+            Opcode::Constant if code.len() == 2 + nots => {
+                !self.chunk.constants[code[1] as usize].is_falsey()
+            }
+            Opcode::SmallInteger if code.len() == 2 + nots => true,
+            _ => return None,
+        };
+        Some(value ^ (nots % 2 == 1))
+    }
+
+    // Compile a statement which can never run, so its errors are still reported, then drop its
+    // code with a warning.
+    fn unreachable_statement(&mut self) {
+        let token = self.current;
+        let mark = self.code_mark();
+        self.statement();
+        self.discard_code(mark);
+        self.report_warning_at(token, "Unreachable code.");
+    }
+
+    // Point the jump whose operand lives at `offset` to the next instruction to be emitted.
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump operand itself.
+        let jump = self.chunk.code.len() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.report_error("Too much code to jump over.");
+        }
+
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.chunk.code[offset] = high;
+        self.chunk.code[offset + 1] = low;
+    }
+
+    // Finish the function, whose calls start with the callee and its `params` parameters.
+    #[allow(dead_code)]
+    fn end(&mut self, params: usize) {
+        self.emit_opcode(Opcode::Return);
+
+        // A mismatch is a compiler bug, which is best caught here rather than as a corrupted stack.
+        if cfg!(debug_assertions) && !self.had_error {
+            if let Err(message) = self.chunk.check_stack_depth(1 + params) {
+                let name = self.function_name.as_deref().unwrap_or("<script>");
+                let listing = Disassembler::new(&self.chunk, Palette::PLAIN).chunk(name);
+                panic!("Inconsistent stack in {}: {}\n{}", name, message, listing);
+            }
+        }
+
+        if cfg!(feature = "debug_print_code") && !self.had_error {
+            let name = self.function_name.as_deref().unwrap_or("<script>");
+            self.chunk.disassemble_chunk(name);
+        }
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn declaration(&mut self) {
+        if self.match_token(TokenKind::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenKind::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenKind::Var) {
+            self.var_declaration();
+        } else if self.match_token(TokenKind::Const) {
+            self.const_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic {
+            self.synchronize();
+        }
+    }
+
+    // Skip tokens until a likely statement boundary so one mistake reports one error.
+    fn synchronize(&mut self) {
+        self.panic = false;
+
+        while self.current.kind != TokenKind::Eof {
+            if self.previous.kind == TokenKind::Semicolon {
+                return;
+            }
+
+            match self.current.kind {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::Const
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Switch
+                | TokenKind::Print
+                | TokenKind::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenKind::Identifier, "Expect class name.");
+        let class_name = self.previous;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_bytes(Opcode::Class as u8, name_constant);
+        self.define_variable(name_constant);
+
+        let mut class = ClassCompiler {
+            has_superclass: false,
+        };
+        if self.match_token(TokenKind::Less) {
+            self.consume(TokenKind::Identifier, "Expect superclass name.");
+            if self.previous.lexeme() == class_name.lexeme() {
+                self.report_error("A class can't inherit from itself.");
+            }
+            self.variable(false);
+            self.named_variable(class_name, false);
+            self.emit_opcode(Opcode::Inherit);
+            class.has_superclass = true;
+        }
+        self.classes.push(class);
+
+        // The class stays on the stack while its methods are attached to it.
+        self.named_variable(class_name, false);
+        self.consume(TokenKind::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.method();
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after class body.");
+        self.emit_opcode(Opcode::Pop);
+        self.classes.pop();
+    }
+
+    fn method(&mut self) {
+        let is_static = self.match_token(TokenKind::Static);
+        self.consume(TokenKind::Identifier, "Expect method name.");
+        let constant = self.identifier_constant(self.previous);
+
+        if is_static {
+            self.function(FunctionKind::StaticMethod);
+            self.emit_bytes(Opcode::StaticMethod as u8, constant);
+            return;
+        }
+
+        let kind = if self.previous.lexeme() == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(kind);
+        self.emit_bytes(Opcode::Method as u8, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function may refer to itself, so its name is usable before the body is compiled.
+        self.mark_initialized();
+        self.function(FunctionKind::Function);
+        self.define_variable(global);
+    }
+
+    // Compile a function's parameters and body, which follow its name, then load it.
+    fn function(&mut self, kind: FunctionKind) {
+        let name = Rc::from(self.previous.lexeme());
+        self.begin_function(name, kind);
+        self.begin_scope();
+
+        let mut arity: usize = 0;
+        let mut variadic = false;
+        self.consume(TokenKind::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                // The rest parameter holds a list of the remaining arguments.
+                if self.match_token(TokenKind::DotDotDot) {
+                    let constant = self.parse_variable("Expect parameter name after '...'.");
+                    self.define_variable(constant);
+                    variadic = true;
+
+                    if !self.check(TokenKind::RightParen) {
+                        self.report_error_at_current("Rest parameter must be the last one.");
+                    }
+                    break;
+                }
+
+                arity += 1;
+                if arity > u8::MAX as usize {
+                    self.report_error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        // No need to end the scope, the frame's slots are discarded when it returns.
+        let function = self.end_function(arity as u8, variadic);
+        self.emit_constant(Value::Function(Rc::new(function)));
+    }
+
+    // Suspend the current function to compile a new one nested in it.
+    fn begin_function(&mut self, name: Rc<str>, kind: FunctionKind) {
+        // Methods find their instance in slot zero.
+        let reserved = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => Local {
+                name: Token::synthetic("this"),
+                depth: Some(0),
+                constant: false,
+            },
+            _ => Local::reserved(),
+        };
+        let enclosing = EnclosingFunction {
+            chunk: mem::take(&mut self.chunk),
+            locals: mem::replace(&mut self.locals, vec![reserved]),
+            scope_depth: mem::replace(&mut self.scope_depth, 0),
+            function_name: self.function_name.replace(name),
+            function_kind: mem::replace(&mut self.function_kind, kind),
+            loops: mem::take(&mut self.loops),
+            defers: mem::take(&mut self.defers),
+        };
+        self.enclosing.push(enclosing);
+    }
+
+    // Finish the innermost function and resume the one it is nested in.
+    fn end_function(&mut self, arity: u8, variadic: bool) -> Function {
+        self.emit_defers_from(0);
+        self.emit_implicit_return_value();
+        self.end(arity as usize + variadic as usize);
+
+        let enclosing = self.enclosing.pop().unwrap();
+        self.locals = enclosing.locals;
+        self.scope_depth = enclosing.scope_depth;
+        self.function_kind = enclosing.function_kind;
+        self.loops = enclosing.loops;
+        self.defers = enclosing.defers;
+        Function {
+            arity,
+            variadic,
+            chunk: mem::replace(&mut self.chunk, enclosing.chunk),
+            name: mem::replace(&mut self.function_name, enclosing.function_name),
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenKind::Equal) {
+            self.expression();
+        } else {
+            self.emit_opcode(Opcode::Nil);
+        }
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    // Like a `var` declaration, but the variable must be initialized and can't be assigned to.
+    fn const_declaration(&mut self) {
+        let global = self.parse_variable("Expect constant name.");
+        if self.scope_depth > 0 {
+            if let Some(local) = self.locals.last_mut() {
+                local.constant = true;
+            }
+        }
+
+        self.consume(TokenKind::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit_bytes(Opcode::DefineConstGlobal as u8, global);
+        }
+    }
+
+    // Consume a variable name. Returns the constant index holding it for globals, and 0 for
+    // locals, which live on the stack instead.
+    fn parse_variable(&mut self, message: &str) -> u8 {
+        self.consume(TokenKind::Identifier, message);
+
+        self.declare_variable();
+        if self.scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(self.previous)
+    }
+
+    // Make the variable just initialized available. A local is already in its stack slot.
+    fn define_variable(&mut self, global: u8) {
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_bytes(Opcode::DefineGlobal as u8, global);
+    }
+
+    // Record a local variable. Globals are late bound, so they are not declared.
+    fn declare_variable(&mut self) {
+        if self.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous;
+        let duplicate = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= self.scope_depth))
+            .any(|local| local.name.lexeme() == name.lexeme());
+        if duplicate {
+            self.report_error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn add_local(&mut self, name: Token<'a>) {
+        if self.locals.len() == UINT8_COUNT {
+            self.report_error("Too many local variables in function.");
+            return;
+        }
+
+        self.locals.push(Local {
+            name,
+            depth: None,
+            constant: false,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.scope_depth == 0 {
+            return;
+        }
+
+        let depth = self.scope_depth;
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+
+    // Find the stack slot of the innermost local called `name`.
+    fn resolve_local(&mut self, name: Token) -> Option<u8> {
+        let (slot, local) = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.lexeme() == name.lexeme())?;
+
+        if local.depth.is_none() {
+            self.report_error("Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    // Leave a block, running its deferred code and popping the locals declared in it.
+    fn end_scope(&mut self) {
+        self.emit_defers_from(self.scope_depth);
+        self.defers
+            .retain(|defer| defer.scope_depth < self.scope_depth);
+        self.scope_depth -= 1;
+
+        while let Some(local) = self.locals.last() {
+            if local.depth.is_some_and(|depth| depth <= self.scope_depth) {
+                break;
+            }
+            self.emit_opcode(Opcode::Pop);
+            self.locals.pop();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            self.declaration();
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after block.");
+    }
+
+    // Store an identifier's name in the constant table, globals are looked up by name at runtime.
+    fn identifier_constant(&mut self, name: Token) -> u8 {
+        let name = LoxString::new(name.lexeme());
+        self.make_constant(Value::String(Rc::new(name)))
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.previous, can_assign);
+    }
+
+    fn named_variable(&mut self, name: Token, can_assign: bool) {
+        let (get_op, set_op, arg) = match self.resolve_local(name) {
+            Some(slot) => (Opcode::GetLocal, Opcode::SetLocal, slot),
+            None => {
+                let arg = self.identifier_constant(name);
+                (Opcode::GetGlobal, Opcode::SetGlobal, arg)
+            }
+        };
+
+        if can_assign && self.match_token(TokenKind::Equal) {
+            // Globals may be redeclared at any time, so only the VM knows whether they're const.
+            if get_op == Opcode::GetLocal && self.locals[arg as usize].constant {
+                let message = format!("Can't assign to constant '{}'.", name.lexeme());
+                self.report_error_at(name, &message);
+            }
+            self.expression();
+            self.emit_bytes(set_op as u8, arg);
+        } else {
+            self.emit_bytes(get_op as u8, arg);
+        }
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(TokenKind::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenKind::If) {
+            self.if_statement();
+        } else if self.match_token(TokenKind::While) {
+            self.while_statement();
+        } else if self.match_token(TokenKind::For) {
+            self.for_statement();
+        } else if self.match_token(TokenKind::Switch) {
+            self.switch_statement();
+        } else if self.match_token(TokenKind::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenKind::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenKind::Continue) {
+            self.continue_statement();
+        } else if self.match_token(TokenKind::Defer) {
+            self.defer_statement();
+        } else if self.match_token(TokenKind::Using) {
+            self.using_statement();
+        } else if self.match_token(TokenKind::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    // An expression evaluated for its side effects. The result is discarded.
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenKind::Semicolon, "Expect ';' after expression.");
+        self.emit_opcode(Opcode::Pop);
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.");
+        let condition = self.code_mark();
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.");
+
+        // Only the branch a constant condition picks is kept, with no test or jumps around it.
+        if let Some(taken) = self.constant_condition(condition.0) {
+            self.discard_code(condition);
+            if taken {
+                self.statement();
+            } else {
+                self.unreachable_statement();
+            }
+            if self.match_token(TokenKind::Else) {
+                if taken {
+                    self.unreachable_statement();
+                } else {
+                    self.statement();
+                }
+            }
+            return;
+        }
+
+        // Each branch pops the condition on its way in.
+        let then_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_opcode(Opcode::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(Opcode::Jump);
+        self.patch_jump(then_jump);
+        self.emit_opcode(Opcode::Pop);
+
+        if self.match_token(TokenKind::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.chunk.code.len();
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.");
+        let condition = self.code_mark();
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.");
+
+        // A loop which always runs is left only by `break`, and one which never runs is dropped.
+        match self.constant_condition(loop_start) {
+            Some(true) => {
+                self.discard_code(condition);
+                self.begin_loop(loop_start);
+                self.statement();
+                self.emit_loop(loop_start);
+                self.end_loop();
+                return;
+            }
+            Some(false) => {
+                self.discard_code(condition);
+                self.begin_loop(loop_start);
+                self.unreachable_statement();
+                self.end_loop();
+                return;
+            }
+            None => {}
+        }
+
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_opcode(Opcode::Pop);
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_opcode(Opcode::Pop);
+        self.end_loop();
+    }
+
+    // Start compiling the body of a loop which repeats from `start`.
+    fn begin_loop(&mut self, start: usize) {
+        self.loops.push(Loop {
+            start,
+            scope_depth: self.scope_depth,
+            breaks: vec![],
+        });
+    }
+
+    // Finish a loop. Breaks land here, past where the condition is popped, since they leave
+    // from the body after it already was.
+    fn end_loop(&mut self) {
+        let finished = self.loops.pop().unwrap();
+        for jump in finished.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        self.consume(TokenKind::Semicolon, "Expect ';' after 'break'.");
+        let Some(scope_depth) = self.loops.last().map(|enclosing| enclosing.scope_depth) else {
+            self.report_error("Can't use 'break' outside of a loop.");
+            return;
+        };
+
+        self.emit_defers_from(scope_depth + 1);
+        self.pop_locals_deeper_than(scope_depth);
+        let jump = self.emit_jump(Opcode::Jump);
+        self.loops.last_mut().unwrap().breaks.push(jump);
+    }
+
+    fn continue_statement(&mut self) {
+        self.consume(TokenKind::Semicolon, "Expect ';' after 'continue'.");
+        let Some(innermost) = self.loops.last() else {
+            self.report_error("Can't use 'continue' outside of a loop.");
+            return;
+        };
+
+        let (start, scope_depth) = (innermost.start, innermost.scope_depth);
+        self.emit_defers_from(scope_depth + 1);
+        self.pop_locals_deeper_than(scope_depth);
+        self.emit_loop(start);
+    }
+
+    // Discard the locals of the scopes being jumped out of. They stay known to the compiler,
+    // since the code after the jump is still in their scope.
+    fn pop_locals_deeper_than(&mut self, depth: usize) {
+        let count = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|local_depth| local_depth > depth))
+            .count();
+        for _ in 0..count {
+            self.emit_opcode(Opcode::Pop);
+        }
+    }
+
+    // `for (init; cond; incr) body` becomes a while loop, with the increment compiled before the
+    // body and jumped over on the way in. The initializer's variable is scoped to the loop.
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.");
+        if self.match_token(TokenKind::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenKind::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.chunk.code.len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenKind::Semicolon) {
+            self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(Opcode::JumpIfFalse));
+            self.emit_opcode(Opcode::Pop);
+        }
+
+        if !self.match_token(TokenKind::RightParen) {
+            let body_jump = self.emit_jump(Opcode::Jump);
+            let increment_start = self.chunk.code.len();
+            self.expression();
+            self.emit_opcode(Opcode::Pop);
+            self.consume(TokenKind::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.begin_loop(loop_start);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_opcode(Opcode::Pop);
+        }
+        self.end_loop();
+        self.end_scope();
+    }
+
+    // What a function returns when it falls off the end or returns without a value: nil, or the
+    // instance for an initializer.
+    fn emit_implicit_return_value(&mut self) {
+        if self.function_kind == FunctionKind::Initializer {
+            self.emit_bytes(Opcode::GetLocal as u8, 0);
+        } else {
+            self.emit_opcode(Opcode::Nil);
+        }
+    }
+
+    fn return_statement(&mut self) {
+        if self.function_kind == FunctionKind::Script {
+            self.report_error("Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenKind::Semicolon) {
+            self.emit_implicit_return_value();
+        } else {
+            if self.function_kind == FunctionKind::Initializer {
+                self.report_error("Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after return value.");
+        }
+        // The return value waits on the stack while the function's deferred expressions run.
+        self.emit_defers_from(0);
+        self.emit_opcode(Opcode::Return);
+    }
+
+    // `defer expr;` runs `expr` when the enclosing scope is left, however that happens. Nothing
+    // is compiled here: the expression is compiled at each exit instead.
+    fn defer_statement(&mut self) {
+        self.defers.push(Defer {
+            code: Deferred::Expression {
+                start: self.current,
+                scanner: self.scanner.clone(),
+            },
+            scope_depth: self.scope_depth,
+            local_count: self.locals.len(),
+        });
+
+        while !self.check(TokenKind::Semicolon) && !self.check(TokenKind::Eof) {
+            self.advance();
+        }
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after deferred expression.",
+        );
+    }
+
+    // `using (var f = open()) body` calls `f.close()` when the body is left, however that happens.
+    fn using_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'using'.");
+        self.consume(TokenKind::Var, "Expect 'var' after '('.");
+        self.parse_variable("Expect variable name.");
+        self.consume(TokenKind::Equal, "Expect '=' after variable name.");
+        self.expression();
+        self.define_variable(0);
+        self.consume(TokenKind::RightParen, "Expect ')' after resource.");
+
+        let slot = (self.locals.len() - 1) as u8;
+        self.defers.push(Defer {
+            code: Deferred::Close(slot),
+            scope_depth: self.scope_depth,
+            local_count: self.locals.len(),
+        });
+        self.statement();
+        self.end_scope();
+    }
+
+    // Compile the deferred code of the scopes at `depth` and deeper, most recent first.
+    fn emit_defers_from(&mut self, depth: usize) {
+        let leaving: Vec<Defer<'a>> = self
+            .defers
+            .iter()
+            .rev()
+            .take_while(|defer| defer.scope_depth >= depth)
+            .cloned()
+            .collect();
+        for defer in leaving {
+            self.emit_deferred(defer);
+        }
+    }
+
+    // Compile deferred code as a statement. Expressions are compiled from their source, then we
+    // carry on where we were.
+    fn emit_deferred(&mut self, defer: Defer<'a>) {
+        // A broken expression is reported the first time it is compiled, once is enough.
+        if self.had_error {
+            return;
+        }
+
+        let (start, scanner) = match defer.code {
+            Deferred::Expression { start, scanner } => (start, scanner),
+            Deferred::Close(slot) => {
+                let close = self.identifier_constant(Token::synthetic("close"));
+                self.emit_bytes(Opcode::GetLocal as u8, slot);
+                self.emit_bytes(Opcode::GetProperty as u8, close);
+                self.emit_bytes(Opcode::Call as u8, 0);
+                self.emit_opcode(Opcode::Pop);
+                return;
+            }
+        };
+
+        let scanner = mem::replace(&mut self.scanner, scanner);
+        let (current, previous) = (self.current, self.previous);
+        // Locals declared after the `defer` keep their slots but can't be named.
+        let later_locals = self.locals.split_off(defer.local_count);
+
+        self.current = start;
+        self.expression();
+        if !self.check(TokenKind::Semicolon) {
+            self.report_error_at_current("Expect ';' after deferred expression.");
+        }
+        self.emit_opcode(Opcode::Pop);
+
+        self.locals.extend(later_locals);
+        self.scanner = scanner;
+        (self.current, self.previous) = (current, previous);
+    }
+
+    // Each case compares the subject with its value and runs its statements if they are equal,
+    // then leaves the switch. There is no fallthrough. The default case, if any, comes last.
+    fn switch_statement(&mut self) {
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'switch'.");
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after value.");
+        self.consume(TokenKind::LeftBrace, "Expect '{' before switch cases.");
+
+        // The subject lives in a local no code can name until the switch ends.
+        self.begin_scope();
+        self.add_local(Token::synthetic(""));
+        self.mark_initialized();
+        let subject = (self.locals.len() - 1) as u8;
+
+        let mut end_jumps = vec![];
+        while self.match_token(TokenKind::Case) {
+            self.emit_bytes(Opcode::GetLocal as u8, subject);
+            self.expression();
+            self.consume(TokenKind::Colon, "Expect ':' after case value.");
+            self.emit_opcode(Opcode::Equal);
+
+            let next_case = self.emit_jump(Opcode::JumpIfFalse);
+            self.emit_opcode(Opcode::Pop);
+            self.case_body();
+            end_jumps.push(self.emit_jump(Opcode::Jump));
+
+            self.patch_jump(next_case);
+            self.emit_opcode(Opcode::Pop);
+        }
+
+        if self.match_token(TokenKind::Default) {
+            self.consume(TokenKind::Colon, "Expect ':' after 'default'.");
+            self.case_body();
+            if self.check(TokenKind::Case) {
+                self.report_error_at_current("Can't have a case after the default case.");
+            }
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after switch cases.");
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.end_scope();
+    }
+
+    // The statements up to the next case or the end of the switch, in a scope of their own.
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenKind::Case)
+            && !self.check(TokenKind::Default)
+            && !self.check(TokenKind::RightBrace)
+            && !self.check(TokenKind::Eof)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenKind::Semicolon, "Expect ';' after value.");
+        self.emit_opcode(Opcode::Print);
+    }
+
+    #[allow(dead_code)]
+    // Convert lexeme into numerical value, then push the value into the constant array.
+    fn number(&mut self) {
+        let value: f64 = self.previous.lexeme().parse::<f64>().unwrap();
+        self.emit_constant(Value::Number(value));
+    }
+
+    // Convert a `1.10d` lexeme into an exact decimal constant.
+    #[cfg(feature = "decimal")]
+    fn decimal(&mut self) {
+        let lexeme = self.previous.lexeme();
+        match Decimal::parse(&lexeme[..lexeme.len() - 1]) {
+            Some(value) => self.emit_constant(Value::Decimal(value)),
+            None => self.report_error("Decimal literal is too large."),
+        }
+    }
+
+    #[cfg(not(feature = "decimal"))]
+    fn decimal(&mut self) {
+        self.report_error("Decimal literals require the 'decimal' feature.");
+    }
+
+    // Note: This function assumes that the '(' has already been consumed.
+    fn grouping(&mut self) {
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after expression.");
+    }
+
+    fn unary(&mut self) {
+        let token_type: TokenKind = self.previous.kind;
+
+        // Collect / compile the operand.
+        self.parse_precedence(Precedence::Unary);
+
+        // Emit the instruction based on the token type.
+        if TokenKind::Minus == token_type {
+            self.emit_opcode(Opcode::Negate);
+        } else if token_type == TokenKind::Bang {
+            self.emit_opcode(Opcode::Not);
+        } else if token_type == TokenKind::Tilde {
+            self.emit_opcode(Opcode::BitNot);
+        }
+    }
+
+    fn binary(&mut self) {
+        let operator_type = self.previous.kind;
+
+        let rule = self.get_rule(operator_type);
+
+        // `**` is right-associative, so its right operand may be another `**`.
+        if operator_type == TokenKind::StarStar {
+            self.parse_precedence(rule.precedence);
+        } else {
+            self.parse_precedence(Precedence::from_u8(rule.precedence as u8 + 1).unwrap());
+        }
+
+        match operator_type {
+            TokenKind::Plus => self.emit_opcode(Opcode::Add),
+            TokenKind::Minus => self.emit_opcode(Opcode::Subtract),
+            TokenKind::Star => self.emit_opcode(Opcode::Multiply),
+            TokenKind::Percent => self.emit_opcode(Opcode::Modulo),
+            TokenKind::StarStar => self.emit_opcode(Opcode::Power),
+            TokenKind::Slash => self.emit_opcode(Opcode::Divide),
+            TokenKind::BangEqual => self.emit_bytes(Opcode::Equal as u8, Opcode::Not as u8),
+            TokenKind::EqualEqual => self.emit_opcode(Opcode::Equal),
+            TokenKind::Greater => self.emit_opcode(Opcode::Greater),
+            TokenKind::GreaterEqual => self.emit_bytes(Opcode::Less as u8, Opcode::Not as u8),
+            TokenKind::Less => self.emit_opcode(Opcode::Less),
+            TokenKind::LessEqual => self.emit_bytes(Opcode::Greater as u8, Opcode::Not as u8),
+            TokenKind::Ampersand => self.emit_opcode(Opcode::BitAnd),
+            TokenKind::Pipe => self.emit_opcode(Opcode::BitOr),
+            TokenKind::Caret => self.emit_opcode(Opcode::BitXor),
+            TokenKind::LessLess => self.emit_opcode(Opcode::ShiftLeft),
+            TokenKind::GreaterGreater => self.emit_opcode(Opcode::ShiftRight),
+            _ => unreachable!(),
+        }
+    }
+
+    // Emit constant pushes two things onto the stack.
+    // - Opcode::Constant
+    // - The index which the constant lives in the constant array.
+    #[allow(dead_code)]
+    fn emit_constant(&mut self, value: Value) {
+        if let Some(small) = value.as_small_integer() {
+            self.emit_bytes(Opcode::SmallInteger as u8, small);
+            return;
+        }
+        let constant = self.make_constant(value);
+        self.emit_bytes(Opcode::Constant as u8, constant);
+    }
+
+    // Creates a new constant and return the index which it lives inside the value array.
+    #[allow(dead_code)]
+    fn make_constant(&mut self, value: Value) -> u8 {
+        let constant = self.chunk.add_constant(value);
+
+        if constant >= UINT8_COUNT {
+            // Reported at the literal which did not fit.
+            self.report_error("Too many constants in one chunk.");
+            0
+        } else {
+            constant as u8
+        }
+    }
+
+    fn get_rule(&mut self, operator_type: TokenKind) -> ParseRule {
+        let empty_rule = ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        };
+        match operator_type {
+            TokenKind::LeftParen => ParseRule {
+                prefix: Some(Box::new(|this, _| this.grouping())),
+                infix: Some(Box::new(|this, _| this.call())),
+                precedence: Precedence::Call,
+            },
+            TokenKind::RightParen => empty_rule,
+            TokenKind::DotDotDot => empty_rule,
+            TokenKind::LeftBrace => ParseRule {
+                prefix: Some(Box::new(|this, _| this.map())),
+                ..empty_rule
+            },
+            TokenKind::RightBrace => empty_rule,
+            TokenKind::LeftBracket => ParseRule {
+                prefix: Some(Box::new(|this, _| this.list())),
+                infix: Some(Box::new(|this, can_assign| this.index(can_assign))),
+                precedence: Precedence::Call,
+            },
+            TokenKind::RightBracket => empty_rule,
+            TokenKind::Colon => ParseRule {
+                prefix: Some(Box::new(|this, _| this.symbol())),
+                ..empty_rule
+            },
+            TokenKind::Comma => empty_rule,
+            TokenKind::Dot => ParseRule {
+                infix: Some(Box::new(|this, can_assign| this.dot(can_assign))),
+                precedence: Precedence::Call,
+                ..empty_rule
+            },
+            TokenKind::Minus => ParseRule {
+                prefix: Some(Box::new(|this, _| this.unary())),
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Term,
+            },
+            TokenKind::Plus => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Term,
+                ..empty_rule
+            },
+            TokenKind::Semicolon => empty_rule,
+            TokenKind::Slash => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Factor,
+                ..empty_rule
+            },
+            TokenKind::Star => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Factor,
+                ..empty_rule
+            },
+            TokenKind::Percent => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Factor,
+                ..empty_rule
+            },
+            TokenKind::StarStar => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Power,
+                ..empty_rule
+            },
+            TokenKind::Ampersand => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::BitAnd,
+                ..empty_rule
+            },
+            TokenKind::Pipe => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::BitOr,
+                ..empty_rule
+            },
+            TokenKind::Caret => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::BitXor,
+                ..empty_rule
+            },
+            TokenKind::Tilde => ParseRule {
+                prefix: Some(Box::new(|this, _| this.unary())),
+                ..empty_rule
+            },
+            TokenKind::Bang => ParseRule {
+                prefix: Some(Box::new(|this, _| this.unary())),
+                ..empty_rule
+            },
+            TokenKind::BangEqual => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Equality,
+                ..empty_rule
+            },
+            TokenKind::Equal => empty_rule,
+            TokenKind::EqualEqual => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Equality,
+                ..empty_rule
+            },
+            TokenKind::Greater => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Comparison,
+                ..empty_rule
+            },
+            TokenKind::GreaterEqual => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Comparison,
+                ..empty_rule
+            },
+            TokenKind::Less => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Comparison,
+                ..empty_rule
+            },
+            TokenKind::LessEqual => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Comparison,
+                ..empty_rule
+            },
+            TokenKind::LessLess => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Shift,
+                ..empty_rule
+            },
+            TokenKind::GreaterGreater => ParseRule {
+                infix: Some(Box::new(|this, _| this.binary())),
+                precedence: Precedence::Shift,
+                ..empty_rule
+            },
+            TokenKind::Question => ParseRule {
+                infix: Some(Box::new(|this, _| this.conditional())),
+                precedence: Precedence::Conditional,
+                ..empty_rule
+            },
+            TokenKind::QuestionQuestion => ParseRule {
+                infix: Some(Box::new(|this, _| this.coalesce())),
+                precedence: Precedence::Coalesce,
+                ..empty_rule
+            },
+            TokenKind::QuestionDot => ParseRule {
+                infix: Some(Box::new(|this, _| this.nil_safe_dot())),
+                precedence: Precedence::Call,
+                ..empty_rule
+            },
+            TokenKind::Identifier => ParseRule {
+                prefix: Some(Box::new(|this, can_assign| this.variable(can_assign))),
+                ..empty_rule
+            },
+            TokenKind::String => ParseRule {
+                prefix: Some(Box::new(|this, _| this.string())),
+                ..empty_rule
+            },
+            TokenKind::Interpolation => ParseRule {
+                prefix: Some(Box::new(|this, _| this.interpolation())),
+                ..empty_rule
+            },
+            TokenKind::Number => ParseRule {
+                prefix: Some(Box::new(|this, _| this.number())),
+                ..empty_rule
+            },
+            TokenKind::Decimal => ParseRule {
+                prefix: Some(Box::new(|this, _| this.decimal())),
+                ..empty_rule
+            },
+            TokenKind::And => ParseRule {
+                infix: Some(Box::new(|this, _| this.and())),
+                precedence: Precedence::And,
+                ..empty_rule
+            },
+            TokenKind::Break => empty_rule,
+            TokenKind::Case => empty_rule,
+            TokenKind::Class => empty_rule,
+            TokenKind::Continue => empty_rule,
+            TokenKind::Default => empty_rule,
+            TokenKind::Defer => empty_rule,
+            TokenKind::Else => empty_rule,
+            TokenKind::False => ParseRule {
+                prefix: Some(Box::new(|this, _| this.literal())),
+                ..empty_rule
+            },
+            TokenKind::For => empty_rule,
+            TokenKind::Fun => empty_rule,
+            TokenKind::If => empty_rule,
+            TokenKind::Nil => ParseRule {
+                prefix: Some(Box::new(|this, _| this.literal())),
+                ..empty_rule
+            },
+            TokenKind::Or => ParseRule {
+                infix: Some(Box::new(|this, _| this.or())),
+                precedence: Precedence::Or,
+                ..empty_rule
+            },
+            TokenKind::Print => empty_rule,
+            TokenKind::Switch => empty_rule,
+            TokenKind::Return => empty_rule,
+            TokenKind::Super => ParseRule {
+                prefix: Some(Box::new(|this, _| this.super_())),
+                ..empty_rule
+            },
+            TokenKind::This => ParseRule {
+                prefix: Some(Box::new(|this, _| this.this())),
+                ..empty_rule
+            },
+            TokenKind::True => ParseRule {
+                prefix: Some(Box::new(|this, _| this.literal())),
+                ..empty_rule
+            },
+            TokenKind::Using => empty_rule,
+            TokenKind::Var => empty_rule,
+            TokenKind::Static => empty_rule,
+            TokenKind::Const => empty_rule,
+            TokenKind::While => empty_rule,
+            TokenKind::Error => empty_rule,
+            TokenKind::Eof => empty_rule,
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+
+        // Retrieve the prefix rule for the given token kind.
+        // We expect this to return a valid rule because if it
+        // does not then we have an incorrect first token.
+        // For instance, an expression can not start with 'else' or '}'.
+        let prefix_rule = self.get_rule(self.previous.kind).prefix;
+
+        // Only a low precedence context may assign, so `a * b = c` is rejected instead of
+        // being parsed as `a * (b = c)`.
+        let can_assign = precedence as u8 <= Precedence::Assignment as u8;
+
+        if let Some(rule) = prefix_rule {
+            rule(self, can_assign);
+        } else {
+            self.report_error("Expect expression.");
+            return;
+        }
+
+        while (precedence as u8) <= (self.get_rule(self.current.kind).precedence as u8) {
+            self.advance();
+            let infix_rule = self.get_rule(self.previous.kind).infix.unwrap();
+            infix_rule(self, can_assign);
+        }
+
+        // Nothing consumed the '=', so the left hand side was not something assignable.
+        if can_assign && self.match_token(TokenKind::Equal) {
+            self.report_error("Invalid assignment target.");
+        }
+    }
+
+    // `cond ? then : else`, with the condition already compiled. As in C, the then branch may be
+    // any expression since `:` ends it. Right-associative, so the else branch may itself be a
+    // conditional.
+    fn conditional(&mut self) {
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_opcode(Opcode::Pop);
+        self.expression();
+        self.consume(
+            TokenKind::Colon,
+            "Expect ':' after then branch of conditional expression.",
+        );
+
+        let end_jump = self.emit_jump(Opcode::Jump);
+        self.patch_jump(else_jump);
+        self.emit_opcode(Opcode::Pop);
+        self.parse_precedence(Precedence::Conditional);
+        self.patch_jump(end_jump);
+    }
+
+    // `a ?? b` evaluates to `b` only when `a` is nil. `b` is skipped entirely otherwise.
+    fn coalesce(&mut self) {
+        let end_jump = self.emit_jump(Opcode::Coalesce);
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn call(&mut self) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(Opcode::Call as u8, arg_count);
+    }
+
+    // A property access, or an assignment to one when it is the target.
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenKind::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.previous);
+
+        if can_assign && self.match_token(TokenKind::Equal) {
+            self.expression();
+            self.emit_bytes(Opcode::SetProperty as u8, name);
+        } else {
+            self.emit_bytes(Opcode::GetProperty as u8, name);
+        }
+    }
+
+    // `a?.name` is nil when `a` is nil, and `a.name` otherwise. A call right after it, as in
+    // `a?.name(x)`, is skipped too, arguments and all. Only that one step is guarded: in `a?.b.c`
+    // a nil `a` still fails at `.c`.
+    fn nil_safe_dot(&mut self) {
+        let end_jump = self.emit_jump(Opcode::JumpIfNil);
+        self.consume(TokenKind::Identifier, "Expect property name after '?.'.");
+        let name = self.identifier_constant(self.previous);
+        self.emit_bytes(Opcode::GetProperty as u8, name);
+        if self.match_token(TokenKind::LeftParen) {
+            self.call();
+        }
+        self.patch_jump(end_jump);
+    }
+
+    // A list literal, e.g. `[1, 2, 3]`. A trailing comma is allowed.
+    fn list(&mut self) {
+        let mut count: usize = 0;
+        while !self.check(TokenKind::RightBracket) {
+            self.expression();
+            if count == u8::MAX as usize {
+                self.report_error("Can't have more than 255 elements in a list literal.");
+            }
+            count += 1;
+
+            if !self.match_token(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightBracket, "Expect ']' after list elements.");
+        self.emit_bytes(Opcode::BuildList as u8, count as u8);
+    }
+
+    // A map literal, e.g. `{"a": 1, "b": 2}`. Only parsed where an expression is expected, since
+    // a statement starting with `{` is a block. A trailing comma is allowed.
+    fn map(&mut self) {
+        let mut count: usize = 0;
+        while !self.check(TokenKind::RightBrace) {
+            self.expression();
+            self.consume(TokenKind::Colon, "Expect ':' after map key.");
+            self.expression();
+            if count == u8::MAX as usize {
+                self.report_error("Can't have more than 255 entries in a map literal.");
+            }
+            count += 1;
+
+            if !self.match_token(TokenKind::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenKind::RightBrace, "Expect '}' after map entries.");
+        self.emit_bytes(Opcode::BuildMap as u8, count as u8);
+    }
+
+    // An element access, or an assignment to one when it is the target.
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenKind::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenKind::Equal) {
+            self.expression();
+            self.emit_opcode(Opcode::IndexSet);
+        } else {
+            self.emit_opcode(Opcode::IndexGet);
+        }
+    }
+
+    // Compile the arguments of a call, up to the closing ')'. Returns how many there were.
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.report_error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    // `a and b` is `a` when it is falsey, without evaluating `b`.
+    fn and(&mut self) {
+        let end_jump = self.emit_jump(Opcode::JumpIfFalse);
+
+        self.emit_opcode(Opcode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    // `a or b` is `a` when it is truthy, without evaluating `b`.
+    fn or(&mut self) {
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse);
+        let end_jump = self.emit_jump(Opcode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_opcode(Opcode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn literal(&mut self) {
+        match self.previous.kind {
+            TokenKind::True => self.emit_opcode(Opcode::True),
+            TokenKind::False => self.emit_opcode(Opcode::False),
+            TokenKind::Nil => self.emit_opcode(Opcode::Nil),
+            _ => unreachable!(),
+        }
+    }
+
+    fn string(&mut self) {
+        self.emit_constant({
+            let mut chars = self.previous.lexeme().chars();
+
+            // Get rid of pre/postfix '"'.
+            chars.next();
+            chars.next_back();
+
+            string_value(chars.as_str())
+        })
+    }
+
+    // `"a ${x} b"` arrives as the segments `"a ${` and `} b"` around the tokens of `x`. The pieces
+    // are joined with OP_ADD, after turning each embedded value into a string.
+    fn interpolation(&mut self) {
+        self.interpolation_segment();
+
+        loop {
+            self.expression();
+            self.emit_opcode(Opcode::Stringify);
+            self.emit_opcode(Opcode::Add);
+
+            if !self.match_token(TokenKind::Interpolation) {
+                break;
+            }
+            self.interpolation_segment();
+            self.emit_opcode(Opcode::Add);
+        }
+
+        // Only the string which ends the interpolation starts with `}`.
+        if self.check(TokenKind::String) && self.current.lexeme().starts_with('}') {
+            self.advance();
+            self.string();
+            self.emit_opcode(Opcode::Add);
+        } else {
+            self.report_error_at_current("Expect '}' after interpolated expression.");
+        }
+    }
+
+    // Push the text of the previous segment, without its opening `"` or `}` and closing `${`.
+    fn interpolation_segment(&mut self) {
+        let segment = self.previous.lexeme();
+        self.emit_constant(string_value(&segment[1..segment.len() - 2]));
+    }
+
+    fn this(&mut self) {
+        if self.classes.is_empty() {
+            self.report_error("Can't use 'this' outside of a class.");
+            return;
+        }
+        if self.function_kind == FunctionKind::StaticMethod {
+            self.report_error("Can't use 'this' in a static method.");
+            return;
+        }
+        // A function nested in a method has no instance to reach.
+        if !matches!(
+            self.function_kind,
+            FunctionKind::Method | FunctionKind::Initializer
+        ) {
+            self.report_error("Can't use 'this' outside of a method.");
+            return;
+        }
+
+        // `this` is a local like any other, which can't be assigned to.
+        self.variable(false);
+    }
+
+    // `super.name` or `super.name(args)`. The VM finds the superclass from the class the running
+    // method was found in, so only the instance is loaded here.
+    fn super_(&mut self) {
+        match self.classes.last() {
+            None => self.report_error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.report_error("Can't use 'super' in a class with no superclass.")
+            }
+            // Without closures, a function nested in a method can't reach the method's instance.
+            _ if !matches!(
+                self.function_kind,
+                FunctionKind::Method | FunctionKind::Initializer
+            ) =>
+            {
+                self.report_error("Can't use 'super' outside of a method.")
+            }
+            _ => {}
+        }
+
+        self.consume(TokenKind::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenKind::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.previous);
+
+        self.named_variable(Token::synthetic("this"), false);
+        if self.match_token(TokenKind::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_bytes(Opcode::SuperInvoke as u8, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_bytes(Opcode::GetSuper as u8, name);
+        }
+    }
+
+    // A symbol literal is a ':' immediately followed by an identifier, e.g. `:red`.
+    fn symbol(&mut self) {
+        let colon = self.previous;
+        let adjacent = self.current.start == colon.start + colon.length;
+
+        if self.current.kind != TokenKind::Identifier || !adjacent {
+            self.report_error_at_current("Expect symbol name after ':'.");
+            return;
+        }
+
+        self.advance();
+        let symbol = Symbol::intern(self.previous.lexeme());
+        self.emit_constant(Value::Symbol(symbol));
+    }
+}
+
+// Parse functions receive whether the expression they start may be an assignment target.
+type ParseFn = Box<dyn Fn(&mut Parser, bool)>;
+
+//
+// Parse rule.
+//
+struct ParseRule {
+    prefix: Option<ParseFn>,
+    infix: Option<ParseFn>,
+    precedence: Precedence,
+}
+
+//
+// The compiler.
+//
+pub struct Compiler<'a> {
+    parser: Parser<'a>,
+}
+
+impl<'a> Compiler<'a> {
+    #[allow(dead_code)]
+    pub fn new(source: &'a str) -> Self {
+        Self {
+            parser: Parser::new(source, 0, None),
+        }
+    }
+
+    // Compile source read from a registered file, so locations and errors name it.
+    pub fn for_file(source: &'a str, file: FileId, sources: &'a SourceRegistry) -> Self {
+        Self {
+            parser: Parser::new(source, file, sources.name(file)),
+        }
+    }
+
+    // Report errors to `console` rather than stderr.
+    fn with_console(mut self, console: Rc<RefCell<Console>>) -> Self {
+        self.parser.console = console;
+        self
+    }
+
+    // Compile a script: a sequence of statements.
+    pub fn compile(&mut self) -> Option<Chunk> {
+        self.parser.advance();
+
+        while !self.parser.match_token(TokenKind::Eof) {
+            self.parser.declaration();
+        }
+        self.parser.emit_defers_from(0);
+
+        // Scripts produce no value.
+        self.parser.emit_opcode(Opcode::Nil);
+        self.parser.end(0);
+        self.finish()
+    }
+
+    // Compile a single expression whose value is left on the stack, e.g. for REPL watches.
+    pub fn compile_expression(&mut self) -> Option<Chunk> {
+        self.parser.advance();
+        self.parser.expression();
+        self.parser
+            .consume(TokenKind::Eof, "Expected end of expression.");
+        self.parser.end(0);
+        self.finish()
+    }
+
+    fn finish(&mut self) -> Option<Chunk> {
+        if self.parser.had_error {
+            None
+        } else {
+            Some(mem::take(&mut self.parser.chunk))
+        }
+    }
+}
+
+// What a piece of source is compiled as.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CompileMode {
+    Script,
+    Expression,
+}
+
+//
+// Virtual Machine.
+//
+
+// The InterpretResult enum symbolises the state of the compiler result.
+#[derive(Debug, PartialEq)]
+pub enum InterpretResult {
+    Ok,
+    CompileError,
+    RuntimeError,
+    // Stopped early by `VmHandle::interrupt`.
+    Interrupted,
+}
+
+/// A thread-safe handle for controlling a running VM from elsewhere, e.g. a signal handler.
+#[derive(Clone)]
+pub struct VmHandle {
+    interrupt: Arc<AtomicBool>,
+}
+
+impl VmHandle {
+    /// Ask the VM to stop at its next safepoint. The run then ends with `Interrupted`, leaving
+    /// the VM ready to interpret more code. Has no effect on a VM which is not running.
+    pub fn interrupt(&self) {
+        self.interrupt.store(true, Ordering::Relaxed);
+    }
+}
+
+// The max size of the stack.
+const STACK_MAX: usize = 256;
+
+// How many compiled snippets the VM remembers before starting over.
+const COMPILE_CACHE_MAX: usize = 64;
+
+// How deeply calls may nest unless `--max-call-depth` says otherwise.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 64;
+
+// How many distinct functions a stack overflow error lists.
+const OVERFLOW_FRAMES_SHOWN: usize = 3;
+
+// A function invocation in progress.
+struct CallFrame {
+    function: Rc<Function>,
+    // Instruction pointer into the function's chunk.
+    ip: usize,
+    // Index of the frame's slot zero, which holds the function itself, in the VM stack.
+    slots: usize,
+    // For a method, the class declaring it, where `super` starts looking.
+    class: Option<Rc<Class>>,
+}
+
+// A checked position in a list, or key into a map.
+enum Subscript {
+    Index(usize),
+    Key(MapKey),
+}
+
+// The virtual machine (VM) is responsible for interpreting bytecode chunks and mutating internal state accordingly.
+pub struct VM {
+    // Calls in progress, innermost last. The script is the outermost.
+    frames: Vec<CallFrame>,
+
+    // How many frames may be in progress at once.
+    pub max_call_depth: usize,
+
+    // Stack.
+    stack: Vec<Value>,
+
+    // Global variables, by name. They outlive individual runs, e.g. REPL lines.
+    globals: StringTable<Value>,
+    // The globals last declared with `const`.
+    const_globals: HashSet<Rc<LoxString>, std::hash::BuildHasherDefault<CachedHasher>>,
+
+    // Chunks compiled from previously interpreted source, keyed by that source and its file.
+    compile_cache: HashMap<(CompileMode, FileId, String), Rc<Function>>,
+
+    // Files loaded so far.
+    pub sources: SourceRegistry,
+
+    // Set by a `VmHandle` to stop the run loop.
+    interrupt: Arc<AtomicBool>,
+
+    // What this VM has done so far.
+    pub usage: Usage,
+
+    // Where scripts write and read, shared with the natives which do.
+    console: Rc<RefCell<Console>>,
+
+    // The name initializers are looked up by, kept so calling a class needs no new string.
+    init_string: Rc<LoxString>,
+
+    // Where dumps of the VM's state go when it finds itself in an impossible state.
+    crash_dir: PathBuf,
+}
+
+/// The streams a VM's scripts write to and read from.
+#[derive(Default)]
+struct Console {
+    /// When set, output is collected here instead of going to stdout.
+    output: Option<String>,
+    /// When set, error reports are collected here instead of going to stderr.
+    errors: Option<String>,
+    /// When set, `input()` reads from here instead of stdin.
+    input: Option<Box<dyn BufRead>>,
+}
+
+impl Console {
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        match self.output.as_mut() {
+            Some(output) => output.push_str(text),
+            None => {
+                let mut stdout = io::stdout();
+                stdout.write_all(text.as_bytes())?;
+                stdout.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Write part of an error report, such as a compile error or a stack trace.
+    fn write_error(&mut self, text: &str) {
+        match self.errors.as_mut() {
+            Some(errors) => errors.push_str(text),
+            None => eprint!("{}", text),
+        }
+    }
+
+    // Read a line without its line ending, or nil at the end of the input.
+    fn read_line(&mut self) -> Result<Value, String> {
+        match self.input.as_mut() {
+            Some(input) => read_input_line(input),
+            None => read_input_line(&mut io::stdin().lock()),
+        }
+    }
+}
+
+/// Counters describing the work a VM has done, reported by `--report`.
+#[derive(serde::Serialize)]
+pub struct Usage {
+    /// Instructions executed.
+    instructions: u64,
+    /// The deepest the value stack got.
+    peak_stack_slots: usize,
+    /// Source files read, in order.
+    pub files: Vec<String>,
+    /// How many times each native function was called.
+    natives_called: BTreeMap<String, u64>,
+    /// Instructions executed, by opcode byte.
+    #[serde(rename = "dispatch", serialize_with = "serialize_dispatch")]
+    dispatched: Vec<u64>,
+    /// How often each conditional jump was taken and not taken, by opcode byte.
+    #[serde(serialize_with = "serialize_branches")]
+    branches: Vec<(u64, u64)>,
+}
+
+impl Default for Usage {
+    fn default() -> Self {
+        Self {
+            instructions: 0,
+            peak_stack_slots: 0,
+            files: vec![],
+            natives_called: BTreeMap::new(),
+            dispatched: vec![0; UINT8_COUNT],
+            branches: vec![(0, 0); UINT8_COUNT],
+        }
+    }
+}
+
+impl Usage {
+    fn record_branch(&mut self, opcode: Opcode, taken: bool) {
+        let (taken_count, not_taken_count) = &mut self.branches[opcode as usize];
+        if taken {
+            *taken_count += 1;
+        } else {
+            *not_taken_count += 1;
+        }
+    }
+}
+
+// Opcodes which never ran are left out of the report.
+fn opcode_counts<T: Copy>(counts: &[T], ran: impl Fn(T) -> bool) -> Vec<(String, T)> {
+    counts
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| ran(**count))
+        .filter_map(|(byte, count)| {
+            let opcode = Opcode::decode(u8::try_from(byte).ok()?)?;
+            Some((opcode.name().to_string(), *count))
+        })
+        .collect()
+}
+
+fn serialize_dispatch<S: serde::Serializer>(
+    counts: &[u64],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let counts: BTreeMap<String, u64> = opcode_counts(counts, |count| count > 0)
+        .into_iter()
+        .collect();
+    serde::Serialize::serialize(&counts, serializer)
+}
+
+#[derive(serde::Serialize)]
+struct BranchReport {
+    taken: u64,
+    not_taken: u64,
+    taken_ratio: f64,
+}
+
+fn serialize_branches<S: serde::Serializer>(
+    counts: &[(u64, u64)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let branches: BTreeMap<String, BranchReport> =
+        opcode_counts(counts, |(taken, not_taken)| taken + not_taken > 0)
+            .into_iter()
+            .map(|(name, (taken, not_taken))| {
+                let taken_ratio = taken as f64 / (taken + not_taken) as f64;
+                let report = BranchReport {
+                    taken,
+                    not_taken,
+                    taken_ratio,
+                };
+                (name, report)
+            })
+            .collect();
+    serde::Serialize::serialize(&branches, serializer)
+}
+
+impl VM {
+    // Return a new virtual machine instance.
+    pub fn new(chunk: Chunk) -> Self {
+        let mut vm = Self {
+            frames: vec![],
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            stack: Vec::with_capacity(STACK_MAX),
+            globals: StringTable::default(),
+            const_globals: HashSet::default(),
+            compile_cache: HashMap::new(),
+            sources: SourceRegistry::default(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            usage: Usage::default(),
+            console: Rc::default(),
+            init_string: Rc::new(LoxString::new("init")),
+            crash_dir: std::env::temp_dir(),
+        };
+        vm.start(Rc::new(Function::script(chunk)));
+
+        let created = Instant::now();
+        vm.define_native("clock", 0, move |_| {
+            Ok(Value::Number(created.elapsed().as_secs_f64()))
+        });
+        vm.define_native("now", 0, |_| {
+            let since_epoch = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_err(|err| err.to_string())?;
+            Ok(Value::Date(Date {
+                epoch: since_epoch.as_secs_f64(),
+            }))
+        });
+        vm.define_native("date", 3, |args| match args {
+            [Value::Number(year), Value::Number(month), Value::Number(day)] => {
+                Ok(Value::Date(Date::from_parts(*year, *month, *day)?))
+            }
+            _ => Err("Date parts must be numbers.".to_string()),
+        });
+        vm.define_native("dateFromEpoch", 1, |args| match args {
+            [Value::Number(epoch)] => Date::from_epoch(*epoch)
+                .map(Value::Date)
+                .ok_or_else(|| "Epoch seconds out of range.".to_string()),
+            _ => Err("Epoch seconds must be a number.".to_string()),
+        });
+        vm.define_native("duration", 1, |args| match args {
+            [Value::Number(seconds)] if seconds.is_finite() => {
+                Ok(Value::Duration(Duration { seconds: *seconds }))
+            }
+            _ => Err("Duration seconds must be a finite number.".to_string()),
+        });
+        vm.define_native("formatDate", 2, |args| match args {
+            [Value::Date(date), pattern] if pattern.is_string() => {
+                Ok(string_value(&date.format(pattern.as_str())?))
+            }
+            _ => Err("formatDate takes a date and a pattern string.".to_string()),
+        });
+        vm.define_native("parse", 2, |args| match args {
+            [text, pattern] if text.is_string() && pattern.is_string() => {
+                parse_text(text.as_str(), pattern.as_str())
+            }
+            _ => Err("parse takes a string and a pattern string.".to_string()),
+        });
+        vm.define_native_class(
+            "Csv",
+            vec![
+                (
+                    "parse",
+                    1,
+                    Box::new(|args| match args {
+                        [text] if text.is_string() => parse_csv(text.as_str()),
+                        _ => Err("Csv.parse takes a string.".to_string()),
+                    }),
+                ),
+                (
+                    "read",
+                    1,
+                    Box::new(|args| match args {
+                        [path] if path.is_string() => {
+                            let path = path.as_str();
+                            let text = std::fs::read_to_string(path)
+                                .map_err(|err| format!("Could not read {}: {}.", path, err))?;
+                            parse_csv(&text)
+                        }
+                        _ => Err("Csv.read takes a path string.".to_string()),
+                    }),
+                ),
+                (
+                    "write",
+                    2,
+                    Box::new(|args| match args {
+                        [path, rows] if path.is_string() => {
+                            let path = path.as_str();
+                            std::fs::write(path, format_csv(rows)?)
+                                .map_err(|err| format!("Could not write {}: {}.", path, err))?;
+                            Ok(Value::Nil)
+                        }
+                        _ => Err("Csv.write takes a path string and a list of maps.".to_string()),
+                    }),
+                ),
+            ],
+        );
+        let console = vm.console.clone();
+        vm.define_native("input", 1, move |args| {
+            let mut console = console.borrow_mut();
+            console
+                .write(&args[0].to_string())
+                .map_err(|err| err.to_string())?;
+            console.read_line()
+        });
+        vm
+    }
+
+    // Collect what scripts print instead of writing it to stdout, until `take_output`.
+    pub fn capture_output(&mut self) {
+        self.console.borrow_mut().output = Some(String::new());
+    }
+
+    // Return what was printed since `capture_output`, and go back to stdout.
+    pub fn take_output(&mut self) -> String {
+        self.console.borrow_mut().output.take().unwrap_or_default()
+    }
+
+    // Collect compile and runtime errors instead of writing them to stderr, until `take_errors`.
+    pub fn capture_errors(&mut self) {
+        self.console.borrow_mut().errors = Some(String::new());
+    }
+
+    // Return the errors reported since `capture_errors`, and go back to stderr.
+    pub fn take_errors(&mut self) -> String {
+        self.console.borrow_mut().errors.take().unwrap_or_default()
+    }
+
+    // Have `input()` read from `reader` rather than stdin.
+    pub fn set_input(&mut self, reader: impl BufRead + 'static) {
+        self.console.borrow_mut().input = Some(Box::new(reader));
+    }
+
+    // Install a Rust function as a global, callable from Lox with exactly `arity` arguments.
+    fn define_native(
+        &mut self,
+        name: &str,
+        arity: u8,
+        body: impl Fn(&[Value]) -> Result<Value, String> + 'static,
+    ) {
+        let native = NativeFn {
+            name: Rc::from(name),
+            arity,
+            body: Box::new(body),
+        };
+        let name = Rc::new(LoxString::new(name));
+        self.globals.insert(name, Value::Native(Rc::new(native)));
+    }
+
+    // Install a class as a global whose static methods are Rust functions, given by name, arity
+    // and body, e.g. `Csv.parse`.
+    fn define_native_class(&mut self, name: &str, methods: Vec<(&str, u8, Box<NativeBody>)>) {
+        let class = Class {
+            name: Rc::from(name),
+            superclass: OnceCell::new(),
+            methods: RefCell::default(),
+            statics: RefCell::default(),
+        };
+        for (method, arity, body) in methods {
+            let native = NativeFn {
+                name: Rc::from(format!("{}.{}", name, method)),
+                arity,
+                body,
+            };
+            let method = Rc::new(LoxString::new(method));
+            class
+                .statics
+                .borrow_mut()
+                .insert(method, Value::Native(Rc::new(native)));
+        }
+        let name = Rc::new(LoxString::new(name));
+        self.globals.insert(name, Value::Class(Rc::new(class)));
+    }
+
+    // Prepare to run `script` from the top, abandoning whatever ran before.
+    fn start(&mut self, script: Rc<Function>) {
+        self.stack.clear();
+        self.frames.clear();
+
+        self.push(Value::Function(script.clone()));
+        self.frames.push(CallFrame {
+            function: script,
+            ip: 0,
+            slots: 0,
+            class: None,
+        });
+    }
+
+    // Return a handle which other threads can use to interrupt this VM.
+    pub fn handle(&self) -> VmHandle {
+        VmHandle {
+            interrupt: self.interrupt.clone(),
+        }
+    }
+
+    // Compile source, reusing the chunk from an earlier identical source when possible.
+    fn compile_cached(
+        &mut self,
+        source: &str,
+        mode: CompileMode,
+        file: FileId,
+    ) -> Option<Rc<Function>> {
+        // Files are usually run once and can be large, so they skip the cache rather than have
+        // their whole text copied into a key.
+        if file != 0 {
+            return self.compile_source(source, mode, file);
+        }
+
+        let key = (mode, file, source.to_string());
+        if let Some(script) = self.compile_cache.get(&key) {
+            return Some(script.clone());
+        }
+
+        let script = self.compile_source(source, mode, file)?;
+
+        if self.compile_cache.len() >= COMPILE_CACHE_MAX {
+            self.compile_cache.clear();
+        }
+        self.compile_cache.insert(key, script.clone());
+
+        Some(script)
+    }
+
+    // Compile source into a script function.
+    fn compile_source(
+        &self,
+        source: &str,
+        mode: CompileMode,
+        file: FileId,
+    ) -> Option<Rc<Function>> {
+        let mut compiler =
+            Compiler::for_file(source, file, &self.sources).with_console(self.console.clone());
+        let chunk = match mode {
+            CompileMode::Script => compiler.compile(),
+            CompileMode::Expression => compiler.compile_expression(),
+        }?;
+        Some(Rc::new(Function::script(chunk)))
+    }
+
+    // Push a new value onto the stack.
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+        self.usage.peak_stack_slots = self.usage.peak_stack_slots.max(self.stack.len());
+    }
+
+    // Pop and return value from the stack.
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap()
+    }
+
+    // Interpret source code. Return Interpret result which symbolizes the success state.
+    pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        match self.run_source(source, CompileMode::Script, 0) {
+            Ok(_) => InterpretResult::Ok,
+            Err(result) => result,
+        }
+    }
+
+    // Evaluate a single expression and return its value.
+    pub fn execute(&mut self, source: &str) -> Result<Value, InterpretResult> {
+        self.run_source(source, CompileMode::Expression, 0)
+    }
+
+    // Compile and run source, returning the value left by its final OP_RETURN.
+    pub fn run_source(
+        &mut self,
+        source: &str,
+        mode: CompileMode,
+        file: FileId,
+    ) -> Result<Value, InterpretResult> {
+        let script = self
+            .compile_cached(source, mode, file)
+            .ok_or(InterpretResult::CompileError)?;
+        self.run_script(script)
+    }
+
+    // Run a compiled script, returning the value left by its final OP_RETURN.
+    pub fn run_script(&mut self, script: Rc<Function>) -> Result<Value, InterpretResult> {
+        self.start(script);
+
+        // An interrupt requested while nothing was running is stale.
+        self.interrupt.store(false, Ordering::Relaxed);
+
+        match self.run(false) {
+            InterpretResult::Ok => Ok(self.pop()),
+            result => Err(result),
+        }
+    }
+
+    // Complete the word before `cursor`, offering the globals defined so far too.
+    pub fn complete(&self, source: &str, cursor: usize) -> Completions {
+        let globals: Vec<&str> = self.globals.keys().map(|name| name.as_str()).collect();
+        complete(source, cursor, &globals)
+    }
+
+    // Interpret a whole file. Embedders use this to load preludes before running their own code.
+    pub fn interpret_file(&mut self, path: &str) -> io::Result<InterpretResult> {
+        let source = SourceText::load(path)?;
+        self.usage.files.push(path.to_string());
+
+        let file = self.sources.register(path);
+        match self.run_source(&source, CompileMode::Script, file) {
+            Ok(_) => Ok(InterpretResult::Ok),
+            Err(result) => Ok(result),
+        }
+    }
+
+    // Interpret the next byte as an opcode.
+    fn read_instruction(&mut self) -> Option<Opcode> {
+        Opcode::decode(self.read_byte())
+    }
+
+    // The innermost call in progress.
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    // The code being run.
+    fn chunk(&self) -> &Chunk {
+        &self.frame().function.chunk
+    }
+
+    // Read the current byte and increment onto the next.
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame_mut();
+        let instruction: u8 = frame.function.chunk.code[frame.ip];
+        frame.ip += 1;
+        instruction
+    }
+
+    // Read a two byte, big endian operand.
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte();
+        let low = self.read_byte();
+        u16::from_be_bytes([high, low])
+    }
+
+    // Read the byte as the value used to index into the constants array.
+    fn read_constant(&mut self) -> &Value {
+        let idx = self.read_byte() as usize;
+        &self.chunk().constants[idx]
+    }
+
+    // Main run loop. Interpret all byte code and mutate internal state.
+    #[allow(dead_code)]
+    fn run(&mut self, debug: bool) -> InterpretResult {
+        while self.frame().ip < self.chunk().code.len() {
+            // Every instruction boundary is a safepoint. A plain load keeps the common case cheap;
+            // the flag is only written once it is found set.
+            if self.interrupt.load(Ordering::Relaxed) {
+                self.interrupt.store(false, Ordering::Relaxed);
+                return InterpretResult::Interrupted;
+            }
+
+            self.usage.instructions += 1;
+            let byte = self.chunk().code[self.frame().ip];
+            self.usage.dispatched[byte as usize] += 1;
+
+            if debug {
+                print!("          ");
+                self.stack.iter().for_each(|slot| print!("[ {} ]", slot));
+                println!();
+                self.chunk().disassemble_instruction(self.frame().ip);
+            }
+            match self.read_instruction() {
+                #[cfg(feature = "decimal")]
+                Some(
+                    opcode @ (Opcode::Add
+                    | Opcode::Subtract
+                    | Opcode::Multiply
+                    | Opcode::Divide
+                    | Opcode::Greater
+                    | Opcode::Less),
+                ) if self.peek(0).is_decimal() && self.peek(1).is_decimal() => {
+                    let b = self.pop().as_decimal();
+                    let a = self.pop().as_decimal();
+                    let result = match opcode {
+                        Opcode::Add => a.add(&b).map(Value::Decimal),
+                        Opcode::Subtract => a.subtract(&b).map(Value::Decimal),
+                        Opcode::Multiply => a.multiply(&b).map(Value::Decimal),
+                        Opcode::Divide => a.divide(&b).map(Value::Decimal),
+                        Opcode::Greater => Ok(Value::Bool(a.compare(&b).is_gt())),
+                        _ => Ok(Value::Bool(a.compare(&b).is_lt())),
+                    };
+
+                    match result {
+                        Ok(value) => self.push(value),
+                        Err(message) => {
+                            self.runtime_error(message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                Some(
+                    opcode @ (Opcode::Add
+                    | Opcode::Subtract
+                    | Opcode::Multiply
+                    | Opcode::Divide
+                    | Opcode::Greater
+                    | Opcode::Less),
+                ) if self.peek(0).is_time() || self.peek(1).is_time() => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    match time_arithmetic(opcode, &a, &b) {
+                        Ok(value) => self.push(value),
+                        Err(message) => {
+                            self.runtime_error(message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                Some(Opcode::Negate) if matches!(self.peek(0), Value::Duration(_)) => {
+                    let Value::Duration(duration) = self.pop() else {
+                        unreachable!()
+                    };
+                    self.push(Value::Duration(Duration {
+                        seconds: -duration.seconds,
+                    }));
+                }
+                #[cfg(feature = "decimal")]
+                Some(Opcode::Negate) if self.peek(0).is_decimal() => {
+                    let negated_value = self.pop().as_decimal().negate();
+                    self.push(Value::Decimal(negated_value));
+                }
+                Some(Opcode::Equal) => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::Bool(a.is_equal(&b)))
+                }
+                Some(Opcode::Greater) => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be numbers.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    self.push(Value::Bool(a > b));
+                }
+                Some(Opcode::Less) => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be numbers.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    self.push(Value::Bool(a < b));
+                }
+                Some(Opcode::Not) => {
+                    let value = self.pop();
+                    self.push(Value::Bool(value.is_falsey()));
+                }
+                Some(Opcode::False) => self.push(Value::Bool(false)),
+                Some(Opcode::True) => self.push(Value::Bool(true)),
+                Some(Opcode::Nil) => self.push(Value::Nil),
+                Some(Opcode::SmallInteger) => {
+                    let value = self.read_byte();
+                    self.push(Value::Number(value as f64));
+                }
+                Some(Opcode::Constant) => {
+                    let constant = self.read_constant().clone();
+                    self.push(constant);
+                }
+                Some(Opcode::Add) => {
+                    if self.peek(0).is_string() && self.peek(1).is_string() {
+                        let b = self.pop();
+                        let a = self.pop();
+                        self.push(string_value(&[a.as_str(), b.as_str()].concat()));
+                    } else if self.peek(0).is_number() && self.peek(1).is_number() {
+                        let b = self.pop().as_number();
+                        let a = self.pop().as_number();
+                        self.push(Value::Number(a + b));
+                    } else {
+                        self.runtime_error("Operands must be two numbers or two strings.");
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                Some(Opcode::Subtract) => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be numbers.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    self.push(Value::Number(a - b));
+                }
+                Some(Opcode::Multiply) => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be numbers.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    self.push(Value::Number(a * b));
+                }
+                Some(Opcode::Divide) => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be numbers.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    self.push(Value::Number(a / b));
+                }
+                Some(Opcode::Modulo) => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be numbers.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    // The remainder of truncating division, so it has the sign of the dividend.
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    self.push(Value::Number(a % b));
+                }
+                Some(Opcode::Power) => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be numbers.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let b = self.pop().as_number();
+                    let a = self.pop().as_number();
+                    self.push(Value::Number(a.powf(b)));
+                }
+                Some(
+                    op @ (Opcode::BitAnd
+                    | Opcode::BitOr
+                    | Opcode::BitXor
+                    | Opcode::ShiftLeft
+                    | Opcode::ShiftRight),
+                ) => {
+                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
+                        self.runtime_error("Operands must be numbers.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    // Both operands are truncated towards zero to 64-bit integers first.
+                    let b = self.pop().as_number() as i64;
+                    let a = self.pop().as_number() as i64;
+                    let result = match op {
+                        Opcode::BitAnd => a & b,
+                        Opcode::BitOr => a | b,
+                        Opcode::BitXor => a ^ b,
+                        _ if !(0..64).contains(&b) => {
+                            self.runtime_error("Shift amount must be between 0 and 63.");
+                            return InterpretResult::RuntimeError;
+                        }
+                        Opcode::ShiftLeft => a << b,
+                        _ => a >> b,
+                    };
+                    self.push(Value::Number(result as f64));
+                }
+                Some(Opcode::BitNot) => {
+                    if !self.peek(0).is_number() {
+                        self.runtime_error("Operand must be a number.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let value = !(self.pop().as_number() as i64);
+                    self.push(Value::Number(value as f64));
+                }
+                Some(Opcode::Negate) => {
+                    if !self.peek(0).is_number() {
+                        self.runtime_error("Operand must be a number.");
+                        return InterpretResult::RuntimeError;
+                    }
+                    let negated_value = -self.pop().as_number();
+                    self.push(Value::Number(negated_value));
+                }
+                Some(Opcode::Stringify) => {
+                    if !self.peek(0).is_string() {
+                        let text = self.pop().to_string();
+                        self.push(string_value(&text));
+                    }
+                }
+                Some(Opcode::Dup) => {
+                    let value = self.peek(0).clone();
+                    self.push(value);
+                }
+                Some(Opcode::Swap) => {
+                    let top = self.stack.len() - 1;
+                    self.stack.swap(top, top - 1);
+                }
+                Some(Opcode::Over) => {
+                    let value = self.peek(1).clone();
+                    self.push(value);
+                }
+                Some(Opcode::Coalesce) => {
+                    let offset = self.read_short();
+                    let taken = !self.peek(0).is_nil();
+                    if taken {
+                        self.frame_mut().ip += offset as usize;
+                    } else {
+                        self.pop();
+                    }
+                    self.usage.record_branch(Opcode::Coalesce, taken);
+                }
+                Some(Opcode::Jump) => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                Some(Opcode::JumpIfFalse) => {
+                    let offset = self.read_short();
+                    let taken = self.peek(0).is_falsey();
+                    if taken {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                    self.usage.record_branch(Opcode::JumpIfFalse, taken);
+                }
+                Some(Opcode::JumpIfNil) => {
+                    let offset = self.read_short();
+                    let taken = self.peek(0).is_nil();
+                    if taken {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                    self.usage.record_branch(Opcode::JumpIfNil, taken);
+                }
+                Some(Opcode::Loop) => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                Some(Opcode::Print) => {
+                    let value = self.pop();
+                    // Failing to write, e.g. to a closed pipe, does not stop the script.
+                    let _ = self.console.borrow_mut().write(&format!("{}\n", value));
+                }
+                Some(Opcode::Pop) => {
+                    self.pop();
+                }
+                Some(Opcode::DefineGlobal) => {
+                    let name = self.read_constant().as_string();
+                    let value = self.pop();
+                    self.const_globals.remove(&name);
+                    self.globals.insert(name, value);
+                }
+                Some(Opcode::DefineConstGlobal) => {
+                    let name = self.read_constant().as_string();
+                    let value = self.pop();
+                    self.const_globals.insert(name.clone());
+                    self.globals.insert(name, value);
+                }
+                Some(Opcode::GetGlobal) => {
+                    let name = self.read_constant().as_string();
+                    match self.globals.get(&name) {
+                        Some(value) => {
+                            let value = value.clone();
+                            self.push(value);
+                        }
+                        None => {
+                            self.runtime_error(&format!("Undefined variable '{}'.", name));
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                Some(Opcode::SetGlobal) => {
+                    let name = self.read_constant().as_string();
+                    if self.const_globals.contains(&name) {
+                        self.runtime_error(&format!("Can't assign to constant '{}'.", name));
+                        return InterpretResult::RuntimeError;
+                    }
+                    // Assignment is an expression, so the value stays on the stack.
+                    let value = self.peek(0).clone();
+                    match self.globals.get_mut(&name) {
+                        Some(slot) => *slot = value,
+                        None => {
+                            self.runtime_error(&format!("Undefined variable '{}'.", name));
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                Some(Opcode::GetLocal) => {
+                    let slot = self.read_byte() as usize + self.frame().slots;
+                    self.push(self.stack[slot].clone());
+                }
+                Some(Opcode::SetLocal) => {
+                    let slot = self.read_byte() as usize + self.frame().slots;
+                    // Assignment is an expression, so the value stays on the stack.
+                    self.stack[slot] = self.peek(0).clone();
+                }
+                Some(Opcode::Call) => {
+                    let arg_count = self.read_byte();
+                    let callee = self.peek(arg_count as usize).clone();
+                    if !self.call_value(callee, arg_count) {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                Some(Opcode::Class) => {
+                    let name = self.read_constant().as_string();
+                    let class = Class {
+                        name: Rc::from(name.as_str()),
+                        superclass: OnceCell::new(),
+                        methods: RefCell::default(),
+                        statics: RefCell::default(),
+                    };
+                    self.push(Value::Class(Rc::new(class)));
+                }
+                // Dates and durations have read-only fields instead of properties.
+                Some(Opcode::GetProperty) if self.peek(0).is_time() => {
+                    let name = self.read_constant().as_string();
+                    let field = match self.peek(0) {
+                        Value::Date(date) => date.field(name.as_str()),
+                        Value::Duration(duration) => duration.field(name.as_str()),
+                        _ => unreachable!(),
+                    };
+                    let Some(field) = field else {
+                        self.runtime_error(&format!("Undefined property '{}'.", name));
+                        return InterpretResult::RuntimeError;
+                    };
+                    self.pop();
+                    self.push(Value::Number(field));
+                }
+                // Functions describe themselves with read-only fields.
+                Some(Opcode::GetProperty) if self.peek(0).is_function() => {
+                    let name = self.read_constant().as_string();
+                    let Some(field) = self.peek(0).function_field(name.as_str()) else {
+                        self.runtime_error(&format!("Undefined property '{}'.", name));
+                        return InterpretResult::RuntimeError;
+                    };
+                    self.pop();
+                    self.push(field);
+                }
+                Some(Opcode::GetProperty) if matches!(self.peek(0), Value::Class(_)) => {
+                    let name = self.read_constant().as_string();
+                    let Value::Class(class) = self.peek(0) else {
+                        unreachable!()
+                    };
+                    let Some(method) = Class::find_static(class, &name) else {
+                        self.runtime_error(&format!("Undefined static method '{}'.", name));
+                        return InterpretResult::RuntimeError;
+                    };
+                    // Static methods have no receiver, so they need no binding.
+                    self.pop();
+                    self.push(method);
+                }
+                Some(Opcode::GetProperty) => {
+                    let name = self.read_constant().as_string();
+                    let Value::Instance(instance) = self.peek(0) else {
+                        self.runtime_error("Only instances have properties.");
+                        return InterpretResult::RuntimeError;
+                    };
+
+                    // Fields shadow methods.
+                    let instance = instance.borrow();
+                    let value = match instance.fields.get(&name) {
+                        Some(value) => Some(value.clone()),
+                        None => self.bind_method(&instance.class, &name),
+                    };
+                    drop(instance);
+
+                    match value {
+                        Some(value) => {
+                            self.pop();
+                            self.push(value);
+                        }
+                        None => {
+                            self.runtime_error(&format!("Undefined property '{}'.", name));
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                Some(Opcode::Inherit) => {
+                    let Value::Class(superclass) = self.peek(1).clone() else {
+                        self.runtime_error("Superclass must be a class.");
+                        return InterpretResult::RuntimeError;
+                    };
+                    let Value::Class(subclass) = self.pop() else {
+                        unreachable!("OP_INHERIT follows the class being declared");
+                    };
+                    // A new class, so it can't have a superclass yet.
+                    let _ = subclass.superclass.set(superclass);
+                    self.pop();
+                }
+                Some(Opcode::GetSuper) => {
+                    let name = self.read_constant().as_string();
+                    let superclass = self.frame_superclass();
+                    match self.bind_method(&superclass, &name) {
+                        Some(bound) => {
+                            self.pop();
+                            self.push(bound);
+                        }
+                        None => {
+                            self.runtime_error(&format!("Undefined property '{}'.", name));
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                Some(Opcode::SuperInvoke) => {
+                    let name = self.read_constant().as_string();
+                    let arg_count = self.read_byte();
+                    let superclass = self.frame_superclass();
+                    let Some((method, class)) = Class::find_method(&superclass, &name) else {
+                        self.runtime_error(&format!("Undefined property '{}'.", name));
+                        return InterpretResult::RuntimeError;
+                    };
+                    // The instance is already in the callee's slot.
+                    if !self.call(method, Some(class), arg_count) {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                Some(Opcode::StaticMethod) => {
+                    let name = self.read_constant().as_string();
+                    let (Value::Function(method), Value::Class(class)) =
+                        (self.peek(0), self.peek(1))
+                    else {
+                        unreachable!("OP_STATIC_METHOD follows a method and its class");
+                    };
+                    class
+                        .statics
+                        .borrow_mut()
+                        .insert(name, Value::Function(method.clone()));
+                    self.pop();
+                }
+                Some(Opcode::Method) => {
+                    let name = self.read_constant().as_string();
+                    let (Value::Function(method), Value::Class(class)) =
+                        (self.peek(0), self.peek(1))
+                    else {
+                        unreachable!("OP_METHOD follows a method and its class");
+                    };
+                    class.methods.borrow_mut().insert(name, method.clone());
+                    self.pop();
+                }
+                Some(Opcode::SetProperty) => {
+                    let name = self.read_constant().as_string();
+                    let Value::Instance(instance) = self.peek(1) else {
+                        self.runtime_error("Only instances have fields.");
+                        return InterpretResult::RuntimeError;
+                    };
+
+                    let value = self.peek(0).clone();
+                    instance.borrow_mut().fields.insert(name, value);
+
+                    // Assignment is an expression, so the value replaces the instance.
+                    let value = self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                Some(Opcode::BuildList) => {
+                    let count = self.read_byte() as usize;
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    self.push(Value::List(Rc::new(RefCell::new(elements))));
+                }
+                Some(Opcode::BuildMap) => {
+                    let count = self.read_byte() as usize;
+                    let entries = self.stack.split_off(self.stack.len() - count * 2);
+                    let mut entries = entries.into_iter();
+
+                    let mut map = IndexMap::with_capacity(count);
+                    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                        match MapKey::new(key) {
+                            Ok(key) => map.insert(key, value),
+                            Err(message) => {
+                                self.runtime_error(message);
+                                return InterpretResult::RuntimeError;
+                            }
+                        };
+                    }
+                    self.push(Value::Map(Rc::new(RefCell::new(map))));
+                }
+                Some(Opcode::IndexGet) => {
+                    let subscript = match self.subscript(1, 0) {
+                        Ok(subscript) => subscript,
+                        Err(message) => {
+                            self.runtime_error(message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    };
+                    let element = match (self.peek(1), subscript) {
+                        (Value::List(list), Subscript::Index(index)) => {
+                            list.borrow()[index].clone()
+                        }
+                        // Missing keys read as nil.
+                        (Value::Map(map), Subscript::Key(key)) => {
+                            map.borrow().get(&key).cloned().unwrap_or(Value::Nil)
+                        }
+                        _ => unreachable!(),
+                    };
+                    self.pop();
+                    self.pop();
+                    self.push(element);
+                }
+                Some(Opcode::IndexSet) => {
+                    let subscript = match self.subscript(2, 1) {
+                        Ok(subscript) => subscript,
+                        Err(message) => {
+                            self.runtime_error(message);
+                            return InterpretResult::RuntimeError;
+                        }
+                    };
+                    let value = self.pop();
+                    self.pop();
+                    match (self.pop(), subscript) {
+                        (Value::List(list), Subscript::Index(index)) => {
+                            list.borrow_mut()[index] = value.clone();
+                        }
+                        (Value::Map(map), Subscript::Key(key)) => {
+                            map.borrow_mut().insert(key, value.clone());
+                        }
+                        _ => unreachable!(),
+                    }
+
+                    // Assignment is an expression, so the value replaces the collection and
+                    // subscript.
+                    self.push(value);
+                }
+                Some(Opcode::Return) => {
+                    if self.stack.len() <= self.frame().slots {
+                        return self.internal_error("Stack underflow on return.");
+                    }
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+
+                    // Discard the callee along with its arguments and locals.
+                    self.stack.truncate(frame.slots);
+                    self.push(result);
+
+                    if self.frames.is_empty() {
+                        // The result stays on the stack for the caller to collect.
+                        return InterpretResult::Ok;
+                    }
+                }
+                None => {
+                    let message = format!("Invalid opcode {}.", byte);
+                    return self.internal_error(&message);
+                }
+            }
+        }
+        self.internal_error("Ran past the end of the chunk.")
+    }
+
+    // Bind the method `name` found on `class` to the instance on top of the stack.
+    fn bind_method(&self, class: &Rc<Class>, name: &Rc<LoxString>) -> Option<Value> {
+        let (method, class) = Class::find_method(class, name)?;
+        let receiver = self.peek(0).clone();
+        let bound = BoundMethod {
+            receiver,
+            method,
+            class,
+        };
+        Some(Value::BoundMethod(Rc::new(bound)))
+    }
+
+    // Check the collection and subscript this far below the top of the stack.
+    fn subscript(&self, collection: usize, subscript: usize) -> Result<Subscript, &'static str> {
+        let list = match self.peek(collection) {
+            Value::List(list) => list,
+            Value::Map(_) => return MapKey::new(self.peek(subscript).clone()).map(Subscript::Key),
+            _ => return Err("Only lists and maps can be indexed."),
+        };
+        let Value::Number(index) = *self.peek(subscript) else {
+            return Err("List index must be a number.");
+        };
+
+        if index.fract() != 0.0 {
+            Err("List index must be an integer.")
+        } else if index < 0.0 || index >= list.borrow().len() as f64 {
+            Err("List index out of range.")
+        } else {
+            Ok(Subscript::Index(index as usize))
+        }
+    }
+
+    // The superclass of the class declaring the running method. The compiler only allows
+    // `super` in methods of classes which have one.
+    fn frame_superclass(&self) -> Rc<Class> {
+        let class = self
+            .frame()
+            .class
+            .as_ref()
+            .expect("`super` is only compiled into methods");
+        class
+            .superclass
+            .get()
+            .expect("`super` needs a superclass")
+            .clone()
+    }
+
+    // Look at a value `offset` slots down from the top of the stack.
+    fn peek(&self, offset: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - offset]
+    }
+
+    // Call `callee`, whose arguments are on top of the stack. Returns false after reporting a
+    // runtime error.
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> bool {
+        match callee {
+            Value::Function(function) => self.call(function, None, arg_count),
+            Value::Native(native) => self.call_native(&native, arg_count),
+            Value::Class(class) => {
+                let initializer = Class::find_method(&class, &self.init_string);
+
+                // The instance takes the class's place, as the initializer's `this` and as the
+                // call's result.
+                let instance = Instance {
+                    class,
+                    fields: StringTable::default(),
+                };
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = Value::Instance(Rc::new(RefCell::new(instance)));
+
+                match initializer {
+                    Some((initializer, class)) => self.call(initializer, Some(class), arg_count),
+                    None if arg_count != 0 => {
+                        self.runtime_error(&format!("Expected 0 arguments but got {}.", arg_count));
+                        false
+                    }
+                    None => true,
+                }
+            }
+            Value::BoundMethod(bound) => {
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = bound.receiver.clone();
+                self.call(bound.method.clone(), Some(bound.class.clone()), arg_count)
+            }
+            _ => {
+                self.runtime_error("Can only call functions and classes.");
+                false
+            }
+        }
+    }
+
+    // Call a function. For a method `class` is the class declaring it, and the instance has
+    // already replaced the callee in slot zero.
+    fn call(&mut self, function: Rc<Function>, class: Option<Rc<Class>>, arg_count: u8) -> bool {
+        let slots = self.stack.len() - arg_count as usize - 1;
+
+        if function.variadic {
+            if arg_count < function.arity {
+                self.runtime_error(&format!(
+                    "Expected at least {} arguments but got {}.",
+                    function.arity, arg_count
+                ));
+                return false;
+            }
+            let rest = self.stack.split_off(slots + 1 + function.arity as usize);
+            self.push(Value::List(Rc::new(RefCell::new(rest))));
+        } else if arg_count != function.arity {
+            self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                function.arity, arg_count
+            ));
+            return false;
+        }
+
+        if self.frames.len() >= self.max_call_depth {
+            self.call_depth_error(&function);
+            return false;
+        }
+
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slots,
+            class,
+        });
+        true
+    }
+
+    // Natives run to completion without a frame of their own, so the result replaces the callee
+    // and its arguments straight away.
+    fn call_native(&mut self, native: &NativeFn, arg_count: u8) -> bool {
+        if arg_count != native.arity {
+            self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                native.arity, arg_count
+            ));
+            return false;
+        }
+
+        *self
+            .usage
+            .natives_called
+            .entry(native.name.to_string())
+            .or_default() += 1;
+
+        let args_start = self.stack.len() - arg_count as usize;
+        match (native.body)(&self.stack[args_start..]) {
+            Ok(result) => {
+                self.stack.truncate(args_start - 1);
+                self.push(result);
+                true
+            }
+            Err(message) => {
+                self.runtime_error(&message);
+                false
+            }
+        }
+    }
+
+    fn runtime_error(&mut self, message: &str) {
+        self.report(message);
+
+        for frame in self.frames.iter().rev() {
+            self.report(&format!(
+                "[{}] in {}",
+                self.frame_location(frame),
+                frame_name(frame)
+            ));
+        }
+    }
+
+    // Write a line of an error report.
+    fn report(&self, line: &str) {
+        self.console
+            .borrow_mut()
+            .write_error(&format!("{}\n", line));
+    }
+
+    // Report a state the compiler should never produce, such as a bad opcode, and save what the
+    // VM looked like for the bug report.
+    fn internal_error(&mut self, message: &str) -> InterpretResult {
+        let dump = self.crash_dump(message);
+        let name = format!(
+            "rustlox-crash-{}-{}.txt",
+            std::process::id(),
+            self.usage.instructions
+        );
+        let path = self.crash_dir.join(name);
+
+        match std::fs::write(&path, &dump) {
+            Ok(()) => self.report(&format!(
+                "Internal error: {}\nCrash dump written to {}.",
+                message,
+                path.display()
+            )),
+            Err(_) => self.report(&dump),
+        }
+        InterpretResult::RuntimeError
+    }
+
+    // Describe the VM's state: the code around the faulting instruction, the stack, the calls in
+    // progress and the globals.
+    fn crash_dump(&self, message: &str) -> String {
+        // Long values, e.g. big lists, are cut short to keep the dump readable.
+        fn clip(text: String) -> String {
+            match text.char_indices().nth(72) {
+                Some((end, _)) => format!("{}...", &text[..end]),
+                None => text,
+            }
+        }
+
+        let mut dump = format!("Internal error: {}\n", message);
+
+        if let Some(frame) = self.frames.last() {
+            let chunk = &frame.function.chunk;
+            // The faulting instruction has already been read.
+            let fault = frame.ip.saturating_sub(1);
+            let mut disassembler = Disassembler::new(chunk, Palette::PLAIN);
+            let mut listing = vec![];
+            let mut offset = 0;
+            while offset < chunk.code.len() {
+                let (text, next) = disassembler.instruction(offset);
+                listing.push((offset, text));
+                offset = next;
+            }
+            let at = listing
+                .iter()
+                .rposition(|(offset, _)| *offset <= fault)
+                .unwrap_or(0);
+
+            dump.push_str(&format!("\n== Code in {} ==\n", frame_name(frame)));
+            for (offset, text) in &listing[at.saturating_sub(8)..listing.len().min(at + 4)] {
+                let marker = if *offset == listing[at].0 { "->" } else { "  " };
+                dump.push_str(&format!("{} {}\n", marker, text));
+            }
+        }
+
+        dump.push_str(&format!(
+            "\n== Stack ({} values, top last) ==\n",
+            self.stack.len()
+        ));
+        for (slot, value) in self.stack.iter().enumerate() {
+            dump.push_str(&format!("{:4} {}\n", slot, clip(value.repr())));
+        }
+
+        dump.push_str(&format!(
+            "\n== Calls ({}, innermost first) ==\n",
+            self.frames.len()
+        ));
+        for frame in self.frames.iter().rev() {
+            dump.push_str(&format!(
+                "[{}] in {} (ip {}, slots from {})\n",
+                self.frame_location(frame),
+                frame_name(frame),
+                frame.ip,
+                frame.slots
+            ));
+        }
+
+        let mut globals: Vec<_> = self.globals.iter().collect();
+        globals.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        dump.push_str(&format!("\n== Globals ({}) ==\n", globals.len()));
+        for (name, value) in globals {
+            dump.push_str(&format!("{} = {}\n", name.as_str(), clip(value.repr())));
+        }
+        dump
+    }
+
+    // Explain a stack overflow by the function that recursed rather than by every frame.
+    fn call_depth_error(&mut self, callee: &Function) {
+        self.report(&format!(
+            "Stack overflow: {} exceeded the call depth limit of {}.",
+            frame_name_of(callee),
+            self.max_call_depth
+        ));
+
+        // Runs of frames in the same function, innermost first.
+        let mut runs: Vec<(&CallFrame, usize)> = vec![];
+        for frame in self.frames.iter().rev() {
+            match runs.last_mut() {
+                Some((last, count)) if Rc::ptr_eq(&last.function, &frame.function) => *count += 1,
+                _ => runs.push((frame, 1)),
+            }
+        }
+
+        self.report("Most recent calls:");
+        for (frame, count) in runs.iter().take(OVERFLOW_FRAMES_SHOWN) {
+            let repeats = if *count > 1 {
+                format!(" ({} frames)", count)
+            } else {
+                String::new()
+            };
+            let location = self.frame_location(frame);
+            self.report(&format!(
+                "  [{}] in {}{}",
+                location,
+                frame_name(frame),
+                repeats
+            ));
+        }
+        if runs.len() > OVERFLOW_FRAMES_SHOWN {
+            self.report("  ...");
+        }
+        self.report("Use --max-call-depth to raise the limit if the recursion is intended.");
+    }
+
+    // Where a frame is: the instruction which failed, or the call it is waiting on.
+    fn frame_location(&self, frame: &CallFrame) -> String {
+        // The instruction has already been read.
+        let instruction = frame.ip.saturating_sub(1);
+        let location = frame.function.chunk.location(instruction);
+        self.sources.describe(location)
+    }
+}
+
+// Read a line for `input()`, without its line ending, or nil at the end of the input.
+fn read_input_line(reader: &mut dyn BufRead) -> Result<Value, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            Ok(string_value(line.strip_suffix('\r').unwrap_or(line)))
+        }
+        Err(err) => Err(format!("Could not read input: {}.", err)),
+    }
+}
+
+// How a frame is named in stack traces, e.g. `fib()` or `script`.
+fn frame_name(frame: &CallFrame) -> String {
+    frame_name_of(&frame.function)
+}
+
+fn frame_name_of(function: &Function) -> String {
+    match &function.name {
+        Some(name) => format!("{}()", name),
+        None => "script".to_string(),
+    }
+}
+
+//
+// CSV.
+//
+
+// A field read from CSV text, and whether it was quoted.
+type CsvField = (String, bool);
+
+// Split CSV text into records of fields, each with the line it starts on. Fields may be quoted
+// to hold commas, line breaks and doubled `""` quotes. Blank lines are skipped.
+fn csv_records(text: &str) -> Result<Vec<(usize, Vec<CsvField>)>, String> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let (mut line, mut record_line) = (1, 1);
+
+    let mut chars = text.chars().peekable();
+    loop {
+        match chars.next() {
+            Some('"') if field.is_empty() && !quoted => {
+                quoted = true;
+                let start = line;
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            field.push('"');
+                        }
+                        Some('"') => break,
+                        Some(c) => {
+                            line += (c == '\n') as usize;
+                            field.push(c);
+                        }
+                        None => {
+                            return Err(format!("Unterminated quoted field on line {}.", start))
+                        }
+                    }
+                }
+                if !matches!(chars.peek(), None | Some(',' | '\r' | '\n')) {
+                    return Err(format!("Expect ',' after quoted field on line {}.", line));
+                }
+            }
+            Some(',') => record.push((mem::take(&mut field), mem::take(&mut quoted))),
+            Some('\r') if chars.peek() == Some(&'\n') => {}
+            end @ (Some('\n') | None) => {
+                record.push((mem::take(&mut field), mem::take(&mut quoted)));
+                let blank = record.len() == 1 && record[0] == (String::new(), false);
+                if !blank {
+                    records.push((record_line, mem::take(&mut record)));
+                }
+                record.clear();
+
+                if end.is_none() {
+                    return Ok(records);
+                }
+                line += 1;
+                record_line = line;
+            }
+            Some(c) => field.push(c),
+        }
+    }
+}
+
+// Parse CSV text whose first record names the columns into a list with a map per row. Unquoted
+// fields which read as numbers become numbers, so quote those meant as text, e.g. "007".
+fn parse_csv(text: &str) -> Result<Value, String> {
+    let mut records = csv_records(text)?.into_iter();
+    let Some((_, header)) = records.next() else {
+        return Ok(Value::List(Rc::default()));
+    };
+
+    let mut columns = vec![];
+    for (name, _) in header {
+        let key = MapKey::new(string_value(&name))?;
+        if columns.contains(&key) {
+            return Err(format!("Duplicate column '{}'.", name));
+        }
+        columns.push(key);
+    }
+
+    let mut rows = vec![];
+    for (line, record) in records {
+        if record.len() != columns.len() {
+            return Err(format!(
+                "Line {} has {} fields but the header has {}.",
+                line,
+                record.len(),
+                columns.len()
+            ));
+        }
+        let row = columns
+            .iter()
+            .cloned()
+            .zip(record)
+            .map(|(column, (text, quoted))| {
+                let number = text
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|n| !quoted && n.is_finite());
+                (
+                    column,
+                    number.map_or_else(|| string_value(&text), Value::Number),
+                )
+            })
+            .collect();
+        rows.push(Value::Map(Rc::new(RefCell::new(row))));
+    }
+    Ok(Value::List(Rc::new(RefCell::new(rows))))
+}
+
+// Write a list of maps as CSV, with the first map's keys as the header. Every row must only use
+// those keys, and a missing one or nil leaves the field empty. Fields are quoted when needed,
+// and so are strings which would read back as numbers.
+fn format_csv(rows: &Value) -> Result<String, String> {
+    const ROWS_ERROR: &str = "CSV rows must be a list of maps.";
+    let Value::List(rows) = rows else {
+        return Err(ROWS_ERROR.to_string());
+    };
+    let maps = rows
+        .borrow()
+        .iter()
+        .map(|row| match row {
+            Value::Map(map) => Ok(map.clone()),
+            _ => Err(ROWS_ERROR.to_string()),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let Some(first) = maps.first() else {
+        return Ok(String::new());
+    };
+    let columns: Vec<MapKey> = first.borrow().keys().cloned().collect();
+
+    // Strings which look like numbers are quoted so they read back as strings. Any other value is
+    // written as printed, quoted if that text holds a separator, e.g. the list `[1, 2]`.
+    fn field(value: &Value) -> String {
+        let (text, string) = match value {
+            Value::Nil => return String::new(),
+            Value::String(_) | Value::InlineStr(_) => (value.as_str().to_string(), true),
+            _ => (value.to_string(), false),
+        };
+        let special = text.contains([',', '"', '\r', '\n']);
+        if special || string && text.parse::<f64>().is_ok_and(f64::is_finite) {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        } else {
+            text
+        }
+    }
+
+    let header: Vec<String> = columns.iter().map(|column| field(&column.0)).collect();
+    let mut text = format!("{}\n", header.join(","));
+    for (index, map) in maps.iter().enumerate() {
+        let map = map.borrow();
+        if let Some(extra) = map.keys().find(|key| !columns.contains(key)) {
+            return Err(format!(
+                "Row {} has column {} which the first row lacks.",
+                index + 1,
+                extra.0.repr()
+            ));
+        }
+        let fields: Vec<String> = columns
+            .iter()
+            .map(|column| map.get(column).map(field).unwrap_or_default())
+            .collect();
+        text.push_str(&fields.join(","));
+        text.push('\n');
+    }
+    Ok(text)
+}
+
+//
+// Text patterns.
+//
+
+// A piece of a `parse` pattern.
+enum PatternPart<'a> {
+    // A character which must appear as is.
+    Literal(char),
+    // A run of whitespace.
+    Space,
+    // A placeholder, with its name if it has one and its kind: `num`, `int` or `word`.
+    Capture(Option<&'a str>, &'a str),
+}
+
+// Split a pattern into its parts, checking its placeholders.
+fn pattern_parts(pattern: &str) -> Result<Vec<PatternPart<'_>>, String> {
+    let mut parts = vec![];
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        if let Some(after) = rest.strip_prefix("{{") {
+            parts.push(PatternPart::Literal('{'));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("}}") {
+            parts.push(PatternPart::Literal('}'));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let (spec, after) = after
+                .split_once('}')
+                .ok_or("Unterminated placeholder in pattern.")?;
+            let (name, kind) = match spec.split_once(':') {
+                Some((name, kind)) => (Some(name), kind),
+                None => (None, spec),
+            };
+            let named = name.is_none_or(|name| !name.is_empty());
+            if !named || !matches!(kind, "num" | "int" | "word") {
+                return Err(format!("Unknown placeholder '{{{}}}' in pattern.", spec));
+            }
+            parts.push(PatternPart::Capture(name, kind));
+            rest = after;
+        } else if c == '}' {
+            return Err("Unmatched '}' in pattern.".to_string());
+        } else if c.is_whitespace() {
+            parts.push(PatternPart::Space);
+            rest = rest.trim_start();
+        } else {
+            parts.push(PatternPart::Literal(c));
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    Ok(parts)
+}
+
+// Read a number from the start of `text`, with an optional sign and, if `fraction` allows it,
+// digits after a decimal point. Returns it with the text after it.
+fn scan_number(text: &str, fraction: bool) -> Option<(f64, &str)> {
+    let digits = |text: &str| {
+        text.find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len())
+    };
+    let sign = text.starts_with(['+', '-']) as usize;
+    let whole = digits(&text[sign..]);
+    if whole == 0 {
+        return None;
+    }
+
+    let mut end = sign + whole;
+    if let Some(after) = text[end..].strip_prefix('.').filter(|_| fraction) {
+        let decimals = digits(after);
+        if decimals > 0 {
+            end += 1 + decimals;
+        }
+    }
+    Some((text[..end].parse().ok()?, &text[end..]))
+}
+
+// Match the whole of `text` against `parts`, returning what the placeholders captured.
+fn match_pattern(parts: &[PatternPart], mut text: &str) -> Option<Vec<Value>> {
+    let mut captures = vec![];
+    for (index, part) in parts.iter().enumerate() {
+        text = match *part {
+            PatternPart::Literal(c) => text.strip_prefix(c)?,
+            PatternPart::Space => {
+                let after = text.trim_start();
+                (after.len() < text.len()).then_some(after)?
+            }
+            PatternPart::Capture(_, "word") => {
+                // A word stops at whitespace, or at the character the pattern expects next.
+                let next = match parts.get(index + 1) {
+                    Some(PatternPart::Literal(c)) => Some(*c),
+                    _ => None,
+                };
+                let end = text
+                    .find(|c: char| c.is_whitespace() || Some(c) == next)
+                    .unwrap_or(text.len());
+                if end == 0 {
+                    return None;
+                }
+                captures.push(string_value(&text[..end]));
+                &text[end..]
+            }
+            PatternPart::Capture(_, kind) => {
+                let (number, after) = scan_number(text, kind == "num")?;
+                captures.push(Value::Number(number));
+                after
+            }
+        };
+    }
+    text.is_empty().then_some(captures)
+}
+
+// Extract values from `text` with a pattern of placeholders, e.g. `parse("12 apples", "{num}
+// {word}")` gives `[12, "apples"]`. `{num}` reads a number, `{int}` one without a fraction and
+// `{word}` a run of characters up to whitespace or the next character in the pattern. Naming
+// every placeholder, as in `{count:int}`, gives a map instead of a list. A space matches any run
+// of whitespace, and `{{` and `}}` match braces. Placeholders never backtrack, and text which
+// doesn't match gives nil.
+fn parse_text(text: &str, pattern: &str) -> Result<Value, String> {
+    let parts = pattern_parts(pattern)?;
+    let names: Vec<Option<&str>> = parts
+        .iter()
+        .filter_map(|part| match part {
+            PatternPart::Capture(name, _) => Some(*name),
+            _ => None,
+        })
+        .collect();
+    let named = names.iter().any(Option::is_some);
+    if named && names.iter().any(Option::is_none) {
+        return Err("Name every placeholder in the pattern or none of them.".to_string());
+    }
+    let mut seen = HashSet::new();
+    if let Some(name) = names.iter().flatten().find(|name| !seen.insert(**name)) {
+        return Err(format!("Duplicate placeholder name '{}'.", name));
+    }
+
+    let Some(captures) = match_pattern(&parts, text) else {
+        return Ok(Value::Nil);
+    };
+    if !named {
+        return Ok(Value::List(Rc::new(RefCell::new(captures))));
+    }
+
+    let map = names
+        .into_iter()
+        .flatten()
+        .zip(captures)
+        .map(|(name, value)| Ok((MapKey::new(string_value(name))?, value)))
+        .collect::<Result<_, String>>()?;
+    Ok(Value::Map(Rc::new(RefCell::new(map))))
+}
+
+//
+// Program generator.
+//
+
+// How deeply generated expressions may nest.
+#[cfg(feature = "gen")]
+const GEN_MAX_DEPTH: u32 = 6;
+
+/// Produces random, syntactically valid programs from the Lox grammar. The same seed always
+/// yields the same program, so a failing case can be reproduced from its seed alone.
+#[cfg(feature = "gen")]
+pub struct Generator {
+    state: u64,
+    // Globals declared so far, named g0, g1, ...
+    globals: u64,
+}
+
+#[cfg(feature = "gen")]
+impl Generator {
+    /// Returns a generator for the given seed.
+    pub fn new(seed: u64) -> Self {
+        // Scramble the seed with splitmix64 so nearby seeds diverge. Xorshift gets stuck on a
+        // zero state, and exactly one seed mixes to zero.
+        let mut state = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Self {
+            state: (state ^ (state >> 31)).max(1),
+            globals: 0,
+        }
+    }
+
+    // Next pseudo-random number (xorshift64).
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    // Pick a number in `0..bound`.
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    /// Generate a complete program.
+    pub fn program(&mut self) -> String {
+        let statements = 1 + self.below(5);
+        (0..statements).map(|_| self.statement()).collect()
+    }
+
+    fn statement(&mut self) -> String {
+        let expression = self.expression(0);
+        match self.below(4) {
+            0 => format!("print {};\n", expression),
+            1 => {
+                self.globals += 1;
+                format!("var g{} = {};\n", self.globals - 1, expression)
+            }
+            2 if self.globals > 0 => format!("g{} = {};\n", self.below(self.globals), expression),
+            _ => format!("{};\n", expression),
+        }
+    }
+
+    fn expression(&mut self, depth: u32) -> String {
+        // Bias towards literals as we get deeper so programs stay finite.
+        if depth >= GEN_MAX_DEPTH || self.below(GEN_MAX_DEPTH as u64) < depth as u64 {
+            return self.literal();
+        }
+
+        match self.below(3) {
+            0 => format!("({})", self.expression(depth + 1)),
+            1 => {
+                let operator = ["-", "!"][self.below(2) as usize];
+                format!("{}{}", operator, self.expression(depth + 1))
+            }
+            _ => {
+                let operators = [
+                    "+", "-", "*", "/", "==", "!=", "<", "<=", ">", ">=", "and", "or",
+                ];
+                let operator = operators[self.below(operators.len() as u64) as usize];
+                let left = self.expression(depth + 1);
+                let right = self.expression(depth + 1);
+                format!("{} {} {}", left, operator, right)
+            }
+        }
+    }
+
+    fn literal(&mut self) -> String {
+        if self.globals > 0 && self.below(4) == 0 {
+            return format!("g{}", self.below(self.globals));
+        }
+
+        match self.below(6) {
+            0 => "nil".to_string(),
+            1 => "true".to_string(),
+            2 => "false".to_string(),
+            3 => format!("\"s{}\"", self.below(100)),
+            4 => format!(":sym{}", self.below(10)),
+            _ => {
+                let number = self.below(1000);
+                if self.below(2) == 0 {
+                    number.to_string()
+                } else {
+                    format!("{}.{}", number, self.below(100))
+                }
+            }
+        }
+    }
+}
+
+//
+// Snapshot tests.
+//
+
+/// What a program printed and reported, and how its run ended.
+#[derive(Debug)]
+pub struct CapturedRun {
+    pub stdout: String,
+    pub stderr: String,
+    pub result: InterpretResult,
+}
+
+/// Run `source` as a script in a fresh VM, collecting its output and errors instead of writing
+/// them out. `input()` finds the end of its input straight away.
+pub fn run_source(source: &str) -> CapturedRun {
+    let mut vm = VM::new(Chunk::new());
+    vm.capture_output();
+    vm.capture_errors();
+    vm.set_input(io::empty());
+
+    let result = vm.interpret(source);
+    CapturedRun {
+        stdout: vm.take_output(),
+        stderr: vm.take_errors(),
+        result,
+    }
+}
+
+/// Run every `.lox` program in `dir`, in name order, and compare its output and errors with the
+/// `.stdout` and `.stderr` files beside it. A missing file expects nothing. With `bless` the files
+/// are rewritten to match instead. Returns a description of each mismatch.
+pub fn check_snapshots(dir: &Path, bless: bool) -> io::Result<Vec<String>> {
+    let mut programs = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    programs.retain(|path| path.extension().is_some_and(|extension| extension == "lox"));
+    programs.sort();
+
+    let mut mismatches = vec![];
+    for program in programs {
+        let run = run_source(&std::fs::read_to_string(&program)?);
+
+        for (extension, actual) in [("stdout", run.stdout), ("stderr", run.stderr)] {
+            let snapshot = program.with_extension(extension);
+            let expected = match std::fs::read_to_string(&snapshot) {
+                Ok(expected) => expected,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+                Err(err) => return Err(err),
+            };
+
+            if bless && actual.is_empty() {
+                if snapshot.exists() {
+                    std::fs::remove_file(&snapshot)?;
+                }
+            } else if bless {
+                std::fs::write(&snapshot, &actual)?;
+            } else if actual != expected {
+                mismatches.push(format!(
+                    "{} differs.\n--- expected\n{}--- actual\n{}",
+                    snapshot.display(),
+                    expected,
+                    actual
+                ));
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+//
+// Control-flow analysis.
+//
+
+/// A run of instructions which is only entered at its first and only left after its last.
+#[derive(Debug, PartialEq)]
+pub struct BasicBlock {
+    /// Offset of the first instruction.
+    pub start: usize,
+    /// Offset just past the last instruction.
+    pub end: usize,
+    /// Start offsets of the blocks control may continue to.
+    pub successors: Vec<usize>,
+}
+
+impl Chunk {
+    /// The offset a jump at `offset` lands on, or `None` if it is not a jump.
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let sign = match Opcode::decode(self.code[offset])?.operands() {
+            Operands::Jump => 1,
+            Operands::Loop => -1,
+            _ => return None,
+        };
+        let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]) as i64;
+        Some((offset as i64 + 3 + sign * jump) as usize)
+    }
+
+    /// Check that every reachable instruction finds the stack equally deep along all paths to it,
+    /// never pops more than is there and only touches local slots that exist, given the `base`
+    /// values a call starts with. Returns the deepest the stack gets.
+    pub fn check_stack_depth(&self, base: usize) -> Result<usize, String> {
+        let mut depths = vec![None; self.code.len()];
+        let mut pending = vec![(0, base)];
+        let mut deepest = base;
+
+        while let Some((offset, depth)) = pending.pop() {
+            match depths.get(offset) {
+                None => {
+                    return Err(format!(
+                        "{:04}: control runs off the end of the code",
+                        offset
+                    ))
+                }
+                Some(Some(seen)) if *seen == depth => continue,
+                Some(Some(seen)) => {
+                    return Err(format!(
+                        "{:04}: the stack holds {} values on one path and {} on another",
+                        offset, seen, depth
+                    ))
+                }
+                Some(None) => depths[offset] = Some(depth),
+            }
+
+            let byte = self.code[offset];
+            let Some(opcode) = Opcode::decode(byte) else {
+                return Err(format!("{:04}: unknown opcode {}", offset, byte));
+            };
+            let width = opcode.operands().width();
+            let Some(&count) = self.code.get(offset + width) else {
+                return Err(format!(
+                    "{:04}: {} is missing its operands",
+                    offset,
+                    opcode.name()
+                ));
+            };
+
+            let (pops, pushes) = opcode.stack_effect(count);
+            if pops > depth {
+                return Err(format!(
+                    "{:04}: {} pops {} values but the stack holds {}",
+                    offset,
+                    opcode.name(),
+                    pops,
+                    depth
+                ));
+            }
+            if matches!(opcode, Opcode::GetLocal | Opcode::SetLocal) && count as usize >= depth {
+                return Err(format!(
+                    "{:04}: {} uses slot {} but the stack holds {} values",
+                    offset,
+                    opcode.name(),
+                    count,
+                    depth
+                ));
+            }
+            let after = depth - pops + pushes;
+            deepest = deepest.max(after);
+
+            // A jump which is taken leaves the stack as it found it.
+            if let Some(target) = self.jump_target(offset) {
+                pending.push((target, depth));
+            }
+            if !matches!(opcode, Opcode::Return | Opcode::Jump | Opcode::Loop) {
+                pending.push((offset + 1 + width, after));
+            }
+        }
+        Ok(deepest)
+    }
+
+    /// Split the chunk into basic blocks, in code order.
+    pub fn basic_blocks(&self) -> Vec<BasicBlock> {
+        // Offsets and successors of every instruction.
+        let mut instructions = vec![];
+        let mut disassembler = Disassembler::new(self, Palette::PLAIN);
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let (_, next) = disassembler.instruction(offset);
+            let target = self.jump_target(offset);
+            let successors = match Opcode::decode(self.code[offset]) {
+                Some(Opcode::Return) => vec![],
+                Some(Opcode::Jump | Opcode::Loop) => vec![target.unwrap()],
+                _ => [Some(next), target].into_iter().flatten().collect(),
+            };
+            instructions.push((offset, next, successors));
+            offset = next;
+        }
+
+        // A block starts at the top, at every jump target and after every jump or return.
+        let mut leaders = HashSet::from([0]);
+        for (offset, next, successors) in &instructions {
+            if self.jump_target(*offset).is_some() || successors.is_empty() {
+                leaders.insert(*next);
+                leaders.extend(successors);
+            }
+        }
+
+        let mut blocks: Vec<BasicBlock> = vec![];
+        for (offset, next, successors) in instructions {
+            if leaders.contains(&offset) || blocks.is_empty() {
+                blocks.push(BasicBlock {
+                    start: offset,
+                    end: offset,
+                    successors: vec![],
+                });
+            }
+            let block = blocks.last_mut().unwrap();
+            block.end = next;
+            block.successors = successors;
+        }
+
+        // A block that runs off the end of the code goes nowhere.
+        let len = self.code.len();
+        for block in &mut blocks {
+            block.successors.retain(|successor| *successor < len);
+        }
+        blocks
+    }
+}
+
+impl Chunk {
+    /// The functions declared directly in this chunk.
+    pub fn functions(&self) -> impl Iterator<Item = &Rc<Function>> {
+        self.constants.iter().filter_map(|constant| match constant {
+            Value::Function(function) => Some(function),
+            _ => None,
+        })
+    }
+
+    /// Names of the globals this chunk reads.
+    pub fn global_reads(&self) -> Vec<Rc<str>> {
+        let mut reads = vec![];
+        let mut disassembler = Disassembler::new(self, Palette::PLAIN);
+        let mut offset = 0;
+        while offset < self.code.len() {
+            if let Some(Opcode::GetGlobal) = Opcode::decode(self.code[offset]) {
+                let name = self.constants[self.code[offset + 1] as usize].as_string();
+                reads.push(Rc::from(name.as_str()));
+            }
+            offset = disassembler.instruction(offset).1;
+        }
+        reads
+    }
+}
+
+// Every function in a program with its name, the script first and each function before the
+// ones declared in it.
+pub fn program_functions(script: &Chunk) -> Vec<(String, &Chunk)> {
+    let mut functions = vec![("script".to_string(), script)];
+
+    let mut index = 0;
+    while index < functions.len() {
+        let chunk = functions[index].1;
+        for function in chunk.functions() {
+            let name = function.name.as_deref().unwrap_or("script");
+            functions.push((name.to_string(), &function.chunk));
+        }
+        index += 1;
+    }
+    functions
+}
+
+// Render the control-flow graph of each function and the call graph between them as Graphviz.
+//
+// Functions are values, so which function a call reaches is only known at runtime. The call
+// graph approximates it with an edge to every function a function reads by global name.
+pub fn graphs_to_dot(name: &str, chunk: &Chunk) -> String {
+    let functions = program_functions(chunk);
+
+    let mut dot = format!("digraph \"{}\" {{\n", dot_escape(name));
+    dot.push_str("    node [shape=box, fontname=monospace];\n");
+    for (index, (function, chunk)) in functions.iter().enumerate() {
+        let id = format!("f{}", index);
+        dot.push_str(&format!("    subgraph \"cluster_{}\" {{\n", id));
+        dot.push_str(&format!("        label=\"{}\";\n", dot_escape(function)));
+
+        let mut disassembler = Disassembler::new(chunk, Palette::PLAIN);
+        let blocks = chunk.basic_blocks();
+        for block in &blocks {
+            let mut label = String::new();
+            let mut offset = block.start;
+            while offset < block.end {
+                let (text, next) = disassembler.instruction(offset);
+                label.push_str(&dot_escape(&text));
+                label.push_str("\\l");
+                offset = next;
+            }
+            dot.push_str(&format!(
+                "        \"{}_{}\" [label=\"{}\"];\n",
+                id, block.start, label
+            ));
+        }
+        for block in &blocks {
+            for successor in &block.successors {
+                dot.push_str(&format!(
+                    "        \"{}_{}\" -> \"{}_{}\";\n",
+                    id, block.start, id, successor
+                ));
+            }
+        }
+        dot.push_str("    }\n");
+    }
+    dot.push_str("}\n\n");
+
+    dot.push_str("digraph calls {\n");
+    for (index, (function, _)) in functions.iter().enumerate() {
+        dot.push_str(&format!(
+            "    f{} [label=\"{}\"];\n",
+            index,
+            dot_escape(function)
+        ));
+    }
+    for (caller, (_, chunk)) in functions.iter().enumerate() {
+        let reads = chunk.global_reads();
+        // The script itself is never called.
+        for (callee, (function, _)) in functions.iter().enumerate().skip(1) {
+            if reads.iter().any(|read| **read == **function) {
+                dot.push_str(&format!("    f{} -> f{};\n", caller, callee));
+            }
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+//
+// Completion.
+//
+
+// Keywords which begin a statement or declaration.
+const STATEMENT_KEYWORDS: &[&str] = &[
+    "break", "class", "const", "continue", "defer", "for", "fun", "if", "print", "return",
+    "switch", "using", "var", "while",
+];
+// Keywords which are operands themselves.
+const OPERAND_KEYWORDS: &[&str] = &["false", "nil", "true"];
+// Keywords which join two operands.
+const INFIX_KEYWORDS: &[&str] = &["and", "or"];
+
+/// What may be typed at a cursor.
+pub struct Completions {
+    /// Where the partial word before the cursor starts. A match replaces it.
+    pub start: usize,
+    /// Keywords and names which can appear there and begin with the partial word, sorted.
+    pub matches: Vec<String>,
+}
+
+// What the code before the cursor leaves room for.
+#[derive(Clone, Copy, PartialEq)]
+enum Expecting {
+    Statement,
+    Operand,
+    // An operator, after a complete operand.
+    Infix,
+    Property,
+    // A new name, or punctuation.
+    Nothing,
+}
+
+// The kind of body a `{` opened.
+#[derive(Clone, Copy, PartialEq)]
+enum Block {
+    Class,
+    Switch,
+    Other,
+}
+
+// What a `(` opened.
+#[derive(Clone, Copy, PartialEq)]
+enum Paren {
+    // The clauses of `if`, `while`, `for`, `switch` or `using`. A statement follows the `)`.
+    Clauses,
+    Parameters,
+    Expression,
+}
+
+/// Complete the word before byte offset `cursor` in `source`, given the names of the globals
+/// already defined.
+///
+/// The code before the word is scanned to work out what can come next, so that e.g. only `and`
+/// and `or` are offered after an operand, and only names declared in an enclosing block are.
+pub fn complete(source: &str, cursor: usize, globals: &[&str]) -> Completions {
+    let before = &source[..cursor];
+    let start = before
+        .trim_end_matches(|c: char| c.is_ascii_alphanumeric() || c == '_')
+        .len();
+    let word = &before[start..];
+    if word.starts_with(|c: char| c.is_ascii_digit()) {
+        return Completions {
+            start,
+            matches: vec![],
+        };
+    }
+
+    let mut scanner = Scanner::new(&source[..start]);
+    let mut expecting = Expecting::Statement;
+    let mut previous: Option<Token> = None;
+    let mut blocks: Vec<Block> = vec![];
+    // The kind of body the next `{` opens.
+    let mut opening = Block::Other;
+    let mut parens: Vec<Paren> = vec![];
+    // Declared variables and the number of blocks they are visible in.
+    let mut names: Vec<(&str, usize)> = vec![];
+    let mut properties: Vec<&str> = vec![];
+    let mut in_parameters = false;
+    let mut in_case_label = false;
+    let mut seen_if = false;
+
+    loop {
+        let token = scanner.scan_token();
+        let previous_kind = previous.map(|previous| previous.kind);
+        let in_class_body = blocks.last() == Some(&Block::Class) && parens.is_empty();
+
+        expecting = match token.kind {
+            TokenKind::Eof => break,
+            // The cursor is inside a string or comment.
+            TokenKind::Error => {
+                return Completions {
+                    start,
+                    matches: vec![],
+                }
+            }
+            TokenKind::Identifier => {
+                let name = token.lexeme();
+                match previous_kind {
+                    Some(TokenKind::Var | TokenKind::Const | TokenKind::Class | TokenKind::Fun) => {
+                        names.push((name, blocks.len()));
+                        Expecting::Nothing
+                    }
+                    Some(TokenKind::Dot) => {
+                        properties.push(name);
+                        Expecting::Infix
+                    }
+                    _ if in_parameters => {
+                        names.push((name, blocks.len() + 1));
+                        Expecting::Nothing
+                    }
+                    // A method declaration.
+                    _ if in_class_body => {
+                        properties.push(name);
+                        Expecting::Nothing
+                    }
+                    _ => Expecting::Infix,
+                }
+            }
+            TokenKind::LeftParen => {
+                let declared =
+                    previous_kind == Some(TokenKind::Identifier) && expecting == Expecting::Nothing;
+                let paren = match previous_kind {
+                    _ if declared => Paren::Parameters,
+                    Some(
+                        TokenKind::If
+                        | TokenKind::While
+                        | TokenKind::For
+                        | TokenKind::Switch
+                        | TokenKind::Using,
+                    ) => Paren::Clauses,
+                    _ => Paren::Expression,
+                };
+                in_parameters = paren == Paren::Parameters;
+                parens.push(paren);
+                if in_parameters {
+                    Expecting::Nothing
+                } else {
+                    Expecting::Operand
+                }
+            }
+            TokenKind::RightParen => {
+                in_parameters = false;
+                match parens.pop() {
+                    Some(Paren::Clauses) => Expecting::Statement,
+                    Some(Paren::Parameters) => Expecting::Nothing,
+                    _ => Expecting::Infix,
+                }
+            }
+            TokenKind::LeftBrace => {
+                blocks.push(mem::replace(&mut opening, Block::Other));
+                Expecting::Statement
+            }
+            TokenKind::RightBrace => {
+                blocks.pop();
+                names.retain(|&(_, depth)| depth <= blocks.len());
+                Expecting::Statement
+            }
+            // Between the clauses of a `for`, an expression follows.
+            TokenKind::Semicolon if parens.is_empty() => Expecting::Statement,
+            TokenKind::Colon if in_case_label => {
+                in_case_label = false;
+                Expecting::Statement
+            }
+            TokenKind::Class => {
+                opening = Block::Class;
+                Expecting::Nothing
+            }
+            TokenKind::Switch => {
+                opening = Block::Switch;
+                Expecting::Nothing
+            }
+            TokenKind::If => {
+                seen_if = true;
+                Expecting::Nothing
+            }
+            TokenKind::Case => {
+                in_case_label = true;
+                Expecting::Operand
+            }
+            TokenKind::Default => {
+                in_case_label = true;
+                Expecting::Nothing
+            }
+            TokenKind::Dot | TokenKind::QuestionDot => Expecting::Property,
+            TokenKind::Else => Expecting::Statement,
+            TokenKind::Number
+            | TokenKind::Decimal
+            | TokenKind::RightBracket
+            | TokenKind::String
+            | TokenKind::True
+            | TokenKind::False
+            | TokenKind::Nil
+            | TokenKind::This => Expecting::Infix,
+            TokenKind::Var
+            | TokenKind::Const
+            | TokenKind::Fun
+            | TokenKind::Super
+            | TokenKind::While
+            | TokenKind::For
+            | TokenKind::Using
+            | TokenKind::Break
+            | TokenKind::Continue => Expecting::Nothing,
+            // Operators, `print`, `return` and the like are followed by an operand.
+            _ => Expecting::Operand,
+        };
+        previous = Some(token);
+    }
+
+    // Directly after `:`, the word is a symbol's name.
+    if let Some(colon) = previous.filter(|token| token.kind == TokenKind::Colon) {
+        if colon.start + colon.length == start {
+            expecting = Expecting::Nothing;
+        }
+    }
+
+    let mut operands: Vec<&str> = vec![];
+    operands.extend(OPERAND_KEYWORDS);
+    operands.extend(names.iter().map(|&(name, _)| name));
+    operands.extend(globals);
+    if blocks.contains(&Block::Class) {
+        operands.extend(["this", "super"]);
+    }
+
+    let mut candidates: Vec<&str> = vec![];
+    match expecting {
+        // The only statements in a class body are method declarations, which need a new name.
+        Expecting::Statement if blocks.last() == Some(&Block::Class) => {}
+        Expecting::Statement => {
+            candidates.extend(STATEMENT_KEYWORDS);
+            candidates.extend(operands);
+            if blocks.last() == Some(&Block::Switch) {
+                candidates.extend(["case", "default"]);
+            }
+            let after_statement = previous.is_some_and(|token| {
+                matches!(token.kind, TokenKind::Semicolon | TokenKind::RightBrace)
+            });
+            if seen_if && after_statement {
+                candidates.push("else");
+            }
+        }
+        Expecting::Operand => candidates.extend(operands),
+        Expecting::Infix => candidates.extend(INFIX_KEYWORDS),
+        Expecting::Property => candidates.extend(properties),
+        Expecting::Nothing => {}
+    }
+
+    let mut matches: Vec<String> = candidates
+        .into_iter()
+        .filter(|candidate| candidate.starts_with(word))
+        .map(str::to_string)
+        .collect();
+    matches.sort();
+    matches.dedup();
+    Completions { start, matches }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Testing scanner.
+
+    #[test]
+    fn test_scanner_basic() {
+        let source = "(";
+        let mut scanner = Scanner::new(source);
+
+        let token = scanner.scan_token();
+
+        assert!(token.kind == TokenKind::LeftParen);
+    }
+
+    #[test]
+    fn test_scanner_multibyte_string() {
+        let mut scanner = Scanner::new("\"héllo\" 1");
+
+        let token = scanner.scan_token();
+        assert!(token.kind == TokenKind::String);
+        assert_eq!(token.lexeme(), "\"héllo\"");
+        assert!(scanner.scan_token().kind == TokenKind::Number);
+    }
+
+    #[test]
+    fn test_scanner() {
+        // `/*` would open a comment.
+        let source = "({;,.-+*/})";
+        let mut scanner = Scanner::new(source);
+
+        let mut idx = 0;
+        let expected = [
+            TokenKind::LeftParen,
+            TokenKind::LeftBrace,
+            TokenKind::Semicolon,
+            TokenKind::Comma,
+            TokenKind::Dot,
+            TokenKind::Minus,
+            TokenKind::Plus,
+            TokenKind::Star,
+            TokenKind::Slash,
+            TokenKind::RightBrace,
+            TokenKind::RightParen,
+        ];
+        while !scanner.is_at_end() {
+            let token = scanner.scan_token();
+
+            assert!(token.kind == expected[idx]);
+
+            idx += 1;
+        }
+    }
+
+    #[test]
+    fn test_scanner_interpolation() {
+        let mut scanner = Scanner::new("\"a ${ {} } b ${x}\"");
+
+        let tokens: Vec<(TokenKind, &str)> = std::iter::from_fn(|| {
+            let token = scanner.scan_token();
+            (token.kind != TokenKind::Eof).then_some((token.kind, token.lexeme()))
+        })
+        .collect();
+
+        // Braces within an interpolation are ordinary tokens until the one that closes it.
+        assert!(
+            tokens
+                == [
+                    (TokenKind::Interpolation, "\"a ${"),
+                    (TokenKind::LeftBrace, "{"),
+                    (TokenKind::RightBrace, "}"),
+                    (TokenKind::Interpolation, "} b ${"),
+                    (TokenKind::Identifier, "x"),
+                    (TokenKind::String, "}\""),
+                ]
+        );
+    }
+
+    #[test]
+    fn test_scanner_columns() {
+        let mut scanner = Scanner::new("print 1;\n  \"a\nb\" x");
+
+        let columns: Vec<(usize, usize)> = std::iter::from_fn(|| {
+            let token = scanner.scan_token();
+            (token.kind != TokenKind::Eof).then_some((token.line, token.column))
+        })
+        .collect();
+
+        // Multi-line tokens are reported where they start.
+        assert_eq!(columns, [(1, 1), (1, 7), (1, 8), (2, 3), (3, 4)]);
+    }
+
+    #[test]
+    fn test_scanner_block_comments() {
+        let mut scanner = Scanner::new("1 /* a /* nested\n */ still\n comment */ 2 /**/ 3");
+
+        let tokens: Vec<(&str, usize)> = std::iter::from_fn(|| {
+            let token = scanner.scan_token();
+            (token.kind != TokenKind::Eof).then_some((token.lexeme(), token.line))
+        })
+        .collect();
+
+        assert_eq!(tokens, [("1", 1), ("2", 3), ("3", 3)]);
+        assert_eq!(evaluate("1 + /* two */ 2"), "3");
+    }
+
+    #[test]
+    fn test_scanner_unterminated_comment() {
+        let mut scanner = Scanner::new("1\n  /* a /* b */\n");
+        assert!(scanner.scan_token().kind == TokenKind::Number);
+
+        let token = scanner.scan_token();
+        assert!(token.kind == TokenKind::Error);
+        assert_eq!(token.lexeme(), "Unterminated comment.");
+        assert_eq!((token.line, token.column), (2, 3));
+        assert!(scanner.scan_token().kind == TokenKind::Eof);
+    }
+
+    #[test]
+    fn test_scanner_error_token_lexeme() {
+        let mut scanner = Scanner::new("  @");
+        let token = scanner.scan_token();
+
+        assert!(token.kind == TokenKind::Error);
+        assert_eq!(token.lexeme(), "Unexpected character.");
+        assert_eq!(token.column, 3);
+    }
+
+    // Testing the source map.
+
+    #[test]
+    fn test_source_map() {
+        let chunk = Compiler::new("print 1 +\n  2;").compile().unwrap();
+
+        // Instructions are attributed to the last token consumed before they were emitted.
+        assert_eq!(
+            chunk.location(0),
+            SourceLocation {
+                file: 0,
+                line: 1,
+                column: 7
+            }
+        );
+        assert_eq!(
+            chunk.location(1),
+            SourceLocation {
+                file: 0,
+                line: 1,
+                column: 7
+            }
+        );
+        assert_eq!(
+            chunk.location(2),
+            SourceLocation {
+                file: 0,
+                line: 2,
+                column: 3
+            }
+        );
+        assert_eq!(
+            chunk.location(4),
+            SourceLocation {
+                file: 0,
+                line: 2,
+                column: 3
+            }
+        );
+        assert_eq!(
+            chunk.location(5),
+            SourceLocation {
+                file: 0,
+                line: 2,
+                column: 4
+            }
+        );
+
+        // Consecutive bytes from the same token share one entry.
+        assert!(chunk.source_map.runs.len() < chunk.code.len());
+    }
+
+    // Testing captured runs.
+
+    #[test]
+    fn test_run_source() {
+        let run = run_source("print 1; print input(\"? \");");
+        assert_eq!(run.stdout, "1\n? nil\n");
+        assert_eq!(run.stderr, "");
+        assert_eq!(run.result, InterpretResult::Ok);
+
+        let run = run_source("print 1;\nprint -nil;");
+        assert_eq!(run.stdout, "1\n");
+        assert_eq!(
+            run.stderr,
+            "Operand must be a number.\n[line 2] in script\n"
+        );
+        assert_eq!(run.result, InterpretResult::RuntimeError);
+
+        let run = run_source("print 1");
+        assert_eq!(run.stdout, "");
+        assert_eq!(
+            run.stderr,
+            "[line 1] Error at end: Expect ';' after value.\n"
+        );
+        assert_eq!(run.result, InterpretResult::CompileError);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let dir = std::env::temp_dir().join(format!("rustlox-snapshots-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.lox"), "print 1;").unwrap();
+        std::fs::write(dir.join("a.stderr"), "stale").unwrap();
+
+        // Blessing records the output and drops snapshots of nothing.
+        assert!(check_snapshots(&dir, true).unwrap().is_empty());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("a.stdout")).unwrap(),
+            "1\n"
+        );
+        assert!(!dir.join("a.stderr").exists());
+
+        std::fs::write(dir.join("a.lox"), "print 2;").unwrap();
+        let mismatches = check_snapshots(&dir, false).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mismatches.len(), 1);
+        assert!(
+            mismatches[0].ends_with("--- expected\n1\n--- actual\n2\n"),
+            "{}",
+            mismatches[0]
+        );
+    }
+
+    // Testing the source registry.
+
+    #[test]
+    fn test_source_registry() {
+        let mut sources = SourceRegistry::default();
+
+        let a = sources.register("a.lox");
+        let b = sources.register("lib/b.lox");
+
+        assert_eq!(sources.register("a.lox"), a);
+        assert_ne!(a, b);
+        assert_eq!(sources.name(b), Some("lib/b.lox"));
+        assert_eq!(sources.name(0), None);
+
+        let location = SourceLocation {
+            file: b,
+            line: 3,
+            column: 7,
+        };
+        assert_eq!(sources.describe(location), "lib/b.lox:3:7");
+        assert_eq!(sources.describe(SourceLocation::line(3)), "line 3");
+    }
+
+    #[test]
+    fn test_chunks_record_their_file() {
+        let mut sources = SourceRegistry::default();
+        let file = sources.register("main.lox");
+
+        let chunk = Compiler::for_file("print 1;", file, &sources)
+            .compile()
+            .unwrap();
+        assert_eq!(chunk.location(0).file, file);
+    }
+
+    // Testing symbols.
+
+    #[test]
+    fn test_symbol_interning() {
+        let a = Symbol::intern("red");
+        let b = Symbol::intern("red");
+        let c = Symbol::intern("green");
+
+        assert!(a == b);
+        assert!(a != c);
+        assert!(Value::Symbol(a).is_equal(&Value::Symbol(b)));
+    }
+
+    #[test]
+    fn test_symbol_literal() {
+        let mut compiler = Compiler::new(":red");
+        let chunk = compiler.compile_expression().unwrap();
+
+        assert!(chunk.constants[0].is_equal(&Value::Symbol(Symbol::intern("red"))));
+    }
+
+    #[test]
+    fn test_symbol_literal_requires_adjacent_name() {
+        let mut compiler = Compiler::new(": red");
+
+        assert!(compiler.compile_expression().is_none());
+    }
+
+    // Testing strings.
+
+    #[test]
+    fn test_string_hash() {
+        // Reference values for 32-bit FNV-1a.
+        assert_eq!(fnv1a(b""), 0x811c9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c292c);
+        assert_eq!(fnv1a(b"foobar"), 0xbf9cf968);
+
+        let a = LoxString::new("key");
+        let b = LoxString::new("key");
+        assert_eq!(a.hash, b.hash);
+        assert!(a == b);
+        assert!(a != LoxString::new("other"));
+
+        // Tables find strings by content, whichever allocation the key came from.
+        let mut table: StringTable<u8> = StringTable::default();
+        table.insert(Rc::new(a), 1);
+        assert_eq!(table.get(&Rc::new(b)), Some(&1));
+        assert_eq!(table.get(&Rc::new(LoxString::new("kez"))), None);
+    }
+
+    #[test]
+    fn test_inline_strings() {
+        assert!(matches!(string_value(""), Value::InlineStr(_)));
+        assert!(matches!(
+            string_value(&"a".repeat(INLINE_STR_MAX)),
+            Value::InlineStr(_)
+        ));
+        assert!(matches!(
+            string_value(&"a".repeat(INLINE_STR_MAX + 1)),
+            Value::String(_)
+        ));
+        #[cfg(not(feature = "decimal"))]
+        assert_eq!(
+            mem::size_of::<Value>(),
+            mem::size_of::<Rc<str>>() + mem::size_of::<usize>()
+        );
+
+        // Both kinds of string behave the same, whichever the operands are.
+        let mut vm = VM::new(Chunk::new());
+        let long = "a".repeat(INLINE_STR_MAX);
+        let source = format!("var short = \"héllo\"; var long = \"{}\" + \"b\";", long);
+        assert!(matches!(vm.interpret(&source), InterpretResult::Ok));
+        assert_eq!(
+            vm.execute("short + \"!\"").ok().unwrap().to_string(),
+            "héllo!"
+        );
+        assert_eq!(
+            vm.execute("long").ok().unwrap().to_string(),
+            format!("{}b", long)
+        );
+        let equal = format!("long == \"{}\" + \"b\" and \"a\" + \"b\" == \"ab\"", long);
+        assert_eq!(vm.execute(&equal).ok().unwrap().to_string(), "true");
+        assert_eq!(
+            vm.execute("short == long").ok().unwrap().to_string(),
+            "false"
+        );
+    }
+
+    #[test]
+    fn test_string_interpolation() {
+        assert_eq!(evaluate("\"a ${1 + 2} b\""), "a 3 b");
+        assert_eq!(evaluate("\"${nil}${true} ${:red}\""), "niltrue :red");
+        assert_eq!(evaluate("\"x = ${\"in ${1} ner\"}!\""), "x = in 1 ner!");
+        // A `$` not followed by `{` is kept as is.
+        assert_eq!(evaluate("\"$5 and ${\"s\"}\""), "$5 and s");
+
+        let mut vm = VM::new(Chunk::new());
+        vm.interpret("class Pair {} var p = Pair(); var n = 2;");
+        assert_eq!(
+            vm.execute("\"${p}, ${n * 2}\"").ok().unwrap().to_string(),
+            "Pair instance, 4"
+        );
+
+        assert!(Compiler::new("print \"${1\";").compile().is_none());
+        assert!(Compiler::new("print \"${}\";").compile().is_none());
+        assert!(Compiler::new("print \"${1 \"x\"}\";").compile().is_none());
+    }
+
+    // Testing value printing.
+
+    #[test]
+    fn test_number_formatting() {
+        assert_eq!(Value::Number(3.0).to_string(), "3");
+        assert_eq!(Value::Number(-0.0).to_string(), "-0");
+        assert_eq!(Value::Number(2.5).to_string(), "2.5");
+        assert_eq!(Value::Number(0.1 + 0.2).to_string(), "0.30000000000000004");
+        assert_eq!(Value::Number(1e21).to_string(), "1e21");
+        assert_eq!(Value::Number(1e-7).to_string(), "1e-7");
+        assert_eq!(Value::Number(f64::INFINITY).to_string(), "inf");
+        assert_eq!(Value::Number(f64::NAN).to_string(), "nan");
+    }
+
+    #[test]
+    fn test_value_display() {
+        assert_eq!(Value::Nil.to_string(), "nil");
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(string_value("hi").to_string(), "hi");
+        assert_eq!(Value::Symbol(Symbol::intern("red")).to_string(), ":red");
+    }
+
+    // Testing statements.
+
+    #[test]
+    fn test_print_statement() {
+        let chunk = Compiler::new("print 1 + 2;").compile().unwrap();
+
+        assert!(chunk.code.contains(&(Opcode::Print as u8)));
+        assert!(Compiler::new("print 1").compile().is_none());
+    }
+
+    #[test]
+    fn test_expression_statements_are_popped() {
+        let mut vm = VM::new(Chunk::new());
+
+        assert!(matches!(
+            vm.interpret("1 + 2; \"a\"; nil ?? 3;"),
+            InterpretResult::Ok
+        ));
+        assert!(vm.stack.is_empty());
+        assert!(Compiler::new("1 + 2").compile().is_none());
+    }
+
+    #[test]
+    fn test_print_leaves_stack_balanced() {
+        let mut vm = VM::new(Chunk::new());
+
+        assert!(matches!(
+            vm.interpret("print 1; print \"two\";"),
+            InterpretResult::Ok
+        ));
+        assert!(vm.stack.is_empty());
+    }
+
+    // Testing global variables.
+
+    #[test]
+    fn test_globals() {
+        let mut vm = VM::new(Chunk::new());
+
+        assert!(matches!(
+            vm.interpret("var a = 1; var b; a = a + 2; var c = a = 10;"),
+            InterpretResult::Ok
+        ));
+        assert_eq!(vm.execute("a").ok().unwrap().to_string(), "10");
+        assert_eq!(vm.execute("b").ok().unwrap().to_string(), "nil");
+        assert_eq!(vm.execute("c").ok().unwrap().to_string(), "10");
+
+        // Globals survive between runs, like REPL lines.
+        assert!(matches!(vm.interpret("a = a * 2;"), InterpretResult::Ok));
+        assert_eq!(vm.execute("a").ok().unwrap().to_string(), "20");
+    }
+
+    #[test]
+    fn test_undefined_globals() {
+        let mut vm = VM::new(Chunk::new());
+
+        assert!(matches!(
+            vm.interpret("print d;"),
+            InterpretResult::RuntimeError
+        ));
+        assert!(matches!(
+            vm.interpret("d = 1;"),
+            InterpretResult::RuntimeError
+        ));
+        assert!(!vm.globals.contains_key(&Rc::new(LoxString::new("d"))));
+    }
+
+    #[test]
+    fn test_invalid_assignment_target() {
+        assert!(Compiler::new("var a; var b; a * b = 1;")
+            .compile()
+            .is_none());
+        assert!(Compiler::new("1 = 2;").compile().is_none());
+        assert!(Compiler::new("var a; (a) = 2;").compile().is_none());
+        assert!(Compiler::new("var a; var b; a = b = 2;")
+            .compile()
+            .is_some());
+    }
+
+    #[test]
+    fn test_while() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "var i = 0; var sum = 0;
+            while (i < 5) sum = sum + (i = i + 1);
+            while (false) sum = nil;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("sum").ok().unwrap().to_string(), "15");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_break() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "var total = 0;
+            for (var i = 0; ; i = i + 1) {
+                var j = 0;
+                while (true) { var k = j; if (k == i) break; j = j + 1; }
+                total = total + j;
+                if (i == 4) { var done = true; break; }
+            }
+            var outer = 0;
+            while (outer < 3) { outer = outer + 1; { var x = 1; { var y = 2; break; } } }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("total").ok().unwrap().to_string(), "10");
+        assert_eq!(vm.execute("outer").ok().unwrap().to_string(), "1");
+        // Breaking out of nested scopes pops their locals.
+        assert!(vm.stack.is_empty());
+
+        let in_function = "fun f() {
+                for (var i = 0; i < 10; i = i + 1) { if (i == 3) break; }
+                return 7;
+            }";
+        assert!(matches!(vm.interpret(in_function), InterpretResult::Ok));
+        assert_eq!(vm.execute("f()").ok().unwrap().to_string(), "7");
+    }
+
+    #[test]
+    fn test_break_errors() {
+        assert!(Compiler::new("break;").compile().is_none());
+        assert!(Compiler::new("if (true) break;").compile().is_none());
+        assert!(Compiler::new("while (true) break").compile().is_none());
+        // A function body is not inside the loop it is declared in.
+        assert!(Compiler::new("while (true) { fun f() { break; } }")
+            .compile()
+            .is_none());
+    }
+
+    #[test]
+    fn test_continue() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "var odd = 0; var even = false;
+            for (var i = 0; i < 10; i = i + 1) { even = !even; if (even) continue; odd = odd + i; }
+            var i = 0; var skipped = 0;
+            while (i < 5) { i = i + 1; { var x = i; if (x < 3) { skipped = skipped + 1; continue; } } }
+            for (var j = 0; j < 3; j = j + 1) { if (j == 1) continue; if (j == 2) break; }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        // `for` loops run their increment before the condition.
+        assert_eq!(vm.execute("odd").ok().unwrap().to_string(), "25");
+        assert_eq!(vm.execute("skipped").ok().unwrap().to_string(), "2");
+        assert!(vm.stack.is_empty());
+
+        assert!(Compiler::new("continue;").compile().is_none());
+        assert!(Compiler::new("while (true) { fun f() { continue; } }")
+            .compile()
+            .is_none());
+        assert!(Compiler::new("for (;;) continue").compile().is_none());
+    }
+
+    #[test]
+    fn test_switch() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "fun name(n) {
+                switch (n) {
+                    case 1: return \"one\";
+                    case 1 + 1: var word = \"two\"; return word;
+                    case \"3\": return \"string\";
+                    default: return \"many\";
+                }
+            }
+            var seen = 0;
+            for (var i = 0; i < 5; i = i + 1) {
+                switch (i) { case 2: continue; case 4: break; }
+                seen = seen + 1;
+            }
+            var empty = 0; switch (empty) {} switch (1) { case 2: empty = 1; }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("name(1)").ok().unwrap().to_string(), "one");
+        assert_eq!(vm.execute("name(2)").ok().unwrap().to_string(), "two");
+        assert_eq!(
+            vm.execute("name(\"3\")").ok().unwrap().to_string(),
+            "string"
+        );
+        assert_eq!(vm.execute("name(3)").ok().unwrap().to_string(), "many");
+        // `break` and `continue` belong to the enclosing loop.
+        assert_eq!(vm.execute("seen").ok().unwrap().to_string(), "3");
+        assert_eq!(vm.execute("empty").ok().unwrap().to_string(), "0");
+        assert!(vm.stack.is_empty());
+
+        assert!(Compiler::new("switch (1) { default: case 1: }")
+            .compile()
+            .is_none());
+        assert!(Compiler::new("switch (1) { case 1 }").compile().is_none());
+        assert!(Compiler::new("switch 1 { }").compile().is_none());
+        assert!(Compiler::new("switch (1) { print 1; }").compile().is_none());
+    }
+
+    #[test]
+    fn test_defer() {
+        let mut vm = VM::new(Chunk::new());
+        vm.capture_output();
+
+        let source = "fun log(s) { print s; }
+            {
+                var x = 1;
+                defer log(\"x is ${x}\");
+                defer log(\"second\");
+                x = 2;
+                log(\"body\");
+            }
+            fun f() { defer log(\"leaving f\"); { defer log(\"inner\"); return 1; } }
+            log(f());
+            for (var i = 0; i < 3; i = i + 1) {
+                defer log(i);
+                if (i == 0) continue;
+                if (i == 1) break;
+            }
+            defer log(\"end\");
+            log(\"last\");";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        // Most recent first, and evaluated on the way out rather than where they were deferred.
+        assert_eq!(
+            vm.take_output(),
+            "body\nsecond\nx is 2\ninner\nleaving f\n1\n0\n1\nlast\nend\n"
+        );
+        assert!(vm.stack.is_empty());
+
+        // Only locals declared before the `defer` are visible, so `y` is looked up as a global.
+        let source = "{ defer y; var y = 1; }";
+        assert!(matches!(
+            vm.interpret(source),
+            InterpretResult::RuntimeError
+        ));
+
+        assert!(Compiler::new("{ defer print 1; }").compile().is_none());
+        assert!(Compiler::new("{ defer 1 2; }").compile().is_none());
+        assert!(Compiler::new("defer 1").compile().is_none());
+    }
+
+    #[test]
+    fn test_using() {
+        let mut vm = VM::new(Chunk::new());
+        vm.capture_output();
+
+        let source = "class File {
+                init(name) { this.name = name; }
+                close() { print \"closed \" + this.name; }
+            }
+            fun read() {
+                using (var f = File(\"a\")) {
+                    using (var g = File(\"b\")) print \"reading\";
+                    return f.name;
+                }
+            }
+            print read();
+            while (true) using (var f = File(\"c\")) break;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(
+            vm.take_output(),
+            "reading\nclosed b\nclosed a\na\nclosed c\n"
+        );
+        assert!(vm.stack.is_empty());
+
+        // The resource must have a `close` method.
+        assert!(matches!(
+            vm.interpret("using (var n = 1) {}"),
+            InterpretResult::RuntimeError
+        ));
+
+        assert!(Compiler::new("using (f = 1) {}").compile().is_none());
+        assert!(Compiler::new("using (var f) {}").compile().is_none());
+        assert!(Compiler::new("using var f = 1 {}").compile().is_none());
+    }
+
+    #[test]
+    fn test_loop_body_too_large() {
+        // Each `== true` is two bytes of code.
+        let body = "true".to_string() + &" == true".repeat(u16::MAX as usize / 2);
+        let source = format!("while (x) {};", body);
+
+        assert!(Compiler::new(&source).compile().is_none());
+    }
+
+    #[test]
+    fn test_disassembly_loop_target() {
+        let chunk = Compiler::new("while (x) 1;").compile().unwrap();
+        let listing = Disassembler::new(&chunk, Palette::PLAIN).chunk("test");
+
+        assert!(listing.contains("OP_LOOP") && listing.contains("-> 0000"));
+    }
+
+    #[test]
+    fn test_for() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "var sum = 0;
+            for (var i = 1; i <= 4; i = i + 1) sum = sum + i;
+            var j = 0;
+            for (j = 10; j < 12;) j = j + 1;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("sum").ok().unwrap().to_string(), "10");
+        assert_eq!(vm.execute("j").ok().unwrap().to_string(), "12");
+        assert!(vm.stack.is_empty());
+
+        assert!(Compiler::new("for (;;) 1;").compile().is_some());
+        assert!(Compiler::new("for (var i = 0; i < 1) 1;")
+            .compile()
+            .is_none());
+    }
+
+    #[test]
+    fn test_for_initializer_scope() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "for (var k = 0; k < 1; k = k + 1) {} print k;";
+        assert!(matches!(
+            vm.interpret(source),
+            InterpretResult::RuntimeError
+        ));
+        assert!(!vm.globals.contains_key(&Rc::new(LoxString::new("k"))));
+    }
+
+    // Testing constants.
+
+    #[test]
+    fn test_const() {
+        let run = run_source("const n = 3; { const half = n / 2; print half; } print n;");
+        assert_eq!(run.stdout, "1.5\n3\n");
+        assert_eq!(run.result, InterpretResult::Ok);
+
+        let run = run_source("const limit = 3;\nlimit = 4;");
+        assert_eq!(
+            run.stderr,
+            "Can't assign to constant 'limit'.\n[line 2] in script\n"
+        );
+
+        // Redeclaring a global with `var` makes it assignable again.
+        let run = run_source("const a = 1; var a = 2; a = 3; print a;");
+        assert_eq!(run.stdout, "3\n");
+
+        let run = run_source("{ const a = 1;\n  a = 2; }");
+        assert_eq!(
+            run.stderr,
+            "[line 2] Error at a: Can't assign to constant 'a'.\n"
+        );
+        assert_eq!(run.result, InterpretResult::CompileError);
+
+        assert!(Compiler::new("{ const a = 1; { var a = 2; a = 3; } }")
+            .compile()
+            .is_some());
+        assert!(Compiler::new("const a;").compile().is_none());
+        assert!(Compiler::new("fun f() { const a = 1; a = 2; }")
+            .compile()
+            .is_none());
+    }
+
+    // Testing local variables.
+
+    #[test]
+    fn test_locals() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "var a = \"global\"; var b;
+            {
+                var a = 1;
+                { var a = 10; b = a; }
+                a = a + 1;
+                b = a;
+            }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("a").ok().unwrap().to_string(), "global");
+        assert_eq!(vm.execute("b").ok().unwrap().to_string(), "2");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_local_errors() {
+        assert!(Compiler::new("{ var a; var a; }").compile().is_none());
+        assert!(Compiler::new("{ var a = 1; { var a = a; } }")
+            .compile()
+            .is_none());
+        assert!(Compiler::new("{ var a; { var a; } }").compile().is_some());
+        assert!(Compiler::new("{ var a;").compile().is_none());
+
+        let too_many = "{".to_string() + &"var a; {".repeat(UINT8_COUNT + 1);
+        assert!(Compiler::new(&too_many).compile().is_none());
+    }
+
+    #[test]
+    fn test_and_or() {
+        assert_eq!(evaluate("1 and 2"), "2");
+        assert_eq!(evaluate("nil and 2"), "nil");
+        assert_eq!(evaluate("false or \"b\""), "b");
+        assert_eq!(evaluate("0 or 2"), "0");
+        assert_eq!(evaluate("false and 1 or 3"), "3");
+        assert_eq!(evaluate("true or 1 and false"), "true");
+    }
+
+    #[test]
+    fn test_and_or_short_circuit() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "var a = 0; false and (a = 1); true or (a = 2); true and (a = a + 10);";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("a").ok().unwrap().to_string(), "10");
+        assert!(vm.stack.is_empty());
+    }
+
+    // Testing the disassembler.
+
+    #[test]
+    fn test_disassembly_marks_jump_targets() {
+        let chunk = Compiler::new("if (x) print 1;").compile().unwrap();
+        let listing = Disassembler::new(&chunk, Palette::PLAIN).chunk("test");
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "== test ==");
+        assert!(lines[2].contains("OP_JUMP_IF_FALSE") && lines[2].ends_with("-> 0012"));
+        assert!(lines.iter().any(|line| line.starts_with(">0012")));
+        assert!(!listing.contains('\x1b'));
+    }
+
+    #[test]
+    fn test_disassembly_constants() {
+        let source = "var s = \"1\"; var n = 1.5; print :red; print \"a\\b\"; fun f() {}";
+        let chunk = Compiler::new(source).compile().unwrap();
+        let listing = Disassembler::new(&chunk, Palette::PLAIN).chunk("test");
+
+        assert!(listing.contains("OP_CONSTANT         1 \"1\"\n"));
+        assert!(listing.contains("OP_DEFINE_GLOBAL    0 \"s\"\n"));
+        assert!(listing.contains("OP_CONSTANT         3 1.5\n"));
+        assert!(listing.contains("OP_CONSTANT         4 :red\n"));
+        assert!(listing.contains("OP_CONSTANT         5 \"a\\\\b\"\n"));
+        assert!(listing.contains("OP_CONSTANT         7 <fn f>\n"));
+    }
+
+    #[test]
+    fn test_disassembly_colors() {
+        let chunk = Compiler::new("print 1.5;").compile().unwrap();
+        let listing = Disassembler::new(&chunk, Palette::ANSI).chunk("test");
+
+        assert!(listing.contains("\x1b[1;34mOP_CONSTANT"));
+        assert!(listing.contains("\x1b[32m1.5\x1b[0m"));
+    }
+
+    // Testing control-flow analysis.
+
+    #[test]
+    fn test_basic_blocks() {
+        let chunk = Compiler::new("if (x) print 1; else print 2;")
+            .compile()
+            .unwrap();
+        let blocks = chunk.basic_blocks();
+
+        let starts: Vec<usize> = blocks.iter().map(|block| block.start).collect();
+        let successors: Vec<&[usize]> = blocks.iter().map(|b| b.successors.as_slice()).collect();
+        // condition, then branch, else branch, the implicit return.
+        assert_eq!(starts, [0, 5, 12, 16]);
+        assert_eq!(successors, [&[5, 12][..], &[16], &[16], &[]]);
+        assert_eq!(blocks.last().unwrap().end, chunk.code.len());
+    }
+
+    #[test]
+    fn test_check_stack_depth() {
+        let chunk = Compiler::new("var a = [1, 2]; print a[0] ?? 3;")
+            .compile()
+            .unwrap();
+        // The script, then the list's two elements.
+        assert_eq!(chunk.check_stack_depth(1), Ok(3));
+
+        let chunk = ChunkBuilder::new()
+            .op(Opcode::Add)
+            .op(Opcode::Return)
+            .build();
+        assert_eq!(
+            chunk.check_stack_depth(1),
+            Err("0000: OP_ADD pops 2 values but the stack holds 1".to_string())
+        );
+
+        // The branch which is taken skips pushing nil, so the paths meet at different depths.
+        let chunk = ChunkBuilder::new()
+            .op(Opcode::True)
+            .jump(Opcode::JumpIfFalse, "end")
+            .op(Opcode::Nil)
+            .label("end")
+            .op(Opcode::Return)
+            .build();
+        assert_eq!(
+            chunk.check_stack_depth(1),
+            Err("0005: the stack holds 3 values on one path and 2 on another".to_string())
+        );
+    }
+
+    #[test]
+    fn test_loop_blocks() {
+        let chunk = Compiler::new("while (x) 1;").compile().unwrap();
+        let blocks = chunk.basic_blocks();
+
+        // The body loops back to the condition.
+        assert_eq!(blocks[1].successors, [0]);
+    }
+
+    #[test]
+    fn test_graphs_to_dot() {
+        let chunk = Compiler::new("print \"a\" ?? 1;").compile().unwrap();
+        let dot = graphs_to_dot("main.lox", &chunk);
+
+        assert!(dot.starts_with("digraph \"main.lox\" {"));
+        assert!(dot.contains("\"f0_0\" -> \"f0_5\";"));
+        assert!(dot.contains("OP_CONSTANT         0 \\\"a\\\"\\l"));
+        assert!(dot.contains("digraph calls {"));
+    }
+
+    #[test]
+    fn test_call_graph() {
+        let source = "fun a() { b(); } fun b() { fun c() {} c(); } a();";
+        let chunk = Compiler::new(source).compile().unwrap();
+        let dot = graphs_to_dot("main.lox", &chunk);
+
+        let names: Vec<String> = program_functions(&chunk)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, ["script", "a", "b", "c"]);
+
+        // Each function gets its own control-flow graph.
+        assert!(dot.contains("subgraph \"cluster_f3\""));
+        assert!(dot.contains("    f0 -> f1;\n"));
+        assert!(dot.contains("    f1 -> f2;\n"));
+        // `c` is a local of `b`, so the approximation cannot see that call.
+        assert!(!dot.contains("f2 -> f3"));
+    }
+
+    // Testing functions.
+
+    #[test]
+    fn test_function_calls() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "var result;
+            fun add(a, b) { var sum = a + b; result = sum; }
+            fun twice(f, x) { f(x, x); }
+            twice(add, 21);";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("result").ok().unwrap().to_string(), "42");
+        assert_eq!(vm.execute("add").ok().unwrap().to_string(), "<fn add>");
+        // Without a return statement, calls evaluate to nil.
+        assert_eq!(vm.execute("add(1, 2)").ok().unwrap().to_string(), "nil");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_return() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); }
+            fun early() { for (var i = 0; i < 10; i = i + 1) { var x = i; if (x == 3) return x; } }
+            fun bare() { return; 1; }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("fib(10)").ok().unwrap().to_string(), "55");
+        // Returning from inside loops and blocks discards their locals.
+        assert_eq!(vm.execute("early()").ok().unwrap().to_string(), "3");
+        assert_eq!(vm.execute("bare()").ok().unwrap().to_string(), "nil");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_return_errors() {
+        assert!(Compiler::new("return 1;").compile().is_none());
+        assert!(Compiler::new("return;").compile().is_none());
+        assert!(Compiler::new("{ return; }").compile().is_none());
+        assert!(Compiler::new("fun f() { return 1 }").compile().is_none());
+        assert!(Compiler::new("fun f() { return 1; }").compile().is_some());
+    }
+
+    #[test]
+    fn test_local_functions_and_recursion() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "var count = 0;
+            fun countdown(n) {
+                if (n > 0) { count = count + 1; countdown(n - 1); }
+            }
+            {
+                fun start() { countdown(5); }
+                start();
+            }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("count").ok().unwrap().to_string(), "5");
+        assert!(vm.execute("start").is_err());
+    }
+
+    #[test]
+    fn test_call_errors() {
+        let mut vm = VM::new(Chunk::new());
+
+        assert!(matches!(
+            vm.interpret("fun f(a) {} f();"),
+            InterpretResult::RuntimeError
+        ));
+        assert!(matches!(
+            vm.interpret("fun f(a) {} f(1, 2);"),
+            InterpretResult::RuntimeError
+        ));
+        assert!(matches!(
+            vm.interpret("\"f\"();"),
+            InterpretResult::RuntimeError
+        ));
+        assert!(matches!(
+            vm.interpret("nil();"),
+            InterpretResult::RuntimeError
+        ));
+
+        // The VM is usable again after an error inside a call.
+        assert!(matches!(
+            vm.interpret("fun g() { -nil; } g();"),
+            InterpretResult::RuntimeError
+        ));
+        assert_eq!(vm.execute("1 + 1").ok().unwrap().to_string(), "2");
+    }
+
+    #[test]
+    fn test_natives() {
+        let mut vm = VM::new(Chunk::new());
+        vm.define_native("add", 2, |args| match args {
+            [Value::Number(a), Value::Number(b)] => Ok(Value::Number(a + b)),
+            _ => Err("add() takes two numbers.".to_string()),
+        });
+
+        assert_eq!(vm.execute("add(1, 2) * 2").ok().unwrap().to_string(), "6");
+        assert_eq!(vm.execute("add").ok().unwrap().to_string(), "<native fn>");
+        assert!(vm.execute("add(1)").is_err());
+        assert!(vm.execute("add(1, \"2\")").is_err());
+        // Calls which fail inside the native still count, ones rejected for arity do not.
+        assert_eq!(vm.usage.natives_called["add"], 2);
+
+        // Natives are values like any other, and clock is always installed.
+        let source = "var time = clock; var first = time(); var second = time();";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(
+            vm.execute("first <= second").ok().unwrap().to_string(),
+            "true"
+        );
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_console() {
+        let mut vm = VM::new(Chunk::new());
+        vm.set_input(io::Cursor::new("Ada\n"));
+        vm.capture_output();
+
+        let source = "var name = input(\"name? \"); print \"hi \" + name; print input(\"> \");";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.take_output(), "name? hi Ada\n> nil\n");
+        assert!(vm.console.borrow().output.is_none());
+    }
+
+    #[test]
+    fn test_read_input_line() {
+        let mut input = io::Cursor::new("42\nsecond\r\n\nlast");
+        let lines: Vec<String> = (0..5)
+            .map(|_| read_input_line(&mut input).ok().unwrap().to_string())
+            .collect();
+        assert_eq!(lines, ["42", "second", "", "last", "nil"]);
+    }
+
+    #[test]
+    fn test_call_depth_limit() {
+        let mut vm = VM::new(Chunk::new());
+        vm.max_call_depth = 10;
+
+        let source = "var depth = 0; fun dive() { depth = depth + 1; dive(); } dive();";
+        assert!(matches!(
+            vm.interpret(source),
+            InterpretResult::RuntimeError
+        ));
+        // The script's frame counts towards the limit.
+        assert_eq!(vm.execute("depth").ok().unwrap().to_string(), "9");
+    }
+
+    #[test]
+    fn test_function_syntax_errors() {
+        assert!(Compiler::new("fun () {}").compile().is_none());
+        assert!(Compiler::new("fun f(a, ) {}").compile().is_none());
+        assert!(Compiler::new("fun f(a) a;").compile().is_none());
+        assert!(Compiler::new("f(1, 2;").compile().is_none());
+
+        let params: Vec<String> = (0..256).map(|i| format!("p{}", i)).collect();
+        let source = format!("fun f({}) {{}}", params.join(", "));
+        assert!(Compiler::new(&source).compile().is_none());
+    }
+
+    #[test]
+    fn test_rest_parameters() {
+        let mut vm = VM::new(Chunk::new());
+        let source = "fun f(a, ...rest) { return [a, rest]; }
+            fun all(...xs) { return xs; }
+            class Log { add(first, ...more) { return more; } }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("f(1)").ok().unwrap().to_string(), "[1, []]");
+        assert_eq!(
+            vm.execute("f(1, 2, 3)").ok().unwrap().to_string(),
+            "[1, [2, 3]]"
+        );
+        assert_eq!(vm.execute("all()").ok().unwrap().to_string(), "[]");
+        let more = vm.execute("Log().add(1, nil, \"x\")").ok().unwrap();
+        assert_eq!(more.to_string(), "[nil, \"x\"]");
+        assert!(matches!(
+            vm.execute("f()"),
+            Err(InterpretResult::RuntimeError)
+        ));
+
+        assert!(Compiler::new("fun f(...rest, a) {}").compile().is_none());
+        assert!(Compiler::new("fun f(...) {}").compile().is_none());
+    }
+
+    #[test]
+    fn test_function_fields() {
+        let mut vm = VM::new(Chunk::new());
+        let source = "fun f(a, b) {} fun g(a, ...rest) {}
+            class Point { init(x, y) {} norm() {} }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+
+        let field = |vm: &mut VM, source| vm.execute(source).ok().unwrap().to_string();
+        assert_eq!(field(&mut vm, "f.arity"), "2");
+        assert_eq!(field(&mut vm, "f.name"), "f");
+        // The rest parameter is not counted.
+        assert_eq!(field(&mut vm, "g.arity"), "1");
+        assert_eq!(field(&mut vm, "Point(1, 2).norm.name"), "norm");
+        assert_eq!(field(&mut vm, "Point(1, 2).norm.arity"), "0");
+        assert_eq!(field(&mut vm, "date.arity"), "3");
+        assert_eq!(field(&mut vm, "clock.name"), "clock");
+
+        assert!(matches!(
+            vm.execute("f.body"),
+            Err(InterpretResult::RuntimeError)
+        ));
+    }
+
+    // Testing classes.
+
+    #[test]
+    fn test_classes() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "class Pair {} var a = Pair(); var b = Pair();
+            { class Local {} var c = Local(); print c; }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("Pair").ok().unwrap().to_string(), "Pair");
+        assert_eq!(vm.execute("a").ok().unwrap().to_string(), "Pair instance");
+        assert_eq!(vm.execute("a == a").ok().unwrap().to_string(), "true");
+        assert_eq!(vm.execute("a == b").ok().unwrap().to_string(), "false");
+        assert!(vm.stack.is_empty());
+
+        assert!(vm.execute("Pair(1)").is_err());
+        assert!(vm.execute("a()").is_err());
+        assert!(Compiler::new("class {}").compile().is_none());
+        assert!(Compiler::new("class A").compile().is_none());
+    }
+
+    #[test]
+    fn test_fields() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "class Point {} var p = Point(); var q = p;
+            p.x = 1; p.y = p.x + 1; q.x = q.x * 10;
+            var r = Point(); r.next = p; r.next.x = r.next.x + 1;
+            var chained = p.z = 3;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        // Copies of an instance value refer to the same object.
+        assert_eq!(vm.execute("p.x").ok().unwrap().to_string(), "11");
+        assert_eq!(vm.execute("p.y").ok().unwrap().to_string(), "2");
+        assert_eq!(vm.execute("chained").ok().unwrap().to_string(), "3");
+        assert!(vm.stack.is_empty());
+
+        assert!(vm.execute("p.missing").is_err());
+        assert!(vm.execute("Point().x").is_err());
+        assert!(matches!(
+            vm.interpret("var n = 1; n.x;"),
+            InterpretResult::RuntimeError
+        ));
+        assert!(matches!(
+            vm.interpret("\"s\".x = 1;"),
+            InterpretResult::RuntimeError
+        ));
+        assert!(Compiler::new("p.;").compile().is_none());
+        assert!(Compiler::new("p.x + 1 = 2;").compile().is_none());
+    }
+
+    #[test]
+    fn test_methods() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "class Counter {
+                bump() { this.count = this.count + 1; return this; }
+                get() { return this.count; }
+            }
+            var c = Counter(); c.count = 0;
+            c.bump().bump();
+            var bump = c.bump; bump();
+            c.get = 99;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("c.count").ok().unwrap().to_string(), "3");
+        assert_eq!(vm.execute("bump").ok().unwrap().to_string(), "<fn bump>");
+        // Fields shadow methods.
+        assert_eq!(vm.execute("c.get").ok().unwrap().to_string(), "99");
+        assert!(vm.stack.is_empty());
+
+        assert!(vm.execute("c.bump(1)").is_err());
+        assert!(Compiler::new("print this;").compile().is_none());
+        assert!(Compiler::new("fun f() { return this; }")
+            .compile()
+            .is_none());
+        assert!(Compiler::new("class A { this() {} }").compile().is_none());
+        assert!(Compiler::new("class A { f() { this = 1; } }")
+            .compile()
+            .is_none());
+        // A function nested in a method can't reach the method's instance.
+        assert!(
+            Compiler::new("class A { f() { fun g() { return this; } return g; } }")
+                .compile()
+                .is_none()
+        );
+        assert!(
+            Compiler::new("class A { init() { fun g() { this.x = 1; } } }")
+                .compile()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_initializers() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "class Point {
+                init(x, y) { this.x = x; this.y = y; if (x < 0) return; this.ok = true; }
+            }
+            class Empty {}
+            var p = Point(1, 2); var q = Point(-1, 0); var again = p.init(3, 4);";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("p.x + p.y").ok().unwrap().to_string(), "7");
+        assert_eq!(vm.execute("q").ok().unwrap().to_string(), "Point instance");
+        // `init` returns the instance, even when called directly or returning early.
+        assert_eq!(vm.execute("again == p").ok().unwrap().to_string(), "true");
+        assert!(vm.stack.is_empty());
+        assert!(vm.execute("q.ok").is_err());
+
+        assert!(vm.execute("Point(1)").is_err());
+        assert!(vm.execute("Empty(1)").is_err());
+        assert!(Compiler::new("class A { init() { return 1; } }")
+            .compile()
+            .is_none());
+        assert!(Compiler::new("class A { init() { return; } }")
+            .compile()
+            .is_some());
+        assert!(Compiler::new("class A { f() { return 1; } }")
+            .compile()
+            .is_some());
+    }
+
+    #[test]
+    fn test_inheritance() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "class A {
+                init(n) { this.n = n; }
+                name() { return \"A\"; }
+                describe() { return this.name() + \" \" + this.kind(); }
+                kind() { return \"base\"; }
+            }
+            class B < A {
+                init(n) { super.init(n * 2); }
+                name() { return \"B<\" + super.name() + \">\"; }
+            }
+            class C < B {
+                kind() { var parent = super.kind; return \"derived of \" + parent(); }
+            }
+            var c = C(5);";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        // init is inherited, and super calls start above the class declaring the method.
+        assert_eq!(vm.execute("c.n").ok().unwrap().to_string(), "10");
+        assert_eq!(
+            vm.execute("c.describe()").ok().unwrap().to_string(),
+            "B<A> derived of base"
+        );
+        assert!(vm.stack.is_empty());
+
+        assert!(matches!(
+            vm.interpret("var N = 1; class D < N {}"),
+            InterpretResult::RuntimeError
+        ));
+        let missing = "class E < A { f() { return super.missing(); } } E(1).f();";
+        assert!(matches!(
+            vm.interpret(missing),
+            InterpretResult::RuntimeError
+        ));
+    }
+
+    #[test]
+    fn test_super_errors() {
+        assert!(Compiler::new("class A < A {}").compile().is_none());
+        assert!(Compiler::new("super.f();").compile().is_none());
+        assert!(Compiler::new("class A { f() { super.f(); } }")
+            .compile()
+            .is_none());
+        assert!(
+            Compiler::new("class B < A { f() { fun g() { super.f(); } } }")
+                .compile()
+                .is_none()
+        );
+        assert!(Compiler::new("class B < A { f() { super; } }")
+            .compile()
+            .is_none());
+        assert!(Compiler::new("class B < A { f() { super.f = 1; } }")
+            .compile()
+            .is_none());
+        assert!(Compiler::new("class B < A { f() { return super.f; } }")
+            .compile()
+            .is_some());
+    }
+
+    #[test]
+    fn test_static_methods() {
+        let run = run_source(
+            "class Math {
+              static square(n) { return n * n; }
+              static sumOfSquares(a, b) { return Math.square(a) + Math.square(b); }
+              square() { return \"instance\"; }
+            }
+            class More < Math {}
+            print Math.square(3);
+            print More.sumOfSquares(1, 2);
+            print Math().square();
+            var square = Math.square;
+            print square(4);",
+        );
+        assert_eq!(run.stderr, "");
+        assert_eq!(run.stdout, "9\n5\ninstance\n16\n");
+
+        let run = run_source("class A {}\nA.missing();");
+        assert_eq!(
+            run.stderr,
+            "Undefined static method 'missing'.\n[line 2] in script\n"
+        );
+
+        let run = run_source("class A { static f() { return this; } }");
+        assert_eq!(
+            run.stderr,
+            "[line 1] Error at this: Can't use 'this' in a static method.\n"
+        );
+        let run = run_source("class A { static f() { fun g() { return this; } return g; } }");
+        assert_eq!(
+            run.stderr,
+            "[line 1] Error at this: Can't use 'this' outside of a method.\n"
+        );
+        assert!(Compiler::new("class A < B { static f() { super.f(); } }")
+            .compile()
+            .is_none());
+    }
+
+    // Testing control flow.
+
+    #[test]
+    fn test_if_else() {
+        let mut vm = VM::new(Chunk::new());
+
+        let source = "var a; var b; var c;
+            if (1 < 2) a = 1; else a = 2;
+            if (nil) b = 1; else b = 2;
+            if (false) c = 1;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("a").ok().unwrap().to_string(), "1");
+        assert_eq!(vm.execute("b").ok().unwrap().to_string(), "2");
+        assert_eq!(vm.execute("c").ok().unwrap().to_string(), "nil");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_if_syntax_errors() {
+        assert!(Compiler::new("if 1 print 2;").compile().is_none());
+        assert!(Compiler::new("if (1 print 2;").compile().is_none());
+        assert!(Compiler::new("if (1) var a = 1;").compile().is_none());
+    }
+
+    #[test]
+    fn test_constant_conditions() {
+        let chunk = Compiler::new("if (false) print 1; while (false) print 2;")
+            .compile()
+            .unwrap();
+        assert_eq!(chunk.code, [Opcode::Nil as u8, Opcode::Return as u8]);
+        assert!(chunk.constants.is_empty());
+
+        let mut vm = VM::new(Chunk::new());
+        vm.capture_errors();
+        let source = "var a; var b;
+            if (!nil) a = 1; else a = 2;
+            if (\"\") b = 1;
+            var c = 0;
+            while (true) { c = c + 1; if (c == 3) break; while (nil) break; }";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("a").ok().unwrap().to_string(), "1");
+        assert_eq!(vm.execute("b").ok().unwrap().to_string(), "1");
+        assert_eq!(vm.execute("c").ok().unwrap().to_string(), "3");
+        assert!(vm.stack.is_empty());
+        assert_eq!(
+            vm.take_errors(),
+            "[line 2] Warning at a: Unreachable code.\n\
+             [line 5] Warning at break: Unreachable code.\n"
+        );
+
+        // Code which is dropped still has its errors reported.
+        assert!(Compiler::new("if (false) print;").compile().is_none());
+    }
+
+    // Testing the chunk builder.
+
+    #[test]
+    fn test_chunk_builder() {
+        let chunk = ChunkBuilder::new()
+            .constant(Value::Number(1.0))
+            .line(2)
+            .op(Opcode::Negate)
+            .op(Opcode::Return)
+            .build();
+
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::Constant as u8,
+                0,
+                Opcode::Negate as u8,
+                Opcode::Return as u8
+            ]
+        );
+        let lines: Vec<u32> = (0..chunk.code.len())
+            .map(|offset| chunk.location(offset).line)
+            .collect();
+        assert_eq!(lines, [1, 1, 2, 2]);
+        assert!(chunk.constants[0].is_equal(&Value::Number(1.0)));
+    }
+
+    #[test]
+    fn test_chunk_builder_jumps() {
+        let chunk = ChunkBuilder::new()
+            .label("top")
+            .byte(Opcode::GetLocal, 1)
+            .jump(Opcode::JumpIfFalse, "end")
+            .op(Opcode::Pop)
+            .loop_to("top")
+            .label("end")
+            .op(Opcode::Return)
+            .build();
+
+        assert_eq!(
+            chunk.code,
+            [
+                Opcode::GetLocal as u8,
+                1,
+                Opcode::JumpIfFalse as u8,
+                0,
+                4,
+                Opcode::Pop as u8,
+                Opcode::Loop as u8,
+                0,
+                9,
+                Opcode::Return as u8
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "GetLocal takes operands.")]
+    fn test_chunk_builder_rejects_missing_operands() {
+        ChunkBuilder::new().op(Opcode::GetLocal);
+    }
+
+    #[test]
+    #[should_panic(expected = "Label 'end' is never placed.")]
+    fn test_chunk_builder_rejects_unplaced_labels() {
+        ChunkBuilder::new().jump(Opcode::Jump, "end").build();
+    }
+
+    // Testing stack manipulation.
+
+    fn run_chunk(chunk: Chunk) -> VM {
+        let mut vm = VM::new(chunk);
+        vm.run(false);
+        vm
+    }
+
+    // Compile and run an expression, returning what it evaluated to.
+    fn evaluate(source: &str) -> String {
+        let mut vm = VM::new(Chunk::new());
+        vm.execute(source).ok().unwrap().to_string()
+    }
+
+    // Testing expression evaluation.
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(evaluate("1 + 2 * 3"), "7");
+        assert_eq!(evaluate("(1 + 2) * 3"), "9");
+        assert_eq!(evaluate("8 / 2 / 2"), "2");
+        assert_eq!(evaluate("10 - 4 - 3"), "3");
+        assert_eq!(evaluate("-(2 + 3)"), "-5");
+        assert_eq!(evaluate("--1"), "1");
+    }
+
+    #[test]
+    fn test_bitwise() {
+        assert_eq!(evaluate("12 & 10"), "8");
+        assert_eq!(evaluate("12 | 3"), "15");
+        assert_eq!(evaluate("12 ^ 10"), "6");
+        assert_eq!(evaluate("~5"), "-6");
+        assert_eq!(evaluate("1 << 10"), "1024");
+        assert_eq!(evaluate("-16 >> 2"), "-4");
+        // Fractions are truncated towards zero.
+        assert_eq!(evaluate("7.9 & -1.5"), "7");
+        // Looser than arithmetic, tighter than comparison.
+        assert_eq!(evaluate("1 | 2 == 3"), "true");
+        assert_eq!(evaluate("1 + 1 << 2"), "8");
+        assert_eq!(evaluate("1 | 6 & 3 ^ 4"), "7");
+
+        let mut vm = VM::new(Chunk::new());
+        assert!(matches!(
+            vm.execute("1 & nil"),
+            Err(InterpretResult::RuntimeError)
+        ));
+        assert!(matches!(
+            vm.execute("~\"a\""),
+            Err(InterpretResult::RuntimeError)
+        ));
+        assert!(matches!(
+            vm.execute("1 << 64"),
+            Err(InterpretResult::RuntimeError)
+        ));
+        assert!(matches!(
+            vm.execute("1 >> -1"),
+            Err(InterpretResult::RuntimeError)
+        ));
+        assert!(matches!(
+            vm.execute("true | 1"),
+            Err(InterpretResult::RuntimeError)
+        ));
+    }
+
+    #[test]
+    fn test_power() {
+        assert_eq!(evaluate("2 ** 10"), "1024");
+        assert_eq!(evaluate("2 ** -1"), "0.5");
+        assert_eq!(evaluate("9 ** 0.5"), "3");
+        // Right-associative, and tighter than unary minus and multiplication.
+        assert_eq!(evaluate("2 ** 3 ** 2"), "512");
+        assert_eq!(evaluate("-2 ** 2"), "-4");
+        assert_eq!(evaluate("(-2) ** 2"), "4");
+        assert_eq!(evaluate("3 * 2 ** 2"), "12");
+
+        let mut vm = VM::new(Chunk::new());
+        assert!(matches!(
+            vm.execute("2 ** nil"),
+            Err(InterpretResult::RuntimeError)
+        ));
+        assert!(Compiler::new("2 ** ;").compile().is_none());
+    }
+
+    #[test]
+    fn test_modulo() {
+        assert_eq!(evaluate("7 % 3"), "1");
+        assert_eq!(evaluate("7.5 % 2"), "1.5");
+        // The result takes the sign of the dividend.
+        assert_eq!(evaluate("-7 % 3"), "-1");
+        assert_eq!(evaluate("7 % -3"), "1");
+        assert_eq!(evaluate("1 + 7 % 4 * 2"), "7");
+        assert_eq!(evaluate("5 % 0 == 5 % 0"), "false");
+
+        let mut vm = VM::new(Chunk::new());
+        assert!(matches!(
+            vm.execute("5 % nil"),
+            Err(InterpretResult::RuntimeError)
+        ));
+        assert!(matches!(
+            vm.execute("\"a\" % 2"),
+            Err(InterpretResult::RuntimeError)
+        ));
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(evaluate("1 < 2"), "true");
+        assert_eq!(evaluate("2 <= 1"), "false");
+        assert_eq!(evaluate("1 + 1 == 2"), "true");
+        assert_eq!(evaluate("1 != 1"), "false");
+    }
+
+    #[test]
+    fn test_execute_reports_failures() {
+        let mut vm = VM::new(Chunk::new());
+
+        assert!(matches!(
+            vm.execute("1 +"),
+            Err(InterpretResult::CompileError)
+        ));
+        assert!(matches!(
+            vm.execute("-nil"),
+            Err(InterpretResult::RuntimeError)
+        ));
+        assert!(matches!(vm.execute("1 + 1"), Ok(Value::Number(n)) if n == 2.0));
+    }
+
+    #[test]
+    fn test_arithmetic_type_errors() {
+        let mut vm = VM::new(Chunk::new());
+
+        for source in [
+            "1 + \"a\"",
+            "\"a\" + nil",
+            "true + true",
+            "1 - nil",
+            "\"a\" * 2",
+            "nil < 1",
+        ] {
+            assert!(
+                matches!(vm.execute(source), Err(InterpretResult::RuntimeError)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn test_truthiness() {
+        assert_eq!(evaluate("!nil"), "true");
+        assert_eq!(evaluate("!false"), "true");
+        assert_eq!(evaluate("!true"), "false");
+        assert_eq!(evaluate("!0"), "false");
+        assert_eq!(evaluate("!\"\""), "false");
+        assert_eq!(evaluate("!:red"), "false");
+        assert_eq!(evaluate("!!nil"), "false");
+    }
+
+    #[test]
+    fn test_string_concatenation() {
+        assert_eq!(evaluate("\"foo\" + \"bar\""), "foobar");
+        assert_eq!(evaluate("\"\" + \"a\" + \"\""), "a");
+        assert_eq!(evaluate("\"ab\" == \"a\" + \"b\""), "true");
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(evaluate("true"), "true");
+        assert_eq!(evaluate("false"), "false");
+        assert_eq!(evaluate("nil"), "nil");
+        assert_eq!(evaluate("nil == false"), "false");
+        assert_eq!(evaluate("true == !false"), "true");
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut vm = VM::new(Compiler::new("print 1 + 2;").compile().unwrap());
+        let handle = vm.handle();
+
+        std::thread::spawn(move || handle.interrupt())
+            .join()
+            .unwrap();
+        assert!(matches!(vm.run(false), InterpretResult::Interrupted));
+
+        // The request is consumed, so the VM keeps working afterwards.
+        assert!(matches!(vm.execute("1 + 2"), Ok(Value::Number(n)) if n == 3.0));
+    }
+
+    #[test]
+    fn test_usage_counters() {
+        let mut vm = VM::new(Chunk::new());
+        vm.execute("1 + 2 * 3").ok();
+
+        // Three constants, two operators and the return.
+        assert_eq!(vm.usage.instructions, 6);
+        // The script's own slot plus three operands.
+        assert_eq!(vm.usage.peak_stack_slots, 4);
+    }
+
+    #[test]
+    fn test_dispatch_and_branch_counters() {
+        let mut vm = VM::new(Chunk::new());
+        vm.interpret("for (var i = 0; i < 3; i = i + 1) {}");
+
+        let report = serde_json::to_value(&vm.usage).unwrap();
+        assert_eq!(report["dispatch"]["OP_LESS"], 4);
+        assert_eq!(report["dispatch"]["OP_LOOP"], 6);
+        assert!(report["dispatch"].get("OP_PRINT").is_none());
+
+        let branch = &report["branches"]["OP_JUMP_IF_FALSE"];
+        assert_eq!(branch["taken"], 1);
+        assert_eq!(branch["not_taken"], 3);
+        assert_eq!(branch["taken_ratio"], 0.25);
+    }
+
+    #[test]
+    fn test_opcode_names() {
+        assert_eq!(Opcode::JumpIfFalse.name(), "OP_JUMP_IF_FALSE");
+        assert_eq!(Opcode::Add.name(), "OP_ADD");
+    }
+
+    #[test]
+    fn test_opcode_table() {
+        for &opcode in Opcode::ALL {
+            assert_eq!(Opcode::decode(opcode as u8), Some(opcode));
+
+            // Names follow the variants, so a row can't be given its neighbour's name.
+            let mut name = "OP".to_string();
+            for c in format!("{:?}", opcode).chars() {
+                if c.is_ascii_uppercase() {
+                    name.push('_');
+                }
+                name.push(c.to_ascii_uppercase());
+            }
+            assert_eq!(opcode.name(), name);
+
+            // The disassembler steps over exactly the operands the table declares.
+            let mut chunk = Chunk::new();
+            chunk.write_instruction(opcode, SourceLocation::line(1));
+            for _ in 0..opcode.operands().width() {
+                chunk.write(0, SourceLocation::line(1));
+            }
+            chunk.add_constant(Value::Nil);
+            let (text, next) = Disassembler::new(&chunk, Palette::PLAIN).instruction(0);
+            assert!(text.contains(opcode.name()), "{}", text);
+            assert_eq!(next, chunk.code.len(), "{}", text);
+        }
+        assert_eq!(Opcode::decode(0), None);
+        assert_eq!(Opcode::decode(Opcode::ALL.len() as u8 + 1), None);
+
+        assert_eq!(Opcode::Call.stack_effect(2), (3, 1));
+        assert_eq!(Opcode::BuildMap.stack_effect(2), (4, 1));
+    }
+
+    #[test]
+    fn test_crash_dump() {
+        let dir = std::env::temp_dir().join(format!("rustlox-crash-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut chunk = ChunkBuilder::new().constant(Value::Number(1.0)).build();
+        chunk.write(255, SourceLocation::line(2));
+        let mut vm = VM::new(chunk);
+        vm.crash_dir = dir.clone();
+        vm.globals
+            .insert(Rc::new(LoxString::new("answer")), Value::Number(42.0));
+        assert!(matches!(vm.run(false), InterpretResult::RuntimeError));
+
+        let dumps: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(dumps.len(), 1);
+        let dump = std::fs::read_to_string(&dumps[0]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(dump.starts_with("Internal error: Invalid opcode 255.\n"));
+        assert!(dump.contains("-> 0002    2 Unknown opcode 255\n"));
+        assert!(dump.contains("\n== Stack (2 values, top last) ==\n"));
+        assert!(dump.contains("   1 1\n"));
+        assert!(dump.contains("in script (ip 3, slots from 0)\n"));
+        assert!(dump.contains("answer = 42\n"));
+    }
+
+    #[test]
+    fn test_negate_type_error() {
+        let chunk = Compiler::new("-true").compile_expression().unwrap();
+        let mut vm = VM::new(chunk);
+
+        assert!(matches!(vm.run(false), InterpretResult::RuntimeError));
+    }
+
+    #[test]
+    fn test_stack_shuffling() {
+        let chunk = ChunkBuilder::new()
+            .constant(Value::Number(1.0))
+            .constant(Value::Number(2.0))
+            .op(Opcode::Over) // 1 2 1
+            .op(Opcode::Swap) // 1 1 2
+            .op(Opcode::Dup) // 1 1 2 2
+            .build();
+
+        let vm = run_chunk(chunk);
+        // Slot zero holds the script itself.
+        let stack: Vec<String> = vm.stack[1..].iter().map(Value::to_string).collect();
+
+        assert_eq!(stack, ["1", "1", "2", "2"]);
+    }
+
+    // Testing lists and maps.
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(
+            evaluate("[1, \"two\", [3, nil],]"),
+            "[1, \"two\", [3, nil]]"
+        );
+        assert_eq!(evaluate("[]"), "[]");
+        assert_eq!(evaluate("[10, 20, 30][1]"), "20");
+        assert_eq!(evaluate("[[1, 2], [3, 4]][1][0]"), "3");
+        assert_eq!(evaluate("[1] == [1]"), "false");
+
+        let mut vm = VM::new(Chunk::new());
+        let source = "var a = [1, 2, 3]; var b = a; b[0] = a[2] * 10; var c = a[1] = 5;
+            var cycle = [0]; cycle[0] = cycle;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        // Lists are shared, not copied.
+        assert_eq!(vm.execute("a").ok().unwrap().to_string(), "[30, 5, 3]");
+        assert_eq!(vm.execute("c").ok().unwrap().to_string(), "5");
+        assert_eq!(vm.execute("cycle").ok().unwrap().to_string(), "[[...]]");
+        assert!(vm.stack.is_empty());
+
+        for source in ["a[3]", "a[-1]", "a[0.5]", "a[\"0\"]", "nil[0]", "a[3] = 1"] {
+            assert!(
+                matches!(vm.execute(source), Err(InterpretResult::RuntimeError)),
+                "{}",
+                source
+            );
+        }
+        assert!(Compiler::new("[1, 2;").compile().is_none());
+        assert!(Compiler::new("a[1;").compile().is_none());
+        assert!(Compiler::new("1 + a[0] = 2;").compile().is_none());
+    }
+
+    #[test]
+    fn test_maps() {
+        assert_eq!(
+            evaluate("{\"a\": 1, 2: [nil], :k: {},}"),
+            "{\"a\": 1, 2: [nil], :k: {}}"
+        );
+        assert_eq!(evaluate("{}"), "{}");
+        assert_eq!(evaluate("{\"a\": 1, \"b\": 2}[\"b\"]"), "2");
+        // Missing keys read as nil, and equal keys are the same key.
+        assert_eq!(evaluate("{\"a\": 1}[\"b\"]"), "nil");
+        assert_eq!(
+            evaluate("{0: \"zero\", 1: \"one\", 1.0: \"uno\"}[-0]"),
+            "zero"
+        );
+        assert_eq!(evaluate("{1: 1, 1.0: 2}"), "{1: 2}");
+        // Maps compare by content, whatever the order.
+        assert_eq!(
+            evaluate("{\"a\": 1, \"b\": [2]} == {\"b\": [2], \"a\": 1}"),
+            "false"
+        );
+        assert_eq!(
+            evaluate("{\"a\": 1, \"b\": {}} == {\"b\": {}, \"a\": 1}"),
+            "true"
+        );
+        assert_eq!(evaluate("{\"a\": 1} == {\"a\": 2}"), "false");
+        assert_eq!(evaluate("{\"a\": nil} == {}"), "false");
+
+        let mut vm = VM::new(Chunk::new());
+        let source = "var m = {\"a\": 1}; var alias = m; alias[\"b\"] = m[\"a\"] + 1; m[\"a\"] = 0;
+            var long = \"a key which is too long to be stored inline\";
+            m[long] = 3;
+            var self = {}; self[:me] = self;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(
+            vm.execute("m").ok().unwrap().to_string(),
+            "{\"a\": 0, \"b\": 2, \"a key which is too long to be stored inline\": 3}"
+        );
+        let key = "m[\"a key which is too long \" + \"to be stored inline\"]";
+        assert_eq!(vm.execute(key).ok().unwrap().to_string(), "3");
+        assert_eq!(vm.execute("self").ok().unwrap().to_string(), "{:me: {...}}");
+        // Maps which contain themselves or each other compare without recursing forever.
+        assert_eq!(vm.execute("self == self").ok().unwrap().to_string(), "true");
+        let source = "var a = {}; var b = {}; a[:other] = b; b[:other] = a;
+            var c = {}; c[:other] = c; var d = {}; d[:other] = 1;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        assert_eq!(vm.execute("a == b").ok().unwrap().to_string(), "true");
+        assert_eq!(vm.execute("a == c").ok().unwrap().to_string(), "true");
+        assert_eq!(vm.execute("a == d").ok().unwrap().to_string(), "false");
+        assert_eq!(
+            vm.execute("{:x: a} == {:x: b}").ok().unwrap().to_string(),
+            "true"
+        );
+        assert!(vm.stack.is_empty());
+
+        for source in ["{[]: 1}", "m[[]]", "m[0/0] = 1", "{m: 1}", "1[0]"] {
+            assert!(
+                matches!(vm.execute(source), Err(InterpretResult::RuntimeError)),
+                "{}",
+                source
+            );
+        }
+        assert!(Compiler::new("var m = {\"a\" 1};").compile().is_none());
+        assert!(Compiler::new("var m = {\"a\": 1;").compile().is_none());
+    }
+
+    // Testing CSV.
+
+    #[test]
+    fn test_parse_csv() {
+        let text = "name,qty,note\r\napple,3,\"crisp, red\"\n\n\
+                    \"pear\",\"007\",\"say \"\"hi\"\"\nthere\"\n";
+        let rows = parse_csv(text).unwrap();
+        assert_eq!(
+            rows.to_string(),
+            "[{\"name\": \"apple\", \"qty\": 3, \"note\": \"crisp, red\"}, \
+             {\"name\": \"pear\", \"qty\": \"007\", \"note\": \"say \\\"hi\\\"\\nthere\"}]"
+        );
+        assert_eq!(parse_csv("").unwrap().to_string(), "[]");
+        assert_eq!(parse_csv("a,b").unwrap().to_string(), "[]");
+
+        assert_eq!(
+            parse_csv("a,b\n1\n").err().unwrap(),
+            "Line 2 has 1 fields but the header has 2."
+        );
+        assert_eq!(parse_csv("a,a\n").err().unwrap(), "Duplicate column 'a'.");
+        assert_eq!(
+            parse_csv("a\n\"1\n2").err().unwrap(),
+            "Unterminated quoted field on line 2."
+        );
+        assert!(parse_csv("a\n\"1\"x\n").is_err());
+    }
+
+    #[test]
+    fn test_csv_natives() {
+        let path = std::env::temp_dir().join(format!("rustlox-csv-{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let run = run_source(&format!(
+            "var rows = [{{\"id\": 1, \"code\": \"42\", \"note\": \"a, b\"}}, {{\"id\": 2}}];
+            Csv.write(\"{path}\", rows);
+            var back = Csv.read(\"{path}\");
+            print back;
+            print back[0][\"id\"] + back[1][\"id\"];
+            print Csv.parse(\"x\n1\");"
+        ));
+        let written = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(run.stderr, "");
+        assert_eq!(written, "id,code,note\n1,\"42\",\"a, b\"\n2,,\n");
+        assert_eq!(
+            run.stdout,
+            "[{\"id\": 1, \"code\": \"42\", \"note\": \"a, b\"}, \
+             {\"id\": 2, \"code\": \"\", \"note\": \"\"}]\n3\n[{\"x\": 1}]\n"
+        );
+
+        // Lists and maps are written as printed and quoted, so the file still reads back.
+        let run = run_source(&format!(
+            "Csv.write(\"{path}\", [{{\"tags\": [1, \"a\"], \"meta\": {{\"k\": 1}}, \"n\": 2}}]);
+            print Csv.read(\"{path}\");"
+        ));
+        let written = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(run.stderr, "");
+        assert_eq!(
+            written,
+            "tags,meta,n\n\"[1, \"\"a\"\"]\",\"{\"\"k\"\": 1}\",2\n"
+        );
+        assert_eq!(
+            run.stdout,
+            "[{\"tags\": \"[1, \\\"a\\\"]\", \"meta\": \"{\\\"k\\\": 1}\", \"n\": 2}]\n"
+        );
+
+        let run = run_source("Csv.write(\"unused.csv\", [{\"a\": 1}, {\"b\": 2}]);");
+        assert_eq!(
+            run.stderr,
+            "Row 2 has column \"b\" which the first row lacks.\n[line 1] in script\n"
+        );
+        for source in [
+            "Csv.write(\"unused.csv\", [1]);",
+            "Csv.read(\"/nonexistent/x.csv\");",
+        ] {
+            assert_eq!(
+                run_source(source).result,
+                InterpretResult::RuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    // Testing text patterns.
+
+    #[test]
+    fn test_parse_text() {
+        let parse = |text, pattern| parse_text(text, pattern).unwrap().to_string();
+        assert_eq!(parse("12 apples", "{num} {word}"), "[12, \"apples\"]");
+        assert_eq!(parse("x = -1.5;", "{word}  =  {num};"), "[\"x\", -1.5]");
+        assert_eq!(parse("3,4", "{int},{int}"), "[3, 4]");
+        assert_eq!(parse("{a}", "{{{word}}}"), "[\"a\"]");
+        assert_eq!(
+            parse("10kg of rice", "{qty:num}kg of {what:word}"),
+            "{\"qty\": 10, \"what\": \"rice\"}"
+        );
+
+        // Text which doesn't match, or has some left over, gives nil.
+        assert_eq!(parse("apples 12", "{num} {word}"), "nil");
+        assert_eq!(parse("1.5", "{int}"), "nil");
+        assert_eq!(parse("12 apples", "{num}"), "nil");
+        assert_eq!(parse("12apples", "{num} {word}"), "nil");
+
+        let error = |pattern| parse_text("1", pattern).err().unwrap();
+        assert_eq!(error("{num"), "Unterminated placeholder in pattern.");
+        assert_eq!(
+            error("{float}"),
+            "Unknown placeholder '{float}' in pattern."
+        );
+        assert_eq!(error("{:num}"), "Unknown placeholder '{:num}' in pattern.");
+        assert_eq!(error("}"), "Unmatched '}' in pattern.");
+        assert_eq!(
+            error("{a:num} {num}"),
+            "Name every placeholder in the pattern or none of them."
+        );
+        assert_eq!(error("{a:num} {a:int}"), "Duplicate placeholder name 'a'.");
+    }
+
+    #[test]
+    fn test_parse_native() {
+        let run = run_source(
+            "var point = parse(\"(3, 4)\", \"({x:num}, {y:num})\");
+            print point[\"x\"] * point[\"y\"];
+            print parse(\"n/a\", \"{num}\");",
+        );
+        assert_eq!(run.stdout, "12\nnil\n");
+
+        let run = run_source("parse(1, \"{num}\");");
+        assert!(run
+            .stderr
+            .starts_with("parse takes a string and a pattern string.\n"));
+    }
+
+    // Testing dates and durations.
+
+    #[test]
+    fn test_civil_days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for days in [-800_000, -1, 0, 59, 11_016, 19_782, 2_000_000] {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn test_dates() {
+        let run = run_source(
+            "var start = date(2024, 2, 28) + duration(90000);
+            print start;
+            print start.day + start.hour / 100;
+            print start.weekday;
+            print formatDate(start, \"%a %d %b %Y, %H:%M:%S %%\");
+            var span = date(2024, 3, 1) - start;
+            print span;
+            print span.hours;
+            print -span * 2;
+            print span / duration(3600);
+            print start < start + duration(0.5);
+            print dateFromEpoch(0.25);
+            print duration(0);
+            print date(2024, 1, 1) == dateFromEpoch(1704067200);
+            print dateFromEpoch(59.9999);
+            print dateFromEpoch(-0.0004);
+            print dateFromEpoch(86399.9996);
+            print formatDate(dateFromEpoch(59.9999), \"%H:%M:%S\");
+            print dateFromEpoch(59.9999).minute;
+            print formatDate(date(-1000000, 1, 1), \"%Y\");
+            print date(1000000, 12, 31).year;",
+        );
+        let expected = "2024-02-29T01:00:00Z\n29.01\n4\nThu 29 Feb 2024, 01:00:00 %\n23h\n23\n\
+                        -1d 22h\n23\ntrue\n1970-01-01T00:00:00.250Z\n0s\ntrue\n\
+                        1970-01-01T00:01:00Z\n1970-01-01T00:00:00Z\n1970-01-02T00:00:00Z\n\
+                        00:01:00\n1\n-1000000\n1000000\n";
+        assert_eq!(run.stderr, "");
+        assert_eq!(run.stdout, expected);
+
+        for source in [
+            "date(2023, 2, 29);",
+            "date(2024, 13, 1);",
+            "date(2024, 1, 1.5);",
+            "date(2024, 1, 1) + date(2024, 1, 1);",
+            "date(2024, 1, 1) < duration(1);",
+            "date(2024, 1, 1) + 1;",
+            "date(2024, 1, 1).hours;",
+            "formatDate(date(2024, 1, 1), \"%q\");",
+            "duration(1) / 0;",
+            "duration(0) / 0;",
+            "date(2024, 1, 1) + duration(1) * (1 / 0);",
+            "formatDate(dateFromEpoch(100000000000000000000000000000), \"%Y\");",
+            "dateFromEpoch(-100000000000000000000000000000).year;",
+            "date(1000000, 12, 31) + duration(86400);",
+            "date(-1000000, 1, 1) - duration(1);",
+        ] {
+            assert_eq!(
+                run_source(source).result,
+                InterpretResult::RuntimeError,
+                "{}",
+                source
+            );
+        }
+    }
+
+    // Testing the program generator.
+
+    #[cfg(feature = "gen")]
+    #[test]
+    fn test_generator_is_deterministic() {
+        assert_eq!(Generator::new(7).program(), Generator::new(7).program());
+        for seed in [0, 0x9e37_79b9_7f4a_7c15, u64::MAX] {
+            assert_ne!(Generator::new(seed).next(), 0, "seed {}", seed);
+        }
+    }
+
+    #[cfg(feature = "gen")]
+    #[test]
+    fn test_generated_programs_compile() {
+        for seed in 0..200 {
+            let program = Generator::new(seed).program();
+            let mut compiler = Compiler::new(&program);
+
+            assert!(compiler.compile().is_some(), "seed {}: {}", seed, program);
+        }
+    }
+
+    // Each generated statement sits on its own line, so a program can also be fed to one VM a
+    // line at a time, as the REPL does. Both ways must print the same and fail the same.
+    #[cfg(feature = "gen")]
+    #[test]
+    fn test_generated_programs_run() {
+        for seed in 0..200 {
+            let program = Generator::new(seed).program();
+            let whole = run_source(&program);
+
+            let mut vm = VM::new(Chunk::new());
+            vm.capture_output();
+            vm.capture_errors();
+            let mut result = InterpretResult::Ok;
+            for line in program.lines() {
+                result = vm.interpret(line);
+                if result != InterpretResult::Ok {
+                    break;
+                }
+            }
+            let errors = vm.take_errors();
+
+            assert_ne!(
+                whole.result,
+                InterpretResult::CompileError,
+                "seed {}: {}",
+                seed,
+                program
+            );
+            assert_eq!(result, whole.result, "seed {}: {}", seed, program);
+            assert_eq!(vm.take_output(), whole.stdout, "seed {}: {}", seed, program);
+            // The messages agree; the line numbers in the traces do not.
+            assert_eq!(
+                errors.lines().next(),
+                whole.stderr.lines().next(),
+                "seed {}",
+                seed
+            );
+        }
+    }
+
+    // Testing nil coalescing.
+
+    #[test]
+    fn test_coalesce() {
+        assert_eq!(evaluate("nil ?? 2"), "2");
+        assert_eq!(evaluate("1 ?? 2"), "1");
+        assert_eq!(evaluate("false ?? 2"), "false");
+    }
+
+    #[test]
+    fn test_nil_safe_property_access() {
+        let run = run_source(
+            "class Box { init(value) { this.value = value; } get(n) { return this.value + n; } }
+            var full = Box(1);
+            var empty = nil;
+            fun loud() { print \"evaluated\"; return 1; }
+            print full?.value;
+            print empty?.value;
+            print full?.get(2);
+            print empty?.get(loud());
+            print empty?.value ?? \"none\";
+            print Box(empty)?.value?.missing;",
+        );
+        assert_eq!(run.stderr, "");
+        assert_eq!(run.stdout, "1\nnil\n3\nnil\nnone\nnil\n");
+
+        // Only nil is skipped, and only the one step.
+        for source in ["false?.x;", "var a; a?.b.c;", "var a = Box(1); a?.missing;"] {
+            let source = format!("class Box {{ init(v) {{ this.v = v; }} }} {}", source);
+            assert_eq!(
+                run_source(&source).result,
+                InterpretResult::RuntimeError,
+                "{}",
+                source
+            );
+        }
+        assert!(Compiler::new("var a; a?.b = 1;").compile().is_none());
+        assert!(Compiler::new("var a; a?.;").compile().is_none());
+    }
+
+    // Testing the conditional operator.
+
+    #[test]
+    fn test_conditional() {
+        assert_eq!(evaluate("true ? 1 : 2"), "1");
+        assert_eq!(evaluate("nil ? 1 : 2"), "2");
+        assert_eq!(evaluate("1 < 2 ? \"yes\" : \"no\""), "yes");
+        // Right-associative, and binding looser than `or` and `??`.
+        assert_eq!(evaluate("false ? 1 : false ? 2 : 3"), "3");
+        assert_eq!(evaluate("true ? false ? 1 : 2 : 3"), "2");
+        assert_eq!(evaluate("false or true ? 1 : 2"), "1");
+        assert_eq!(evaluate("nil ?? false ? 1 : 2"), "2");
+        assert_eq!(evaluate("true ? :a : :b"), ":a");
+
+        let mut vm = VM::new(Chunk::new());
+        let source = "var a = 0; var b = 0; var c = true ? a = 1 : (b = 1); var d = 5;";
+        assert!(matches!(vm.interpret(source), InterpretResult::Ok));
+        // Only the chosen branch runs.
+        assert_eq!(vm.execute("b").ok().unwrap().to_string(), "0");
+        assert!(vm.stack.is_empty());
+
+        assert!(Compiler::new("true ? 1;").compile().is_none());
+        assert!(Compiler::new("true ? 1 : ;").compile().is_none());
+        assert!(Compiler::new("var x; true ? x : x = 1;")
+            .compile()
+            .is_none());
+    }
+
+    // Testing the compile cache.
+
+    #[test]
+    fn test_compile_cache_reuses_chunks() {
+        let mut vm = VM::new(Chunk::new());
+
+        let first = vm
+            .compile_cached("print 1;", CompileMode::Script, 0)
+            .unwrap();
+        let second = vm
+            .compile_cached("print 1;", CompileMode::Script, 0)
+            .unwrap();
+        let other = vm
+            .compile_cached("print 2;", CompileMode::Script, 0)
+            .unwrap();
+        let expression = vm.compile_cached("1", CompileMode::Expression, 0).unwrap();
+
+        assert!(Rc::ptr_eq(&first, &second));
+        assert!(!Rc::ptr_eq(&first, &other));
+        assert!(!Rc::ptr_eq(&first, &expression));
+        assert!(vm
+            .compile_cached("print 1", CompileMode::Script, 0)
+            .is_none());
+        assert_eq!(vm.compile_cache.len(), 3);
+    }
+
+    // Testing compiler limits.
+
+    #[test]
+    fn test_constant_limit() {
+        let numbers = |count: usize| (0..count).map(|n| format!("{}.5", n)).collect::<Vec<_>>();
+
+        let fits = numbers(UINT8_COUNT).join(" + ");
+        assert!(Compiler::new(&fits).compile_expression().is_some());
+
+        let too_many = numbers(UINT8_COUNT + 1).join(" + ");
+        assert!(Compiler::new(&too_many).compile_expression().is_none());
+
+        // Repeated constants share a slot, so they never run into the limit.
+        let repeated = vec!["1.5"; UINT8_COUNT * 2].join(" + ");
+        assert!(Compiler::new(&repeated).compile_expression().is_some());
+    }
+
+    #[test]
+    fn test_shared_constants() {
+        let chunk = Compiler::new("var a = 0.5; a = a + 0.5 + 0.50; print \"a\";")
+            .compile()
+            .unwrap();
+        let constants: Vec<String> = chunk.constants.iter().map(|c| c.to_string()).collect();
+        assert_eq!(constants, ["a", "0.5"]);
+        // A name must not reuse a literal, which may be stored inline.
+        let chunk = Compiler::new("print \"a\"; var a;").compile().unwrap();
+        assert!(matches!(chunk.constants[1], Value::String(_)));
+
+        // Only identical constants are merged.
+        let chunk = Compiler::new("0.5 + -0.5 + 0.5;").compile().unwrap();
+        assert_eq!(chunk.constants.len(), 1);
+        let chunk = Compiler::new("print 256; print 256.0 * -1; print 1000;")
+            .compile()
+            .unwrap();
+        assert_eq!(chunk.constants.len(), 2);
+
+        // Whole numbers from 0 to 255 need no slot at all.
+        let chunk = Compiler::new("print 0 + 1 + 255 + 0.0 + -0;")
+            .compile()
+            .unwrap();
+        assert!(chunk.constants.is_empty());
+        let listing = Disassembler::new(&chunk, Palette::PLAIN).chunk("test");
+        assert!(listing.contains("OP_SMALL_INTEGER  255\n"));
+        assert_eq!(evaluate("255 + 1"), "256");
+        assert_eq!(evaluate("0 * -1"), "-0");
+
+        // Long strings are shared between chunks, e.g. each function's copy of a name.
+        let name = "a_name_too_long_to_be_stored_inline";
+        let source = format!("fun f() {{ return {name}; }} fun g() {{ return {name}; }}");
+        let chunk = Compiler::new(&source).compile().unwrap();
+        let names: Vec<Rc<LoxString>> = chunk
+            .constants
+            .iter()
+            .filter_map(|constant| match constant {
+                Value::Function(function) => match &function.chunk.constants[0] {
+                    Value::String(name) => Some(name.clone()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names.len(), 2);
+        assert!(Rc::ptr_eq(&names[0], &names[1]));
+    }
+
+    // Testing batch runs.
+
+    #[test]
+    fn test_files_share_globals() {
+        let dir = std::env::temp_dir().join(format!("rustlox-batch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.lox");
+        let second = dir.join("second.lox");
+        std::fs::write(
+            &first,
+            "var greeting = \"hi\"; fun shout(s) { return s + \"!\"; }",
+        )
+        .unwrap();
+        std::fs::write(&second, "var result = shout(greeting);").unwrap();
+
+        let mut vm = VM::new(Chunk::new());
+        assert!(matches!(
+            vm.interpret_file(first.to_str().unwrap()),
+            Ok(InterpretResult::Ok)
+        ));
+        assert!(matches!(
+            vm.interpret_file(second.to_str().unwrap()),
+            Ok(InterpretResult::Ok)
+        ));
+        assert_eq!(vm.execute("result").ok().unwrap().to_string(), "hi!");
+        assert_eq!(vm.usage.files.len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_source_files_are_not_cached() {
+        let dir = std::env::temp_dir().join(format!("rustlox-source-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("script.lox");
+        let empty = dir.join("empty.lox");
+        let binary = dir.join("binary.lox");
+        std::fs::write(&script, "var answer = 6 * 7;").unwrap();
+        std::fs::write(&empty, "").unwrap();
+        std::fs::write(&binary, [0xff, 0xfe]).unwrap();
+
+        assert_eq!(
+            &*SourceText::load(script.to_str().unwrap()).unwrap(),
+            "var answer = 6 * 7;"
+        );
+        assert_eq!(&*SourceText::load(empty.to_str().unwrap()).unwrap(), "");
+        let err = SourceText::load(binary.to_str().unwrap()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut vm = VM::new(Chunk::new());
+        assert!(matches!(
+            vm.interpret_file(script.to_str().unwrap()),
+            Ok(InterpretResult::Ok)
+        ));
+        assert!(matches!(
+            vm.interpret_file(empty.to_str().unwrap()),
+            Ok(InterpretResult::Ok)
+        ));
+        assert_eq!(vm.execute("answer").ok().unwrap().to_string(), "42");
+        // Only the `answer` snippet was cached.
+        assert_eq!(vm.compile_cache.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // Testing completion.
+
+    fn completions(source: &str) -> Vec<String> {
+        complete(source, source.len(), &["clock"]).matches
+    }
+
+    #[test]
+    fn test_complete_keywords() {
+        assert_eq!(completions("pr"), ["print"]);
+        assert_eq!(
+            completions("var x = 1; c"),
+            ["class", "clock", "const", "continue"]
+        );
+        assert_eq!(completions("print x a"), ["and"]);
+        assert_eq!(completions("print (1) o"), ["or"]);
+        assert_eq!(completions("print [1] o"), ["or"]);
+        assert_eq!(completions("print 1 + t"), ["true"]);
+        assert_eq!(completions("if (x) print 1; e"), ["else"]);
+        assert_eq!(completions("while (x) b"), ["break"]);
+        assert_eq!(completions("switch (x) { d"), ["default", "defer"]);
+        assert_eq!(completions("switch (x) { case 1: d"), ["default", "defer"]);
+        // New names, strings, comments and symbols are not completed.
+        assert!(completions("var p").is_empty());
+        assert!(completions("print \"pr").is_empty());
+        assert!(completions("print :p").is_empty());
+        assert!(completions("print 1p").is_empty());
+
+        let source = "x = 1; pr x;";
+        assert_eq!(complete(source, 9, &[]).start, 7);
+        assert_eq!(complete(source, 9, &[]).matches, ["print"]);
+    }
+
+    #[test]
+    fn test_complete_names() {
+        assert_eq!(completions("var apple = 1; print a"), ["apple"]);
+        assert_eq!(completions("fun area(width) { return w"), ["width"]);
+        assert_eq!(completions("fun area(width) { } w"), ["while"]);
+        assert_eq!(
+            completions("{ var inner = 1; } print i"),
+            Vec::<String>::new()
+        );
+        assert_eq!(completions("var total = 1; print \"${to"), ["total"]);
+        assert_eq!(completions("for (var i = 0; i"), ["i"]);
+
+        let source = "class Point { init(x) { this.x = x; } norm() { return t";
+        assert_eq!(completions(source), ["this", "true"]);
+        assert_eq!(
+            completions("class Point { init(x) { this.x = x; } n"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            completions("class P { init() {} } var p = P(); p.i"),
+            ["init"]
+        );
+    }
+
+    // Testing decimals.
+
+    #[test]
+    fn test_scanner_decimal_suffix() {
+        let mut scanner = Scanner::new("1.10d 2d 3");
+
+        assert!(scanner.scan_token().kind == TokenKind::Decimal);
+        assert!(scanner.scan_token().kind == TokenKind::Decimal);
+        assert!(scanner.scan_token().kind == TokenKind::Number);
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn test_decimal_arithmetic() {
+        let a = Decimal::parse("0.1").unwrap();
+        let b = Decimal::parse("0.2").unwrap();
+        let price = Decimal::parse("1.10").unwrap();
+        let three = Decimal::parse("3").unwrap();
+
+        assert_eq!(a.add(&b).unwrap().to_string(), "0.3");
+        assert_eq!(price.multiply(&three).unwrap().to_string(), "3.30");
+        assert_eq!(price.subtract(&three).unwrap().to_string(), "-1.90");
+        assert_eq!(
+            Decimal::parse("1")
+                .unwrap()
+                .divide(&three)
+                .unwrap()
+                .to_string(),
+            "0.333333333333333333"
+        );
+        assert_eq!(
+            Decimal::parse("2")
+                .unwrap()
+                .divide(&three)
+                .unwrap()
+                .to_string(),
+            "0.666666666666666667"
+        );
+        assert_eq!(
+            price
+                .divide(&Decimal::parse("2").unwrap())
+                .unwrap()
+                .to_string(),
+            "0.55"
+        );
+        assert!(three.divide(&Decimal::parse("0.0").unwrap()).is_err());
+        // A product of two tiny values has too many fractional digits to scale down.
+        let tiny = Decimal::parse("0.00000000000000000000000000000000000001").unwrap();
+        let product = tiny.multiply(&tiny).unwrap();
+        assert_eq!(
+            product.divide(&Decimal::parse("1").unwrap()).err(),
+            Some("Decimal overflow.")
+        );
+        assert!(a
+            .add(&b)
+            .unwrap()
+            .compare(&Decimal::parse("0.30").unwrap())
+            .is_eq());
+    }
+
+    #[cfg(feature = "decimal")]
+    #[test]
+    fn test_decimal_comparison() {
+        use std::cmp::Ordering::{Equal, Greater, Less};
+
+        let tiny = "0.0000000000000000000000000000000000000001";
+        let zero = "0.00000000000000000000000000000000000000000";
+        let cases = [
+            ("-1".to_string(), tiny.to_string(), Less),
+            ("1".to_string(), tiny.to_string(), Greater),
+            (format!("-{}", tiny), tiny.to_string(), Less),
+            ("0".to_string(), zero.to_string(), Equal),
+            (format!("-{}", zero), zero.to_string(), Equal),
+            ("0".to_string(), tiny.to_string(), Less),
+            (
+                tiny.to_string(),
+                "0.00000000000000000000000000000000000000011".to_string(),
+                Less,
+            ),
+            (
+                format!("-{}", tiny),
+                "-0.00000000000000000000000000000000000000011".to_string(),
+                Greater,
+            ),
+            ("1.50".to_string(), "1.5".to_string(), Equal),
+            ("-2.5".to_string(), "-2.25".to_string(), Less),
+        ];
+
+        for (a, b, expected) in cases {
+            let (a, b) = (Decimal::parse(&a).unwrap(), Decimal::parse(&b).unwrap());
+            assert_eq!(a.compare(&b), expected, "{} vs {}", a, b);
+        }
+    }
+}
@@ -1,11 +1,19 @@
 use std::{
-    fmt,
-    io::{self, BufRead, Write},
-    mem,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
     process::ExitCode,
+    rc::Rc,
+    time::Instant,
 };
 
-use clap::command;
+use log::error;
+#[cfg(feature = "gen")]
+use sugoi_na::Generator;
+use sugoi_na::{
+    check_snapshots, graphs_to_dot, program_functions, Chunk, CompileMode, Compiler, Disassembler,
+    Function, InterpretResult, Palette, SourceRegistry, SourceText, Usage, DEFAULT_MAX_CALL_DEPTH,
+    VM,
+};
 
 //
 // CLI.
@@ -17,1268 +25,1135 @@ struct Args {
     // Source code file path. If not specifed, REPL mode will start.
     #[arg(short, long)]
     path: Option<String>,
-}
 
-use log::error;
-use num_derive::FromPrimitive;
-use num_traits::FromPrimitive;
+    // Scripts to run one after another in a single VM, so later ones see earlier ones' globals.
+    #[arg(value_name = "FILE", conflicts_with_all = ["path", "remote"])]
+    scripts: Vec<String>,
+
+    // Scripts to run before the main script or REPL, in order. May be repeated.
+    #[arg(long, value_name = "FILE")]
+    preload: Vec<String>,
+
+    // Write a JSON report of the resources the run used to this file.
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    // Print the script's bytecode instead of running it.
+    #[arg(long, requires = "path")]
+    disasm: bool,
+
+    // Write the script's control-flow and call graphs to this file as Graphviz, then exit.
+    #[arg(long, value_name = "FILE", requires = "path")]
+    cfg: Option<PathBuf>,
+
+    // Serve a persistent session over stdin/stdout, one JSON-RPC message per line.
+    #[arg(long, conflicts_with = "path")]
+    remote: bool,
+
+    // Listen on this Unix socket and serve each connection as a `--remote` session on its own
+    // thread, with the preloads already run.
+    #[cfg(unix)]
+    #[arg(long, value_name = "SOCKET", conflicts_with_all = ["path", "remote", "scripts"])]
+    daemon: Option<PathBuf>,
+
+    // End the REPL at the first compile or runtime error, with the exit code a script would get.
+    #[arg(long, conflicts_with_all = ["path", "remote"])]
+    fail_fast: bool,
+
+    // How deeply function calls may nest before the script is stopped.
+    #[arg(long, value_name = "DEPTH", default_value_t = DEFAULT_MAX_CALL_DEPTH)]
+    max_call_depth: usize,
+
+    // Run every `.lox` program in this directory and compare its output with the `.stdout` and
+    // `.stderr` snapshots beside it, then exit.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["path", "remote", "scripts"])]
+    snapshots: Option<PathBuf>,
+
+    // Rewrite the snapshots to match the programs' output instead of comparing them.
+    #[arg(long, requires = "snapshots")]
+    bless: bool,
+
+    // Configuration file to use instead of the default one.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    // Print a random well-formed program generated from the given seed, then exit.
+    #[cfg(feature = "gen")]
+    #[arg(long = "gen", value_name = "SEED")]
+    gen_seed: Option<u64>,
+}
 
 //
-// Value.
+// Configuration.
 //
-#[derive(Clone)]
-enum Value {
-    Bool(bool),
-    Nil,
-    Number(f32),
-    String(String),
-}
 
-impl Value {
-    #[allow(dead_code)]
-    fn is_bool(&self) -> bool {
-        matches!(*self, Value::Bool(_))
-    }
-
-    fn is_number(&self) -> bool {
-        matches!(*self, Value::Number(_))
-    }
-
-    #[allow(dead_code)]
-    fn is_nil(&self) -> bool {
-        matches!(*self, Value::Nil)
-    }
-
-    fn is_string(&self) -> bool {
-        matches!(*self, Value::String(_))
-    }
-
-    #[allow(dead_code)]
-    fn as_bool(&self) -> bool {
-        match *self {
-            Value::Bool(value) => value,
-            _ => unreachable!(),
-        }
-    }
+/// User defaults loaded from `config.toml`, so common flags need not be repeated.
+///
+/// Keys this version does not know about are ignored.
+#[derive(serde::Deserialize, Debug)]
+#[serde(default)]
+struct Config {
+    /// Prompt printed by the REPL before each line.
+    prompt: String,
+}
 
-    fn as_number(&self) -> f32 {
-        match *self {
-            Value::Number(value) => value,
-            _ => unreachable!(),
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            prompt: "> ".to_string(),
         }
     }
+}
 
-    #[allow(dead_code)]
-    fn as_string(&self) -> String {
-        match *self {
-            Value::String(ref value) => value.clone(),
-            _ => unreachable!(),
-        }
-    }
+impl Config {
+    // Default location: $XDG_CONFIG_HOME/rustlox/config.toml, falling back to ~/.config.
+    fn default_path() -> Option<PathBuf> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
 
-    fn is_falsey(&self) -> bool {
-        match *self {
-            Value::Bool(value) => !value,
-            Value::Nil => true,
-            _ => false,
-        }
+        Some(config_home.join("rustlox").join("config.toml"))
     }
 
-    fn is_equal(&self, other: &Value) -> bool {
-        match (self, other) {
-            (Value::Bool(a), Value::Bool(b)) => a == b,
-            (Value::Nil, Value::Nil) => true,
-            (Value::Number(a), Value::Number(b)) => a == b,
-            (Value::String(a), Value::String(b)) => a == b,
-            _ => false,
-        }
-    }
-}
+    // Load the configuration. A missing default file means defaults, a missing explicit one is an error.
+    fn load(explicit: Option<&PathBuf>) -> Result<Self, String> {
+        let path = match explicit.cloned().or_else(Config::default_path) {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
 
-impl fmt::Display for Value {
-    // This trait requires `fmt` with this exact signature.
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Value::Bool(v) => write!(f, "{}", v),
-            Value::Nil => write!(f, "nil"),
-            Value::Number(v) => write!(f, "{}", v),
-            Value::String(_) => todo!(),
-        }
-    }
-}
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(_) if explicit.is_none() => return Ok(Config::default()),
+            Err(err) => return Err(format!("Could not read {}: {}", path.display(), err)),
+        };
 
-fn print_value(value: &Value) {
-    match value {
-        Value::Bool(v) => print!("{}", v),
-        Value::Nil => print!("nil"),
-        Value::Number(v) => print!("{}", v),
-        Value::String(ref str) => print!("{}", str),
+        toml::from_str(&text).map_err(|err| format!("Invalid config {}: {}", path.display(), err))
     }
 }
 
 //
-// Chunk.
+// Main driver.
 //
+fn main() -> ExitCode {
+    let args = <Args as clap::Parser>::parse();
+    let mut vm = VM::new(Chunk::new());
+    vm.max_call_depth = args.max_call_depth;
+
+    let config = match Config::load(args.config.as_ref()) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::from(78);
+        }
+    };
 
-// List of VM instructions.
-#[derive(FromPrimitive)]
-#[repr(u8)]
-pub enum Opcode {
-    Constant = 1,
-    Nil,   // These three value area added here because it's better for performance.
-    True,  // By doing so, we don't need to create another look up table,
-    False, // and we can save an additional byte (no need for Opcode::Constant).
-    Equal,
-    Greater,
-    Less,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Not,
-    Negate,
-    Return,
-}
-
-// Precedence table. From lowest to highest.
-#[derive(FromPrimitive, Clone, Copy)]
-#[repr(u8)]
-pub enum Precedence {
-    None = 1,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
-    Primary,
-}
+    #[cfg(feature = "gen")]
+    if let Some(seed) = args.gen_seed {
+        println!("{}", Generator::new(seed).program());
+        return ExitCode::SUCCESS;
+    }
 
-/// A chunk is a sequence of bytecode.
-#[derive(Default)]
-pub struct Chunk {
-    /// The list of bytecode which represents the program.
-    pub code: Vec<u8>,
-    /// The list of constants declared.
-    constants: Vec<Value>,
-    /// The line numbers for each bytecode.
-    pub lines: Vec<i32>,
-}
+    let started = Instant::now();
+    let code = run(&args, &mut vm, &config);
 
-impl Chunk {
-    /// Returns a newly intialized chunk.
-    pub fn new() -> Self {
-        Self {
-            code: vec![],
-            constants: vec![],
-            lines: vec![],
+    if let Some(path) = args.report.as_ref() {
+        if let Err(err) = write_usage_report(path, &vm.usage, started) {
+            eprintln!("Could not write report {}: {}", path.display(), err);
         }
     }
 
-    /// Write a byte into the chunk.
-    pub fn write(&mut self, byte: u8, line: i32) {
-        self.code.push(byte);
-        self.lines.push(line);
-    }
+    code
+}
 
-    /// Write an instruction into the chunk.
-    pub fn write_instruction(&mut self, instruction: Opcode, line: i32) {
-        self.write(instruction as u8, line);
+// Run the preloads, then the script or the REPL.
+fn run(args: &Args, vm: &mut VM, config: &Config) -> ExitCode {
+    #[cfg(unix)]
+    if let Some(socket) = args.daemon.as_ref() {
+        return run_daemon(socket, &args.preload, args.max_call_depth);
     }
-
-    // TODO: I should probably move this out.
-    /// Print instruction name and return the next offset.
-    pub fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{} ", name);
-        offset + 1
+    if let Some(dir) = args.snapshots.as_ref() {
+        return run_snapshots(dir, args.bless);
     }
 
-    /// Push a constant into the constant vector, return the index which the constant resides.
-    fn add_constant(&mut self, value: Value) -> u16 {
-        self.constants.push(value);
-        (self.constants.len() - 1) as u16
+    for path in &args.preload {
+        if let Err(code) = run_file(vm, path) {
+            return code;
+        }
     }
 
-    /// Print the constant's handle and it's value. Returns the next offset.
-    pub fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant_index = self.code[offset + 1] as usize;
-        print!("{:-16} {:4} '", name, constant_index);
-        print_value(&self.constants[constant_index]);
-        println!("'");
-        offset + 2
+    if !args.scripts.is_empty() {
+        // The first failing script ends the batch with its exit code.
+        return match args.scripts.iter().try_for_each(|path| run_file(vm, path)) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(code) => code,
+        };
     }
 
-    /// Dump the instruction's information.
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
-
-        let offset_index = offset;
-        if offset_index > 0 && self.lines[offset_index] == self.lines[offset_index - 1] {
-            print!("   | ")
-        } else {
-            print!("{:4} ", self.lines[offset_index]);
+    if let Some(path) = args.path.as_deref() {
+        if args.disasm {
+            return disassemble_file(path);
+        }
+        if let Some(out) = args.cfg.as_ref() {
+            return write_graphs(path, out);
         }
 
-        let byte = self.code[offset];
-        let instruction: Option<Opcode> = FromPrimitive::from_u8(byte);
-
-        match instruction {
-            Some(Opcode::Greater) => self.simple_instruction("OP_GREATER", offset),
-            Some(Opcode::Less) => self.simple_instruction("OP_GREATER", offset),
-            Some(Opcode::Equal) => self.simple_instruction("OP_EQUAL", offset),
-            Some(Opcode::True) => self.simple_instruction("OP_TRUE", offset),
-            Some(Opcode::False) => self.simple_instruction("OP_FALSE", offset),
-            Some(Opcode::Nil) => self.simple_instruction("OP_NIL", offset),
-            Some(Opcode::Constant) => self.constant_instruction("OP_CONSTANT", offset),
-            Some(Opcode::Add) => self.simple_instruction("OP_ADD", offset),
-            Some(Opcode::Subtract) => self.simple_instruction("OP_SUBTRACT", offset),
-            Some(Opcode::Multiply) => self.simple_instruction("OP_MULTIPLY", offset),
-            Some(Opcode::Divide) => self.simple_instruction("OP_DIVIDE", offset),
-            Some(Opcode::Negate) => self.simple_instruction("OP_NEGATE", offset),
-            Some(Opcode::Not) => self.simple_instruction("OP_NOT", offset),
-            Some(Opcode::Return) => self.simple_instruction("OP_RETURN", offset),
-            None => {
-                println!("Unknown opcode {}", byte);
-                offset + 1
-            }
+        match run_file(vm, path) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(code) => code,
         }
+    } else if args.remote {
+        run_remote(vm)
+    } else {
+        run_repl(vm, config, args.fail_fast)
     }
+}
 
-    /// For debugging. Dumps the program's instructions.
-    pub fn disassemble_chunk(&self, name: &str) {
-        println!("== {} ==", name);
-
-        let mut offset: usize = 0;
-        while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+// Check the snapshots in `dir`, reporting each mismatch.
+fn run_snapshots(dir: &Path, bless: bool) -> ExitCode {
+    match check_snapshots(dir, bless) {
+        Ok(mismatches) if mismatches.is_empty() => ExitCode::SUCCESS,
+        Ok(mismatches) => {
+            for mismatch in &mismatches {
+                eprintln!("{}", mismatch);
+            }
+            eprintln!("{} snapshots differ.", mismatches.len());
+            ExitCode::FAILURE
+        }
+        Err(err) => {
+            eprintln!("Could not check snapshots in {}: {}", dir.display(), err);
+            ExitCode::from(74)
         }
     }
 }
 
 //
-// Token.
+// Disassembly.
 //
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum TokenKind {
-    // Single-character tokens.
-    LeftParen,
-    RightParen,
-    LeftBrace,
-    RightBrace,
-    Comma,
-    Dot,
-    Minus,
-    Plus,
-    Semicolon,
-    Slash,
-    Star,
-    // One or two character tokens.
-    Bang,
-    BangEqual,
-    Equal,
-    EqualEqual,
-    Greater,
-    GreaterEqual,
-    Less,
-    LessEqual,
-    // Literals.
-    Identifier,
-    String,
-    Number,
-    // Keywords.
-    And,
-    Class,
-    Else,
-    False,
-    For,
-    Fun,
-    If,
-    Nil,
-    Or,
-    Print,
-    Return,
-    Super,
-    This,
-    True,
-    Var,
-    While,
-
-    Error,
-    Eof,
-}
 
-#[derive(Clone, Copy)]
-struct Token<'a> {
-    kind: TokenKind,
-    start: usize,
-    length: usize,
-    line: usize,
-    source: &'a str,
+// Compile a script without running it. Returns the chunk and the script's display name.
+fn compile_file(path: &str) -> Result<(Chunk, String), ExitCode> {
+    let Ok(source) = SourceText::load(path) else {
+        eprintln!("Could not read {}.", path);
+        return Err(ExitCode::from(74));
+    };
+
+    let mut sources = SourceRegistry::default();
+    let file = sources.register(path);
+    let chunk = Compiler::for_file(&source, file, &sources)
+        .compile()
+        .ok_or(ExitCode::from(65))?;
+    Ok((chunk, sources.name(file).unwrap_or(path).to_string()))
 }
 
-impl<'a> Token<'a> {
-    fn lexeme(&self) -> &'a str {
-        &self.source[self.start..self.start + self.length]
-    }
-
-    fn new(tty: TokenKind, start: usize, length: usize, line: usize, source: &'a str) -> Self {
-        Self {
-            kind: tty,
-            start,
-            length,
-            line,
-            source,
-        }
-    }
+// Print a script's bytecode, colored and paged when writing to a terminal.
+fn disassemble_file(path: &str) -> ExitCode {
+    let (chunk, name) = match compile_file(path) {
+        Ok(compiled) => compiled,
+        Err(code) => return code,
+    };
 
-    fn dummy() -> Self {
-        Token::new(TokenKind::Eof, 0, 0, 0, "")
-    }
+    let terminal = io::stdout().is_terminal();
+    let palette = if terminal && std::env::var_os("NO_COLOR").is_none() {
+        Palette::ANSI
+    } else {
+        Palette::PLAIN
+    };
+    // The script is listed under the file's name, then every function declared in it.
+    let listing: String = program_functions(&chunk)
+        .into_iter()
+        .enumerate()
+        .map(|(index, (function, chunk))| {
+            let title = if index == 0 { name.as_str() } else { &function };
+            Disassembler::new(chunk, palette).chunk(title)
+        })
+        .collect();
+
+    if terminal && listing.lines().count() > terminal_height() && page(&listing).is_ok() {
+        return ExitCode::SUCCESS;
+    }
+    print!("{}", listing);
+    ExitCode::SUCCESS
 }
 
-//
-// Scanner.
-//
-struct Scanner<'a> {
-    current: usize,
-    line: usize,
-    source: &'a str,
-    start: usize,
+// Rows on the terminal, as exported by the shell, or the traditional 24.
+fn terminal_height() -> usize {
+    std::env::var("LINES")
+        .ok()
+        .and_then(|lines| lines.parse().ok())
+        .unwrap_or(24)
 }
 
-impl<'a> Scanner<'a> {
-    // Creates a new [`Scanner`].
-    fn new(source: &'a str) -> Self {
-        Self {
-            current: 0,
-            line: 1,
-            start: 0,
-            source,
-        }
-    }
+// Show text through $PAGER, or `less -R` so colors survive.
+fn page(text: &str) -> io::Result<()> {
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less -R".to_string());
+    let mut child = std::process::Command::new("sh")
+        .args(["-c", &pager])
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+
+    // The pager may quit before reading everything, which is fine.
+    let _ = child.stdin.take().unwrap().write_all(text.as_bytes());
+    child.wait()?;
+    Ok(())
+}
 
-    fn skip_whitespace(&mut self) {
-        loop {
-            let c: char = self.peek();
-            match c {
-                // General whitespace.
-                ' ' | '\r' | '\t' => {
-                    self.advance();
-                }
-                // Handle newline.
-                '\n' => {
-                    self.line += 1;
-                    self.advance();
-                }
-                // Handle comments.
-                '/' => {
-                    if self.peek_offset(1) == '/' {
-                        // Ignore until newline or scanner exhausted.
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
-                    }
-                }
-                _ => return,
-            }
+// Compile a script and write its graphs to `out`.
+fn write_graphs(path: &str, out: &PathBuf) -> ExitCode {
+    let (chunk, name) = match compile_file(path) {
+        Ok(compiled) => compiled,
+        Err(code) => return code,
+    };
+
+    match std::fs::write(out, graphs_to_dot(&name, &chunk)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Could not write {}: {}", out.display(), err);
+            ExitCode::from(74)
         }
     }
+}
 
-    // Scan the next token.
-    fn scan_token(&mut self) -> Token<'a> {
-        self.skip_whitespace();
-        self.start = self.current;
-
-        if self.is_at_end() {
-            return self.make_token(TokenKind::Eof);
-        }
-
-        let c = self.advance();
-
-        if c.is_ascii_digit() {
-            return self.number();
-        } else if c.is_ascii_alphabetic() || c == '_' {
-            return self.identifer();
-        }
-
-        match c {
-            '(' => return self.make_token(TokenKind::LeftParen),
-            ')' => return self.make_token(TokenKind::RightParen),
-            '{' => return self.make_token(TokenKind::LeftBrace),
-            '}' => return self.make_token(TokenKind::RightBrace),
-            ';' => return self.make_token(TokenKind::Semicolon),
-            ',' => return self.make_token(TokenKind::Comma),
-            '.' => return self.make_token(TokenKind::Dot),
-            '-' => return self.make_token(TokenKind::Minus),
-            '+' => return self.make_token(TokenKind::Plus),
-            '/' => return self.make_token(TokenKind::Slash),
-            '*' => return self.make_token(TokenKind::Star),
-            '!' => {
-                if self.match_char('=') {
-                    return self.make_token(TokenKind::BangEqual);
-                } else {
-                    return self.make_token(TokenKind::Bang);
-                }
-            }
-            '=' => {
-                if self.match_char('=') {
-                    return self.make_token(TokenKind::EqualEqual);
-                } else {
-                    return self.make_token(TokenKind::Equal);
-                }
-            }
-            '<' => {
-                if self.match_char('=') {
-                    return self.make_token(TokenKind::LessEqual);
-                } else {
-                    return self.make_token(TokenKind::Less);
-                }
-            }
-            '>' => {
-                if self.match_char('=') {
-                    return self.make_token(TokenKind::GreaterEqual);
-                } else {
-                    return self.make_token(TokenKind::Greater);
-                }
-            }
-            '"' => {
-                return self.string();
-            }
-            _ => {}
-        }
+//
+// Usage report.
+//
 
-        return self.error_token("Unexpected character.");
-    }
+#[derive(serde::Serialize)]
+struct UsageReport<'a> {
+    wall_time_ms: f64,
+    // Peak resident memory of the whole process, where the platform reports it.
+    peak_memory_bytes: Option<u64>,
+    #[serde(flatten)]
+    usage: &'a Usage,
+}
 
-    // Advance to the next character.
-    fn advance(&mut self) -> char {
-        let c = self.peek();
-        self.current += 1;
-        c
-    }
+fn write_usage_report(path: &PathBuf, usage: &Usage, started: Instant) -> io::Result<()> {
+    let report = UsageReport {
+        wall_time_ms: started.elapsed().as_secs_f64() * 1000.0,
+        peak_memory_bytes: peak_memory_bytes(),
+        usage,
+    };
 
-    fn peek_offset(&self, idx: usize) -> char {
-        self.source.chars().nth(self.current + idx).unwrap_or('\0')
-    }
+    let json = serde_json::to_string_pretty(&report).map_err(io::Error::other)?;
+    std::fs::write(path, json + "\n")
+}
 
-    fn peek(&self) -> char {
-        self.peek_offset(0)
-    }
+// Read the high water mark of resident memory (Linux only).
+fn peak_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
 
-    fn match_char(&mut self, expected: char) -> bool {
-        if self.is_at_end() || self.peek() != expected {
-            false
-        } else {
-            self.current += 1;
-            true
+//
+// Run file.
+//
+fn run_file(vm: &mut VM, path: &str) -> Result<(), ExitCode> {
+    if let Ok(result) = vm.interpret_file(path) {
+        match result {
+            InterpretResult::CompileError => Err(ExitCode::from(65)),
+            InterpretResult::Ok => Ok(()),
+            InterpretResult::RuntimeError => Err(ExitCode::from(70)),
+            // 128 + SIGINT, as shells report a process stopped by Ctrl-C.
+            InterpretResult::Interrupted => Err(ExitCode::from(130)),
         }
+    } else {
+        // File not found.
+        error!("File at path not found: {}", path);
+        io::stdout().flush().unwrap();
+        Err(ExitCode::from(74))
     }
+}
 
-    // Returns true when the scanner is exhausted.
-    fn is_at_end(&self) -> bool {
-        self.current >= self.source.len()
-    }
+//
+// REPL.
+//
+// Errors are reported and the session carries on, unless `fail_fast` is set.
+fn run_repl(vm: &mut VM, config: &Config, fail_fast: bool) -> ExitCode {
+    // Expressions registered with `:watch`, re-evaluated after every input.
+    let mut watches: Vec<String> = vec![];
 
-    // Create a new token of given kind.
-    fn make_token(&self, tty: TokenKind) -> Token<'a> {
-        Token::new(
-            tty,
-            self.start,
-            self.current - self.start,
-            self.line,
-            self.source,
-        )
+    // Ctrl-C cancels the running evaluation instead of killing the session.
+    let handle = vm.handle();
+    if let Err(err) = ctrlc::set_handler(move || handle.interrupt()) {
+        eprintln!("Could not install the Ctrl-C handler: {}", err);
     }
 
-    // Create a new error token with the specific message.
-    fn error_token(&self, message: &'static str) -> Token<'a> {
-        Token {
-            kind: TokenKind::Error,
-            start: self.start,
-            length: self.current - self.start,
-            line: self.line,
-            source: message,
-        }
-    }
+    print!("{}", config.prompt);
+    io::stdout().flush().unwrap();
 
-    fn string(&mut self) -> Token<'a> {
-        while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
-            }
-            _ = self.advance();
-        }
+    // Reading stops at end of input (Ctrl-D).
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line.unwrap();
 
-        if self.peek() != '"' {
-            self.error_token("Unterminated string")
+        if let Some(expression) = line.strip_prefix(":watch ") {
+            watches.push(expression.trim().to_string());
+        } else if line.trim() == ":unwatch" {
+            watches.clear();
         } else {
-            _ = self.advance();
-            self.make_token(TokenKind::String)
-        }
-    }
-
-    fn number(&mut self) -> Token<'a> {
-        while self.peek().is_ascii_digit() {
-            _ = self.advance();
-        }
-
-        // Handle fraction.
-        if self.peek() == '.' {
-            self.advance();
-
-            while self.peek().is_ascii_digit() {
-                _ = self.advance();
+            match vm.interpret(&line) {
+                InterpretResult::CompileError if fail_fast => return ExitCode::from(65),
+                InterpretResult::RuntimeError if fail_fast => return ExitCode::from(70),
+                InterpretResult::Interrupted => eprintln!("Interrupted."),
+                _ => {}
             }
         }
 
-        self.make_token(TokenKind::Number)
-    }
-
-    fn identifer(&mut self) -> Token<'a> {
-        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
-            self.advance();
-        }
-        self.make_token(self.identifer_type())
-    }
-
-    fn identifer_type(&self) -> TokenKind {
-        match self.source.chars().nth(self.start).unwrap() {
-            'a' => return self.check_keyword(1, "nd", TokenKind::And),
-            'c' => return self.check_keyword(1, "lass", TokenKind::Class),
-            'e' => return self.check_keyword(1, "lse", TokenKind::Else),
-            'f' => {
-                if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
-                        'a' => return self.check_keyword(2, "lse", TokenKind::False),
-                        'o' => return self.check_keyword(2, "r", TokenKind::For),
-                        'u' => return self.check_keyword(2, "n", TokenKind::Fun),
-                        _ => {}
-                    }
-                }
-            }
-            't' => {
-                if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
-                        'h' => return self.check_keyword(2, "is", TokenKind::This),
-                        'r' => return self.check_keyword(2, "ue", TokenKind::True),
-                        _ => {}
-                    }
-                }
-            }
-            'i' => return self.check_keyword(1, "f", TokenKind::If),
-            'n' => return self.check_keyword(1, "il", TokenKind::Nil),
-            'o' => return self.check_keyword(1, "r", TokenKind::Or),
-            'p' => return self.check_keyword(1, "rint", TokenKind::Print),
-            'r' => return self.check_keyword(1, "eturn", TokenKind::Return),
-            's' => return self.check_keyword(1, "uper", TokenKind::Super),
-            'v' => return self.check_keyword(1, "ar", TokenKind::Var),
-            'w' => return self.check_keyword(1, "hile", TokenKind::While),
-            _ => {}
-        }
-
-        TokenKind::Identifier
+        print_watches(vm, &watches);
+        print!("{}", config.prompt);
+        io::stdout().flush().unwrap();
     }
+    ExitCode::SUCCESS
+}
 
-    fn check_keyword(&self, offset: usize, expected: &str, kind: TokenKind) -> TokenKind {
-        let keyword_found =
-            &self.source[self.start + offset..self.start + offset + expected.len()] == expected;
-
-        let length_matches = (self.current - self.start) == expected.len() + offset;
-
-        if keyword_found && length_matches {
-            kind
-        } else {
-            TokenKind::Identifier
+// Print the current value of each watch expression.
+fn print_watches(vm: &mut VM, watches: &[String]) {
+    for watch in watches {
+        match vm.execute(watch) {
+            Ok(value) => println!("  {} = {}", watch, value),
+            Err(_) => println!("  {} = <error>", watch),
         }
     }
 }
 
 //
-// The parser.
+// Remote session.
 //
-struct Parser<'a> {
-    scanner: Scanner<'a>,
-    current: Token<'a>,
-    previous: Token<'a>,
-    had_error: bool,
-    chunk: Chunk,
-
-    // Flag for sane error reporting.
-    // Resync the state of the parser.
-    panic: bool,
-}
-
-impl<'a> Parser<'a> {
-    fn new(source: &'a str) -> Self {
-        Self {
-            scanner: Scanner::new(source),
-            current: Token::dummy(),
-            previous: Token::dummy(),
-            had_error: false,
-            panic: false,
-            chunk: Chunk::new(),
-        }
-    }
 
-    fn advance(&mut self) {
-        self.previous = self.current;
-
-        loop {
-            self.current = self.scanner.scan_token();
-            if self.current.kind != TokenKind::Error {
-                break;
-            }
-
-            let lexeme = self.current.lexeme();
-            self.report_error_at_current(lexeme);
-        }
+// JSON-RPC 2.0 error codes.
+const RPC_PARSE_ERROR: i64 = -32700;
+const RPC_INVALID_REQUEST: i64 = -32600;
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+const RPC_INVALID_PARAMS: i64 = -32602;
+// Application errors, in the range JSON-RPC leaves to servers.
+const RPC_COMPILE_ERROR: i64 = -32001;
+const RPC_RUNTIME_ERROR: i64 = -32002;
+const RPC_INTERRUPTED: i64 = -32003;
+
+// Serve requests from stdin until it closes, so an editor or notebook can drive a live session.
+//
+// Each line is a JSON-RPC 2.0 request and each gets one line of response on stdout:
+//
+//   {"jsonrpc":"2.0","id":1,"method":"run","params":{"source":"var a = 1; print a;"}}
+//   {"jsonrpc":"2.0","id":1,"result":{"output":"1\n"}}
+//
+// `run` executes statements, `evaluate` an expression and also returns its value. Lines holding
+// a Jupyter message are answered with Jupyter messages instead, see `Kernel`. A failed request
+// gets an error whose `data` holds the text the failure reported:
+//
+//   {"jsonrpc":"2.0","id":2,"method":"evaluate","params":{"source":"-nil"}}
+//   {"jsonrpc":"2.0","id":2,"error":{"code":-32002,"message":"Runtime error.","data":"..."}}
+fn run_remote(vm: &mut VM) -> ExitCode {
+    let handle = vm.handle();
+    if let Err(err) = ctrlc::set_handler(move || handle.interrupt()) {
+        eprintln!("Could not install the Ctrl-C handler: {}", err);
     }
 
-    fn consume(&mut self, kind: TokenKind, message: &str) {
-        let got_expected = self.current.kind == kind;
+    // Requests arrive on stdin, so scripts must not read from it.
+    vm.set_input(io::empty());
 
-        if got_expected {
-            self.advance();
-        } else {
-            self.report_error_at_current(message);
-        }
-    }
+    // The session ends with its input, or once replies can't be written.
+    let _ = serve(vm, io::stdin().lock(), io::stdout());
+    ExitCode::SUCCESS
+}
 
-    fn report_error_at(&mut self, token: Token, message: &str) {
-        if self.panic {
-            return;
+// Answer the JSON-RPC requests and Jupyter messages read from `reader`, one per line, until it
+// ends.
+fn serve(vm: &mut VM, reader: impl BufRead, mut writer: impl Write) -> io::Result<()> {
+    let mut kernel = Kernel::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
         }
 
-        self.panic = true;
-
-        eprint!("[line {}] Error", token.line);
-
-        if token.kind == TokenKind::Eof {
-            eprint!(" at end");
-        } else if token.kind == TokenKind::Error {
-            // Do nothing.
-        } else {
-            eprint!(" at {}", token.lexeme());
+        let responses = match serde_json::from_str::<serde_json::Value>(&line) {
+            Ok(message) if message.get("header").is_some() => kernel.handle(vm, &message),
+            _ => handle_remote_request(vm, &line).into_iter().collect(),
+        };
+        for response in responses {
+            writeln!(writer, "{}", response)?;
         }
-
-        // Print error message
-        eprintln!(": {}", message);
-
-        self.had_error = true;
-    }
-
-    fn report_error_at_current(&mut self, message: &str) {
-        let token = self.current;
-        self.report_error_at(token, message);
+        writer.flush()?;
     }
+    Ok(())
+}
 
-    #[allow(dead_code)]
-    fn report_error(&mut self, message: &str) {
-        let token = self.previous;
-        self.report_error_at(token, message);
-    }
-
-    #[allow(dead_code)]
-    fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write(byte, self.previous.line as i32);
-    }
-
-    fn emit_opcode(&mut self, opcode: Opcode) {
-        self.emit_byte(opcode as u8);
-    }
-
-    #[allow(dead_code)]
-    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
-        self.emit_byte(byte1);
-        self.emit_byte(byte2);
-    }
-
-    #[allow(dead_code)]
-    fn end(&mut self) {
-        self.emit_opcode(Opcode::Return);
-
-        if cfg!(debug_assetions) && !self.had_error {
-            self.chunk.disassemble_chunk("code");
+// A preload's path and source text, read once for every daemon session.
+#[cfg(unix)]
+type PreloadSource = (String, String);
+
+// A compiled preload, with the path it was loaded from.
+#[cfg(unix)]
+type Preload = (String, Rc<Function>);
+
+// Keep a warm process for editors and test runners: each connection to `socket` is served on its
+// own thread, by a fresh VM which has already run the preloads.
+#[cfg(unix)]
+fn run_daemon(socket: &std::path::Path, paths: &[String], max_call_depth: usize) -> ExitCode {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    let sources = match read_preloads(paths) {
+        Ok(sources) => sources,
+        Err(code) => return code,
+    };
+    // Fail now rather than in every session.
+    if let Err(code) =
+        compile_preloads(&sources).and_then(|preloads| daemon_session(&preloads, max_call_depth))
+    {
+        return code;
+    }
+
+    // A socket nobody answers on was left behind by a daemon which is gone.
+    if UnixStream::connect(socket).is_err() {
+        let _ = std::fs::remove_file(socket);
+    }
+    let listener = match UnixListener::bind(socket) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Could not listen on {}: {}", socket.display(), err);
+            return ExitCode::from(74);
         }
-    }
-
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
-    }
-
-    #[allow(dead_code)]
-    // Convert lexeme into numerical value, then push the value into the constant array.
-    fn number(&mut self) {
-        let value: f32 = self.previous.lexeme().parse::<f32>().unwrap();
-        self.emit_constant(Value::Number(value));
-    }
+    };
 
-    // Note: This function assumes that the '(' has already been consumed.
-    fn grouping(&mut self) {
-        self.expression();
-        self.consume(TokenKind::RightParen, "Expect ')' after expression.");
-    }
-
-    fn unary(&mut self) {
-        let token_type: TokenKind = self.previous.kind;
-
-        // Collect / compile the operand.
-        self.parse_precedence(Precedence::Unary);
+    serve_connections(listener.incoming().flatten(), sources, max_call_depth);
+    ExitCode::SUCCESS
+}
 
-        // Emit the instruction based on the token type.
-        if TokenKind::Minus == token_type {
-            self.emit_opcode(Opcode::Negate);
-        } else if token_type == TokenKind::Bang {
-            self.emit_opcode(Opcode::Not);
+// Hand each connection to a worker thread, starting a new worker whenever all are busy.
+//
+// Compiled code holds `Rc`s and can't move between threads, so rather than starting a thread per
+// connection, which would compile the preloads each time, workers compile them once and then
+// serve one connection after another.
+#[cfg(unix)]
+fn serve_connections(
+    connections: impl Iterator<Item = std::os::unix::net::UnixStream>,
+    sources: Vec<PreloadSource>,
+    max_call_depth: usize,
+) {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    };
+
+    let (sender, receiver) = mpsc::channel();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let sources = Arc::new(sources);
+    // Workers free to take a connection which hasn't been handed to one yet.
+    let idle = Arc::new(AtomicUsize::new(0));
+
+    for stream in connections {
+        let claimed = idle
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |idle| {
+                idle.checked_sub(1)
+            })
+            .is_ok();
+        if !claimed {
+            let (receiver, sources, idle) = (receiver.clone(), sources.clone(), idle.clone());
+            std::thread::spawn(move || {
+                let Ok(preloads) = compile_preloads(&sources) else {
+                    return;
+                };
+                loop {
+                    // The lock is only held while waiting, so the other workers keep serving.
+                    let stream = receiver.lock().unwrap().recv();
+                    let Ok(stream) = stream else { return };
+                    serve_connection(stream, &preloads, max_call_depth);
+                    idle.fetch_add(1, Ordering::SeqCst);
+                }
+            });
         }
+        // A worker holds the receiver until the daemon exits.
+        let _ = sender.send(stream);
     }
+}
 
-    fn binary(&mut self) {
-        let operator_type = self.previous.kind;
-
-        let rule = self.get_rule(operator_type);
-
-        self.parse_precedence(Precedence::from_u8(rule.precedence as u8 + 1).unwrap());
-
-        match operator_type {
-            TokenKind::Plus => self.emit_opcode(Opcode::Add),
-            TokenKind::Minus => self.emit_opcode(Opcode::Subtract),
-            TokenKind::Star => self.emit_opcode(Opcode::Multiply),
-            TokenKind::Slash => self.emit_opcode(Opcode::Divide),
-            TokenKind::BangEqual => self.emit_bytes(Opcode::Equal as u8, Opcode::Not as u8),
-            TokenKind::EqualEqual => self.emit_opcode(Opcode::Equal),
-            TokenKind::Greater => self.emit_opcode(Opcode::Greater),
-            TokenKind::GreaterEqual => self.emit_bytes(Opcode::Less as u8, Opcode::Not as u8),
-            TokenKind::Less => self.emit_opcode(Opcode::Less),
-            TokenKind::LessEqual => self.emit_bytes(Opcode::Greater as u8, Opcode::Not as u8),
-            _ => unreachable!(),
-        }
-    }
+// Serve one connection until the client closes it.
+#[cfg(unix)]
+fn serve_connection(
+    stream: std::os::unix::net::UnixStream,
+    preloads: &[Preload],
+    max_call_depth: usize,
+) {
+    let Ok(reader) = stream.try_clone() else {
+        return;
+    };
+    let Ok(mut vm) = daemon_session(preloads, max_call_depth) else {
+        return;
+    };
+    let _ = serve(&mut vm, io::BufReader::new(reader), stream);
+}
 
-    // Emit constant pushes two things onto the stack.
-    // - Opcode::Constant
-    // - The index which the constant lives in the constant array.
-    #[allow(dead_code)]
-    fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(Opcode::Constant as u8, constant);
-    }
+// Read the preloads, to be compiled by each worker.
+#[cfg(unix)]
+fn read_preloads(paths: &[String]) -> Result<Vec<PreloadSource>, ExitCode> {
+    paths
+        .iter()
+        .map(|path| match std::fs::read_to_string(path) {
+            Ok(source) => Ok((path.clone(), source)),
+            Err(_) => {
+                eprintln!("Could not read {}.", path);
+                Err(ExitCode::from(74))
+            }
+        })
+        .collect()
+}
 
-    // Creates a new constant and return the index which it lives inside the value array.
-    #[allow(dead_code)]
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.chunk.add_constant(value);
+// Compile the preloads without running them.
+#[cfg(unix)]
+fn compile_preloads(sources: &[PreloadSource]) -> Result<Vec<Preload>, ExitCode> {
+    let mut registry = SourceRegistry::default();
+    sources
+        .iter()
+        .map(|(path, source)| {
+            let file = registry.register(path);
+            let chunk = Compiler::for_file(source, file, &registry)
+                .compile()
+                .ok_or(ExitCode::from(65))?;
+            Ok((path.clone(), Rc::new(Function::script(chunk))))
+        })
+        .collect()
+}
 
-        if constant > std::u8::MAX as u16 {
-            self.report_error("Too many constants in one chunk.");
-            0
-        } else {
-            constant as u8
+// A VM for one daemon connection, with the preloads run.
+#[cfg(unix)]
+fn daemon_session(preloads: &[Preload], max_call_depth: usize) -> Result<VM, ExitCode> {
+    let mut vm = VM::new(Chunk::new());
+    vm.max_call_depth = max_call_depth;
+    // Requests arrive on the connection, so scripts must not read from it.
+    vm.set_input(io::empty());
+
+    for (path, script) in preloads {
+        // Registered in the order they were compiled in, so the file ids in their chunks match.
+        vm.sources.register(path);
+        vm.usage.files.push(path.clone());
+        if vm.run_script(script.clone()).is_err() {
+            return Err(ExitCode::from(70));
         }
     }
+    Ok(vm)
+}
 
-    fn get_rule(&mut self, operator_type: TokenKind) -> ParseRule {
-        let empty_rule = ParseRule {
-            prefix: None,
-            infix: None,
-            precedence: Precedence::None,
-        };
-        match operator_type {
-            TokenKind::LeftParen => ParseRule {
-                prefix: Some(Box::new(|this| this.grouping())),
-                precedence: Precedence::None,
-                ..empty_rule
-            },
-            TokenKind::RightParen => empty_rule,
-            TokenKind::LeftBrace => empty_rule,
-            TokenKind::RightBrace => empty_rule,
-            TokenKind::Comma => empty_rule,
-            TokenKind::Dot => empty_rule,
-            TokenKind::Minus => ParseRule {
-                prefix: Some(Box::new(|this| this.unary())),
-                infix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Term,
-            },
-            TokenKind::Plus => ParseRule {
-                infix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Term,
-                ..empty_rule
-            },
-            TokenKind::Semicolon => empty_rule,
-            TokenKind::Slash => ParseRule {
-                infix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Factor,
-                ..empty_rule
-            },
-            TokenKind::Star => ParseRule {
-                infix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Factor,
-                ..empty_rule
-            },
-            TokenKind::Bang => ParseRule {
-                prefix: Some(Box::new(|this| this.unary())),
-                ..empty_rule
-            },
-            TokenKind::BangEqual => ParseRule {
-                prefix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Equality,
-                ..empty_rule
-            },
-            TokenKind::Equal => empty_rule,
-            TokenKind::EqualEqual => ParseRule {
-                infix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Equality,
-                ..empty_rule
-            },
-            TokenKind::Greater => ParseRule {
-                infix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Comparison,
-                ..empty_rule
-            },
-            TokenKind::GreaterEqual => ParseRule {
-                infix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Comparison,
-                ..empty_rule
-            },
-            TokenKind::Less => ParseRule {
-                infix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Comparison,
-                ..empty_rule
-            },
-            TokenKind::LessEqual => ParseRule {
-                infix: Some(Box::new(|this| this.binary())),
-                precedence: Precedence::Comparison,
-                ..empty_rule
-            },
-            TokenKind::Identifier => empty_rule,
-            TokenKind::String => ParseRule {
-                prefix: Some(Box::new(|this| this.string())),
-                ..empty_rule
-            },
-            TokenKind::Number => ParseRule {
-                prefix: Some(Box::new(|this| this.number())),
-                ..empty_rule
-            },
-            TokenKind::And => empty_rule,
-            TokenKind::Class => empty_rule,
-            TokenKind::Else => empty_rule,
-            TokenKind::False => ParseRule {
-                prefix: Some(Box::new(|this| this.literal())),
-                ..empty_rule
-            },
-            TokenKind::For => empty_rule,
-            TokenKind::Fun => empty_rule,
-            TokenKind::If => empty_rule,
-            TokenKind::Nil => ParseRule {
-                prefix: Some(Box::new(|this| this.literal())),
-                ..empty_rule
-            },
-            TokenKind::Or => empty_rule,
-            TokenKind::Print => empty_rule,
-            TokenKind::Return => empty_rule,
-            TokenKind::Super => empty_rule,
-            TokenKind::This => empty_rule,
-            TokenKind::True => ParseRule {
-                prefix: Some(Box::new(|this| this.literal())),
-                ..empty_rule
-            },
-            TokenKind::Var => empty_rule,
-            TokenKind::While => empty_rule,
-            TokenKind::Error => empty_rule,
-            TokenKind::Eof => empty_rule,
-        }
-    }
-
-    fn parse_precedence(&mut self, precedence: Precedence) {
-        self.advance();
-
-        // Retrieve the prefix rule for the given token kind.
-        // We expect this to return a valid rule because if it
-        // does not then we have an incorrect first token.
-        // For instance, an expression can not start with 'else' or '}'.
-        let prefix_rule = self.get_rule(self.previous.kind).prefix;
-
-        if let Some(rule) = prefix_rule {
-            rule(self);
-        } else {
-            self.report_error("Expect expression.");
-            return;
-        }
-
-        while (precedence as u8) <= (self.get_rule(self.current.kind).precedence as u8) {
-            self.advance();
-            let infix_rule = self.get_rule(self.previous.kind).infix.unwrap();
-            infix_rule(self);
+// Handle one request line, returning the response unless the request was a notification.
+fn handle_remote_request(vm: &mut VM, line: &str) -> Option<serde_json::Value> {
+    use serde_json::{json, Value as Json};
+
+    let request: Json = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => return Some(rpc_error(Json::Null, RPC_PARSE_ERROR, &err.to_string())),
+    };
+
+    // Requests without an id are notifications and get no reply.
+    let notification = request.get("id").is_none();
+    let reply = |response: Json| (!notification).then_some(response);
+    let id = request.get("id").cloned().unwrap_or(Json::Null);
+
+    let Some(method) = request.get("method").and_then(Json::as_str) else {
+        return reply(rpc_error(id, RPC_INVALID_REQUEST, "Expect a method."));
+    };
+    let mode = match method {
+        "run" => CompileMode::Script,
+        "evaluate" => CompileMode::Expression,
+        _ => {
+            let message = format!("Unknown method '{}'.", method);
+            return reply(rpc_error(id, RPC_METHOD_NOT_FOUND, &message));
         }
-    }
-
-    fn literal(&mut self) {
-        match self.previous.kind {
-            TokenKind::True => self.emit_opcode(Opcode::True),
-            TokenKind::False => self.emit_opcode(Opcode::False),
-            TokenKind::Nil => self.emit_opcode(Opcode::Nil),
-            _ => unreachable!(),
+    };
+    let Some(source) = request.pointer("/params/source").and_then(Json::as_str) else {
+        return reply(rpc_error(
+            id,
+            RPC_INVALID_PARAMS,
+            "Expect a 'source' string.",
+        ));
+    };
+
+    vm.capture_output();
+    vm.capture_errors();
+    let result = vm.run_source(source, mode, 0);
+    let output = vm.take_output();
+    let errors = vm.take_errors();
+
+    let mut response = match result {
+        Ok(value) => {
+            let mut result = json!({ "output": output });
+            if let CompileMode::Expression = mode {
+                result["value"] = json!(value.to_string());
+            }
+            json!({ "jsonrpc": "2.0", "id": id, "result": result })
         }
-    }
-
-    fn string(&mut self) {
-        self.emit_constant(Value::String({
-            let mut chars = self.previous.lexeme().chars();
-
-            // Get rid of pre/postfix '"'.
-            chars.next();
-            chars.next_back();
-
-            chars.into_iter().collect::<String>()
-        }))
-    }
+        Err(InterpretResult::CompileError) => rpc_error(id, RPC_COMPILE_ERROR, "Compile error."),
+        Err(InterpretResult::Interrupted) => rpc_error(id, RPC_INTERRUPTED, "Interrupted."),
+        Err(_) => rpc_error(id, RPC_RUNTIME_ERROR, "Runtime error."),
+    };
+    // The messages and stack trace go with the error, since the client can't see stderr.
+    if let Some(error) = response.get_mut("error") {
+        error["data"] = json!(errors);
+    }
+    reply(response)
 }
 
-type ParseFn = Box<dyn Fn(&mut Parser)>;
-
-//
-// Parse rule.
-//
-struct ParseRule {
-    prefix: Option<ParseFn>,
-    infix: Option<ParseFn>,
-    precedence: Precedence,
+fn rpc_error(id: serde_json::Value, code: i64, message: &str) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
 }
 
 //
-// The compiler.
+// Jupyter messaging.
 //
-struct Compiler<'a> {
-    parser: Parser<'a>,
+
+// The version of the Jupyter messaging protocol we speak.
+const JUPYTER_PROTOCOL_VERSION: &str = "5.3";
+
+/// Answers Jupyter messages so Lox can run in notebooks.
+///
+/// Messages use the JSON form of the Jupyter websocket protocol, where each message names the
+/// channel it belongs to. Replies go out on `shell`, while status changes, echoed input and
+/// printed output are broadcast on `iopub`. Relaying them to and from the ZeroMQ sockets of a
+/// real kernel connection is left to the bridge that spawns `--remote`.
+struct Kernel {
+    session: String,
+    // Messages sent so far, used to give each one a unique id.
+    sent: u64,
+    // Incremented by every execute_request which is not silent.
+    execution_count: u64,
 }
 
-impl<'a> Compiler<'a> {
-    fn new(source: &'a str) -> Self {
+impl Kernel {
+    fn new() -> Self {
         Self {
-            parser: Parser::new(source),
+            session: format!("lox-{}", std::process::id()),
+            sent: 0,
+            execution_count: 0,
         }
     }
 
-    fn compile(&mut self) -> Option<Chunk> {
-        self.parser.advance();
-        self.parser.expression();
-        self.parser
-            .consume(TokenKind::Eof, "Expected end of expression.");
-        self.parser.end();
+    // Handle one message, returning the messages it produced in the order they should be sent.
+    fn handle(&mut self, vm: &mut VM, request: &serde_json::Value) -> Vec<serde_json::Value> {
+        use serde_json::json;
+
+        let msg_type = request.pointer("/header/msg_type").and_then(|t| t.as_str());
+        let busy = json!({ "execution_state": "busy" });
+        let mut messages = vec![self.message(request, "iopub", "status", busy)];
+
+        match msg_type {
+            Some("kernel_info_request") => {
+                let content = json!({
+                    "status": "ok",
+                    "protocol_version": JUPYTER_PROTOCOL_VERSION,
+                    "implementation": "rust-lox",
+                    "implementation_version": env!("CARGO_PKG_VERSION"),
+                    "language_info": {
+                        "name": "lox",
+                        "version": env!("CARGO_PKG_VERSION"),
+                        "mimetype": "text/x-lox",
+                        "file_extension": ".lox",
+                    },
+                    "banner": "Lox",
+                });
+                messages.push(self.message(request, "shell", "kernel_info_reply", content));
+            }
+            Some("execute_request") => self.execute(vm, request, &mut messages),
+            Some("complete_request") => self.complete(vm, request, &mut messages),
+            // Requests we do not implement are ignored, as the protocol allows.
+            _ => {}
+        }
 
-        if self.parser.had_error {
-            None
-        } else {
-            Some(mem::take(&mut self.parser.chunk))
+        let idle = json!({ "execution_state": "idle" });
+        messages.push(self.message(request, "iopub", "status", idle));
+        messages
+    }
+
+    fn execute(
+        &mut self,
+        vm: &mut VM,
+        request: &serde_json::Value,
+        messages: &mut Vec<serde_json::Value>,
+    ) {
+        use serde_json::json;
+
+        let code = request
+            .pointer("/content/code")
+            .and_then(|c| c.as_str())
+            .unwrap_or("");
+        let silent = request
+            .pointer("/content/silent")
+            .and_then(|s| s.as_bool())
+            .unwrap_or(false);
+
+        if !silent {
+            self.execution_count += 1;
+            let content = json!({ "code": code, "execution_count": self.execution_count });
+            messages.push(self.message(request, "iopub", "execute_input", content));
         }
-    }
-}
 
-//
-// Virtual Machine.
-//
+        vm.capture_output();
+        vm.capture_errors();
+        let result = vm.interpret(code);
+        let output = vm.take_output();
+        let errors = vm.take_errors();
 
-// The InterpretResult enum symbolises the state of the compiler result.
-enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
-}
+        if !silent && !output.is_empty() {
+            let content = json!({ "name": "stdout", "text": output });
+            messages.push(self.message(request, "iopub", "stream", content));
+        }
 
-// The max size of the stack.
-const STACK_MAX: usize = 256;
+        let reply = match result {
+            InterpretResult::Ok => {
+                json!({ "status": "ok", "execution_count": self.execution_count })
+            }
+            error => {
+                let (ename, fallback) = match error {
+                    InterpretResult::CompileError => ("CompileError", "Compile error."),
+                    InterpretResult::Interrupted => ("Interrupted", "Interrupted."),
+                    _ => ("RuntimeError", "Runtime error."),
+                };
+                // The first line is the runtime error's message or the first compile error, and
+                // the rest are the stack trace or further compile errors.
+                let traceback: Vec<&str> = errors.lines().collect();
+                let evalue = traceback.first().copied().unwrap_or(fallback);
+                let content = json!({ "ename": ename, "evalue": evalue, "traceback": traceback });
+                if !silent {
+                    messages.push(self.message(request, "iopub", "error", content.clone()));
+                }
 
-// The virtual machine (VM) is responsible for interpreting bytecode chunks and mutating internal state accordingly.
-struct VM {
-    // Bytecode chunks.
-    chunk: Chunk,
+                let mut reply = content;
+                reply["status"] = json!("error");
+                reply["execution_count"] = json!(self.execution_count);
+                reply
+            }
+        };
+        messages.push(self.message(request, "shell", "execute_reply", reply));
+    }
+
+    fn complete(
+        &mut self,
+        vm: &VM,
+        request: &serde_json::Value,
+        messages: &mut Vec<serde_json::Value>,
+    ) {
+        let code = request
+            .pointer("/content/code")
+            .and_then(|c| c.as_str())
+            .unwrap_or("");
+        // Jupyter counts positions in code points, not bytes.
+        let length = code.chars().count();
+        let cursor_pos = request
+            .pointer("/content/cursor_pos")
+            .and_then(|p| p.as_u64())
+            .map_or(length, |p| (p as usize).min(length));
+        let cursor = code
+            .char_indices()
+            .nth(cursor_pos)
+            .map_or(code.len(), |(offset, _)| offset);
+
+        let completions = vm.complete(code, cursor);
+        let content = serde_json::json!({
+            "status": "ok",
+            "matches": completions.matches,
+            "cursor_start": code[..completions.start].chars().count(),
+            "cursor_end": cursor_pos,
+            "metadata": {},
+        });
+        messages.push(self.message(request, "shell", "complete_reply", content));
+    }
+
+    // Build a message sent in response to `parent`.
+    fn message(
+        &mut self,
+        parent: &serde_json::Value,
+        channel: &str,
+        msg_type: &str,
+        content: serde_json::Value,
+    ) -> serde_json::Value {
+        self.sent += 1;
+        serde_json::json!({
+            "channel": channel,
+            "header": {
+                "msg_id": format!("{}-{}", self.session, self.sent),
+                "session": self.session,
+                "username": "kernel",
+                "msg_type": msg_type,
+                "version": JUPYTER_PROTOCOL_VERSION,
+            },
+            "parent_header": parent.get("header").cloned().unwrap_or_default(),
+            "metadata": {},
+            "content": content,
+        })
+    }
+}
 
-    // Instruction pointer.
-    ip: usize,
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Stack.
-    stack: Vec<Value>,
-}
+    // Testing the remote session.
 
-impl VM {
-    // Return a new virtual machine instance.
-    fn new(chunk: Chunk) -> Self {
-        Self {
-            chunk,
-            ip: 0,
-            stack: Vec::with_capacity(STACK_MAX),
-        }
+    fn remote(vm: &mut VM, line: &str) -> serde_json::Value {
+        handle_remote_request(vm, line).unwrap()
     }
 
-    // Push a new value onto the stack.
-    fn push(&mut self, value: Value) {
-        self.stack.push(value);
-    }
+    #[test]
+    fn test_remote_session_persists() {
+        let mut vm = VM::new(Chunk::new());
+
+        let response = remote(
+            &mut vm,
+            r#"{"jsonrpc":"2.0","id":1,"method":"run","params":{"source":"var a = 2; print a;"}}"#,
+        );
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["output"], "2\n");
 
-    // Pop and return value from the stack.
-    fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
+        let response = remote(
+            &mut vm,
+            r#"{"jsonrpc":"2.0","id":2,"method":"evaluate","params":{"source":"a * 3"}}"#,
+        );
+        assert_eq!(response["result"]["value"], "6");
     }
 
-    // Interpret source code. Return Interpret result which symbolizes the success state.
-    #[allow(unused_variables)]
-    fn interpret(&mut self, source: &str) -> InterpretResult {
-        let mut compiler = Compiler::new(source);
+    #[test]
+    fn test_remote_errors() {
+        let mut vm = VM::new(Chunk::new());
 
-        let chunk = compiler.compile();
+        assert_eq!(remote(&mut vm, "{")["error"]["code"], RPC_PARSE_ERROR);
 
-        if chunk.is_none() {
-            return InterpretResult::CompileError;
-        }
+        let response = remote(&mut vm, r#"{"jsonrpc":"2.0","id":1,"method":"nope"}"#);
+        assert_eq!(response["error"]["code"], RPC_METHOD_NOT_FOUND);
 
-        // Take the compiled chunk.
-        self.chunk = chunk.unwrap();
+        let response = remote(&mut vm, r#"{"jsonrpc":"2.0","id":1,"method":"run"}"#);
+        assert_eq!(response["error"]["code"], RPC_INVALID_PARAMS);
 
-        self.ip = 0;
+        let response = remote(
+            &mut vm,
+            r#"{"jsonrpc":"2.0","id":1,"method":"run","params":{"source":"print -nil;"}}"#,
+        );
+        assert_eq!(response["error"]["code"], RPC_RUNTIME_ERROR);
+        let data = response["error"]["data"].as_str().unwrap();
+        assert!(data.contains("Operand must be a number."));
 
-        self.run(false)
-    }
+        let response = remote(
+            &mut vm,
+            r#"{"jsonrpc":"2.0","id":1,"method":"run","params":{"source":"print;"}}"#,
+        );
+        assert_eq!(response["error"]["code"], RPC_COMPILE_ERROR);
+        let data = response["error"]["data"].as_str().unwrap();
+        assert!(data.contains("Expect expression."));
 
-    // Interpret the next byte as an opcode.
-    fn read_instruction(&mut self) -> Option<Opcode> {
-        FromPrimitive::from_u8(self.read_byte())
+        // Notifications get no response.
+        let notification = r#"{"jsonrpc":"2.0","method":"run","params":{"source":"1;"}}"#;
+        assert!(handle_remote_request(&mut vm, notification).is_none());
     }
 
-    // Read the current byte and increment onto the next.
-    fn read_byte(&mut self) -> u8 {
-        let instruction: u8 = self.chunk.code[self.ip];
-        self.ip += 1;
-        instruction
-    }
+    #[cfg(unix)]
+    #[test]
+    fn test_daemon_sessions() {
+        let dir = std::env::temp_dir().join(format!("rustlox-daemon-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let prelude = dir.join("prelude.lox");
+        let broken = dir.join("broken.lox");
+        std::fs::write(&prelude, "fun double(n) { return n * 2; }").unwrap();
+        std::fs::write(&broken, "fun (").unwrap();
+        let prelude = prelude.to_str().unwrap().to_string();
+
+        let sources = read_preloads(std::slice::from_ref(&prelude)).unwrap();
+        let preloads = compile_preloads(&sources).unwrap();
+        let mut vm = daemon_session(&preloads, DEFAULT_MAX_CALL_DEPTH).unwrap();
+        let requests = concat!(
+            r#"{"jsonrpc":"2.0","id":1,"method":"run","params":{"source":"var kept = 1;"}}"#,
+            "\n\n",
+            r#"{"jsonrpc":"2.0","id":2,"method":"evaluate","params":{"source":"double(21)"}}"#,
+        );
+        let mut replies = vec![];
+        serve(&mut vm, io::Cursor::new(requests), &mut replies).unwrap();
+        let replies = String::from_utf8(replies).unwrap();
+        assert_eq!(replies.lines().count(), 2);
+        assert!(replies.contains(r#""value":"42""#));
+
+        // Every session starts from the preloads alone.
+        let mut vm = daemon_session(&preloads, DEFAULT_MAX_CALL_DEPTH).unwrap();
+        assert!(matches!(
+            vm.execute("kept"),
+            Err(InterpretResult::RuntimeError)
+        ));
+        assert_eq!(vm.execute("double(2)").ok().unwrap().to_string(), "4");
+
+        let broken = broken.to_str().unwrap().to_string();
+        let sources = read_preloads(&[prelude, broken]).unwrap();
+        assert!(compile_preloads(&sources).is_err());
+        assert!(read_preloads(&["missing.lox".to_string()]).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_daemon_serves_connections_at_once() {
+        use std::os::unix::net::UnixStream;
+
+        let sources = vec![(
+            "prelude.lox".to_string(),
+            "fun double(n) { return n * 2; }".to_string(),
+        )];
+        let (idle_client, idle_server) = UnixStream::pair().unwrap();
+        let (client, server) = UnixStream::pair().unwrap();
+        serve_connections(
+            [idle_server, server].into_iter(),
+            sources,
+            DEFAULT_MAX_CALL_DEPTH,
+        );
+
+        // The first connection stays open, and must not hold up the second.
+        client
+            .set_read_timeout(Some(std::time::Duration::from_secs(10)))
+            .unwrap();
+        let mut writer = client.try_clone().unwrap();
+        let mut reader = io::BufReader::new(client);
+        let mut reply = String::new();
+
+        writeln!(
+            writer,
+            r#"{{"jsonrpc":"2.0","id":1,"method":"evaluate","params":{{"source":"double(21)"}}}}"#
+        )
+        .unwrap();
+        reader.read_line(&mut reply).unwrap();
+        assert!(reply.contains(r#""value":"42""#));
+
+        // Errors come back with their text, as the daemon's stderr is out of sight.
+        reply.clear();
+        writeln!(
+            writer,
+            r#"{{"jsonrpc":"2.0","id":2,"method":"evaluate","params":{{"source":"double(nil)"}}}}"#
+        )
+        .unwrap();
+        reader.read_line(&mut reply).unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply["error"]["code"], RPC_RUNTIME_ERROR);
+        let data = reply["error"]["data"].as_str().unwrap();
+        assert!(data.contains("in double()"));
 
-    // Read the byte as the value used to index into the constants array.
-    fn read_constant(&mut self) -> &Value {
-        let idx = self.read_byte() as usize;
-        &self.chunk.constants[idx]
+        drop(idle_client);
     }
 
-    // Main run loop. Interpret all byte code and mutate internal state.
-    #[allow(dead_code)]
-    fn run(&mut self, debug: bool) -> InterpretResult {
-        while self.ip < self.chunk.code.len() {
-            if debug {
-                print!("          ");
-                self.stack.iter().for_each(|slot| print!("[ {} ]", slot));
-                println!();
-                self.chunk.disassemble_instruction(self.ip);
-            }
-            match self.read_instruction() {
-                Some(Opcode::Equal) => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    self.push(Value::Bool(a.is_equal(&b)))
-                }
-                Some(Opcode::Greater) => {
-                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let b = self.pop().as_number();
-                    let a = self.pop().as_number();
-                    self.push(Value::Bool(a > b));
-                }
-                Some(Opcode::Less) => {
-                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let b = self.pop().as_number();
-                    let a = self.pop().as_number();
-                    self.push(Value::Bool(a < b));
-                }
-                Some(Opcode::Not) => {
-                    let value = self.pop();
-                    self.push(Value::Bool(value.is_falsey()));
-                }
-                Some(Opcode::False) => self.push(Value::Bool(false)),
-                Some(Opcode::True) => self.push(Value::Bool(true)),
-                Some(Opcode::Nil) => self.push(Value::Nil),
-                Some(Opcode::Constant) => {
-                    let constant = self.read_constant().clone();
-                    self.push(constant);
-                }
-                Some(Opcode::Add) => {
-                    if self.peek(0).is_string() || self.peek(1).is_string() {
-                        let b = self.pop().as_string();
-                        let a = self.pop().as_string();
-                        self.push(Value::String(a + &b));
-                    } else if self.peek(0).is_number() || self.peek(1).is_number() {
-                        let b = self.pop().as_number();
-                        let a = self.pop().as_number();
-                        self.push(Value::Number(a + b));
-                    } else {
-                        self.runtime_error("Operands must be numbers or strings.");
-                        return InterpretResult::RuntimeError;
-                    }
-                }
-                Some(Opcode::Subtract) => {
-                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let b = self.pop().as_number();
-                    let a = self.pop().as_number();
-                    self.push(Value::Number(a - b));
-                }
-                Some(Opcode::Multiply) => {
-                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let a = self.pop().as_number();
-                    let b = self.pop().as_number();
-                    self.push(Value::Number(a * b));
-                }
-                Some(Opcode::Divide) => {
-                    if !self.peek(0).is_number() || !self.peek(1).is_number() {
-                        self.runtime_error("Operands must be numbers.");
-                        return InterpretResult::RuntimeError;
-                    }
-                    let a = self.pop().as_number();
-                    let b = self.pop().as_number();
-                    self.push(Value::Number(a / b));
-                }
-                Some(Opcode::Negate) => {
-                    if self.peek(0).is_number() {
-                        self.runtime_error("Operand must be a number.");
-                    }
-                    let negated_value = -self.pop().as_number();
-                    self.push(Value::Number(negated_value));
-                }
-                Some(Opcode::Return) => {
-                    print_value(&self.pop());
-                    println!();
-                    return InterpretResult::Ok;
-                }
-                None => {
-                    println!("Invalid opcode found.")
-                }
-            }
-        }
-        InterpretResult::CompileError
-    }
+    // Testing Jupyter messaging.
 
-    fn peek(&self, offset: usize) -> &Value {
-        &self.stack[offset]
+    fn jupyter_request(msg_type: &str, content: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({
+            "channel": "shell",
+            "header": { "msg_id": "m1", "session": "s", "msg_type": msg_type, "version": "5.3" },
+            "parent_header": {},
+            "metadata": {},
+            "content": content,
+        })
     }
 
-    fn runtime_error(&mut self, message: &str) {
-        eprintln!("{}", message);
-        let instruction = self.ip;
-        let line = self.chunk.lines[instruction];
-        eprintln!("[line {}] in script", line);
+    fn msg_types(messages: &[serde_json::Value]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message["header"]["msg_type"].as_str().unwrap())
+            .collect()
     }
-}
 
-//
-// Main driver.
-//
-fn main() -> ExitCode {
-    let args = <Args as clap::Parser>::parse();
-    let vm = VM::new(Chunk::new());
-
-    if let Some(path) = args.path.as_deref() {
-        run_file(vm, path)
-    } else {
-        run_repl(vm)
+    #[test]
+    fn test_jupyter_execute() {
+        let mut vm = VM::new(Chunk::new());
+        let mut kernel = Kernel::new();
+
+        let code = serde_json::json!({ "code": "var a = 1;" });
+        let request = jupyter_request("execute_request", code);
+        kernel.handle(&mut vm, &request);
+
+        let code = serde_json::json!({ "code": "print a + 1;" });
+        let request = jupyter_request("execute_request", code);
+        let messages = kernel.handle(&mut vm, &request);
+        assert_eq!(
+            msg_types(&messages),
+            [
+                "status",
+                "execute_input",
+                "stream",
+                "execute_reply",
+                "status"
+            ]
+        );
+        assert_eq!(messages[2]["content"]["text"], "2\n");
+        assert_eq!(messages[3]["channel"], "shell");
+        assert_eq!(messages[3]["content"]["status"], "ok");
+        assert_eq!(messages[3]["content"]["execution_count"], 2);
+        assert_eq!(messages[3]["parent_header"]["msg_id"], "m1");
     }
-}
-
-//
-// Run file.
-//
-fn run_file(mut vm: VM, path: &str) -> ExitCode {
-    let file_source = std::fs::read_to_string(path);
-
-    if let Ok(file_source) = file_source {
-        let result = vm.interpret(&file_source);
 
-        match result {
-            InterpretResult::CompileError => ExitCode::from(65),
-            InterpretResult::Ok => ExitCode::SUCCESS,
-            InterpretResult::RuntimeError => ExitCode::from(70),
-        }
-    } else {
-        // File not found.
-        error!("File at path not found: {}", path);
-        io::stdout().flush().unwrap();
-        ExitCode::from(74)
+    #[test]
+    fn test_jupyter_errors_and_info() {
+        let mut vm = VM::new(Chunk::new());
+        let mut kernel = Kernel::new();
+
+        let request = jupyter_request("execute_request", serde_json::json!({ "code": "-nil;" }));
+        let messages = kernel.handle(&mut vm, &request);
+        assert_eq!(
+            msg_types(&messages),
+            [
+                "status",
+                "execute_input",
+                "error",
+                "execute_reply",
+                "status"
+            ]
+        );
+        let reply = &messages[3]["content"];
+        assert_eq!(reply["ename"], "RuntimeError");
+        assert_eq!(reply["evalue"], "Operand must be a number.");
+        assert_eq!(
+            reply["traceback"],
+            serde_json::json!(["Operand must be a number.", "[line 1] in script"])
+        );
+        assert_eq!(messages[2]["content"]["traceback"], reply["traceback"]);
+
+        let request = jupyter_request("execute_request", serde_json::json!({ "code": "print;" }));
+        let messages = kernel.handle(&mut vm, &request);
+        let reply = &messages[3]["content"];
+        assert_eq!(reply["ename"], "CompileError");
+        assert_eq!(reply["evalue"], "[line 1] Error at ;: Expect expression.");
+
+        let request = jupyter_request("kernel_info_request", serde_json::json!({}));
+        let messages = kernel.handle(&mut vm, &request);
+        assert_eq!(messages[1]["content"]["language_info"]["name"], "lox");
     }
-}
 
-//
-// REPL.
-//
-fn run_repl(mut vm: VM) -> ExitCode {
-    let _ = vm;
-    print!("> ");
-    io::stdout().flush().unwrap();
+    #[test]
+    fn test_jupyter_complete() {
+        let mut vm = VM::new(Chunk::new());
+        let mut kernel = Kernel::new();
+        vm.interpret("var grape = 1; var greeting = \"hi\"; var other;");
 
-    'l: loop {
-        let stdin = io::stdin();
-        for line in stdin.lock().lines() {
-            let line = line.unwrap();
-            if let InterpretResult::CompileError = vm.interpret(&line) {
-                break 'l;
-            }
-            print!("> ");
-            io::stdout().flush().unwrap();
-        }
+        let content = serde_json::json!({ "code": "print \"é\" + gr;", "cursor_pos": 14 });
+        let messages = kernel.handle(&mut vm, &jupyter_request("complete_request", content));
+        assert_eq!(msg_types(&messages), ["status", "complete_reply", "status"]);
+        let reply = &messages[1]["content"];
+        assert_eq!(reply["matches"], serde_json::json!(["grape", "greeting"]));
+        assert_eq!(
+            (reply["cursor_start"].as_u64(), reply["cursor_end"].as_u64()),
+            (Some(12), Some(14))
+        );
     }
-    ExitCode::SUCCESS
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
-    // Testing scanner.
+    // Testing configuration.
 
     #[test]
-    fn test_scanner_basic() {
-        let source = "(";
-        let mut scanner = Scanner::new(source);
+    fn test_config_parsing() {
+        let config: Config = toml::from_str("prompt = \"lox> \"\nhistory_size = 10\n").unwrap();
+        assert_eq!(config.prompt, "lox> ");
 
-        let token = scanner.scan_token();
-
-        assert!(token.kind == TokenKind::LeftParen);
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.prompt, "> ");
     }
 
     #[test]
-    fn test_scanner() {
-        let source = "({;,.-+/*})";
-        let mut scanner = Scanner::new(source);
-
-        let mut idx = 0;
-        let expected = [
-            TokenKind::LeftParen,
-            TokenKind::LeftBrace,
-            TokenKind::Semicolon,
-            TokenKind::Comma,
-            TokenKind::Dot,
-            TokenKind::Minus,
-            TokenKind::Plus,
-            TokenKind::Slash,
-            TokenKind::Star,
-            TokenKind::RightBrace,
-            TokenKind::RightParen,
-        ];
-        while !scanner.is_at_end() {
-            let token = scanner.scan_token();
-
-            assert!(token.kind == expected[idx]);
-
-            idx += 1;
-        }
+    fn test_config_explicit_path_must_exist() {
+        let missing = PathBuf::from("/nonexistent/rustlox/config.toml");
+        assert!(Config::load(Some(&missing)).is_err());
     }
 }
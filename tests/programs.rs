@@ -0,0 +1,13 @@
+// Runs every program in `tests/programs` and compares what it prints and reports with the
+// snapshots beside it. Rerun with `--snapshots tests/programs --bless` to update them.
+
+use std::path::Path;
+
+use sugoi_na::check_snapshots;
+
+#[test]
+fn programs_match_snapshots() {
+    let programs = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mismatches = check_snapshots(&programs, false).unwrap();
+    assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+}